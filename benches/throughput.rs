@@ -0,0 +1,149 @@
+//! Measures request throughput against a running server with many concurrent keep-alive connections.
+//!
+//! Start the server first, with rate limiting off and `connections.max_per_client` set to 0 since
+//! every connection comes from the same address, then run:
+//!
+//! `cargo bench --bench throughput`
+//!
+//! Settings are read from `WAITER_BENCH_ADDRESS` (default `127.0.0.1:7878`), `WAITER_BENCH_PATH`
+//! (default `/`), `WAITER_BENCH_CONNECTIONS` (default 200) and `WAITER_BENCH_SECONDS` (default 10).
+
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct ConnectionResult {
+    latencies: Vec<Duration>,
+    statuses: BTreeMap<u16, usize>,
+    errors: usize,
+}
+
+fn main() {
+    let address = env::var("WAITER_BENCH_ADDRESS").unwrap_or_else(|_| "127.0.0.1:7878".to_string());
+    let path = env::var("WAITER_BENCH_PATH").unwrap_or_else(|_| "/".to_string());
+    let connections = get_setting("WAITER_BENCH_CONNECTIONS", 200);
+    let seconds = get_setting("WAITER_BENCH_SECONDS", 10);
+
+    println!("Benchmarking http://{}{} with {} connections for {}s", address, path, connections, seconds);
+
+    let barrier = Arc::new(Barrier::new(connections + 1));
+    let handles: Vec<_> =
+        (0..connections)
+            .map(|_| {
+                let (address, path, barrier) = (address.clone(), path.clone(), barrier.clone());
+                thread::spawn(move || {
+                    barrier.wait();
+                    run_connection(&address, &path, Instant::now() + Duration::from_secs(seconds as u64))
+                })
+            })
+            .collect();
+
+    barrier.wait();
+    let start = Instant::now();
+    let results: Vec<ConnectionResult> = handles.into_iter().filter_map(|h| h.join().ok()).collect();
+    let elapsed = start.elapsed();
+
+    let mut latencies: Vec<Duration> = results.iter().flat_map(|r| r.latencies.iter().copied()).collect();
+    latencies.sort();
+    let mut statuses = BTreeMap::new();
+    for (status, count) in results.iter().flat_map(|r| r.statuses.iter()) {
+        *statuses.entry(*status).or_insert(0) += count;
+    }
+    let errors: usize = results.iter().map(|r| r.errors).sum();
+
+    println!("Requests:   {}", latencies.len());
+    println!("Throughput: {:.0} requests/s", latencies.len() as f64 / elapsed.as_secs_f64());
+    println!("Latency:    p50 {:?}, p99 {:?}, max {:?}", percentile(&latencies, 0.5), percentile(&latencies, 0.99), latencies.last().copied().unwrap_or_default());
+    println!("Statuses:   {:?}", statuses);
+    println!("Errors:     {}", errors);
+}
+
+fn get_setting(name: &str, default: usize) -> usize {
+    env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    match sorted.is_empty() {
+        true => Duration::default(),
+        false => sorted[((sorted.len() - 1) as f64 * p) as usize]
+    }
+}
+
+/// Send requests one after another on a keep-alive connection until the deadline,
+/// reconnecting whenever the server closes the connection.
+fn run_connection(address: &str, path: &str, deadline: Instant) -> ConnectionResult {
+    let mut result = ConnectionResult { latencies: vec![], statuses: BTreeMap::new(), errors: 0 };
+    let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, address);
+    let mut stream = None;
+
+    while Instant::now() < deadline {
+        if stream.is_none() {
+            match TcpStream::connect(address) {
+                Ok(s) => stream = Some(s),
+                Err(_) => {
+                    result.errors += 1;
+                    thread::sleep(Duration::from_millis(10));
+                    continue;
+                }
+            }
+        }
+
+        let s = stream.as_mut().unwrap();
+        let sent = Instant::now();
+        let response = s.write_all(request.as_bytes()).ok().and_then(|_| read_response(s));
+        match response {
+            Some((status, keep_alive)) => {
+                result.latencies.push(sent.elapsed());
+                *result.statuses.entry(status).or_insert(0) += 1;
+                if !keep_alive {
+                    stream = None;
+                }
+            }
+            None => {
+                result.errors += 1;
+                stream = None;
+            }
+        }
+    }
+
+    result
+}
+
+/// Read one response, returning its status and whether the connection stays open.
+fn read_response(stream: &mut TcpStream) -> Option<(u16, bool)> {
+    let mut data = Vec::new();
+    let mut chunk = [0; 8192];
+
+    let header_end = loop {
+        if let Some(i) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        match stream.read(&mut chunk) {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => data.extend_from_slice(&chunk[..n])
+        }
+    };
+
+    let header = String::from_utf8_lossy(&data[..header_end]).to_lowercase();
+    let status = header.split(' ').nth(1)?.parse().ok()?;
+    let content_length: usize =
+        header
+            .lines()
+            .find_map(|l| l.strip_prefix("content-length:"))
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(0);
+    let keep_alive = header.lines().any(|l| l.starts_with("connection:") && l.contains("keep-alive"));
+
+    while data.len() < header_end + content_length {
+        match stream.read(&mut chunk) {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => data.extend_from_slice(&chunk[..n])
+        }
+    }
+
+    Some((status, keep_alive))
+}
@@ -0,0 +1,399 @@
+use std::net::{IpAddr, Ipv6Addr};
+use std::str::FromStr;
+use crate::http::HttpRequest;
+
+/// An ip address range in CIDR notation, a bare address is treated as a single host.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix: u8,
+}
+
+/// Allow and deny lists of networks. Deny entries always win,
+/// if the allow list is empty any address not denied is allowed.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct AccessList {
+    allow: Vec<IpNetwork>,
+    deny: Vec<IpNetwork>,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum ClientIpHeader {
+    XForwardedFor,
+    Forwarded,
+}
+
+/// Resolves the real client address for connections from trusted proxies.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct ClientIpResolver {
+    trusted_proxies: Vec<IpNetwork>,
+    header: Option<ClientIpHeader>,
+    proxy_protocol: bool,
+}
+
+/// The server wide access settings.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct AccessPolicy {
+    pub access_list: AccessList,
+    pub resolver: ClientIpResolver,
+}
+
+impl IpNetwork {
+    pub fn parse(data: &str) -> Result<IpNetwork, &'static str> {
+        let (address, prefix) =
+            match data.trim().split_once('/') {
+                None => (data.trim(), None),
+                Some((a, p)) => (a, Some(p))
+            };
+
+        let address = normalize(IpAddr::from_str(address).map_err(|_| "Invalid ip address")?);
+        let max_prefix = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix =
+            match prefix {
+                None => max_prefix,
+                Some(p) => p.parse::<u8>().map_err(|_| "Invalid network prefix")?
+            };
+
+        match prefix > max_prefix {
+            true => Err("Network prefix too long"),
+            false => Ok(IpNetwork { address, prefix })
+        }
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (&self.address, &normalize(*ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                (u32::from(*network) & mask) == (u32::from(*ip) & mask)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                (u128::from(*network) & mask) == (u128::from(*ip) & mask)
+            }
+            _ => false
+        }
+    }
+}
+
+impl AccessList {
+    pub fn new(allow: Vec<IpNetwork>, deny: Vec<IpNetwork>) -> AccessList {
+        AccessList { allow, deny }
+    }
+
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        if self.deny.iter().any(|n| n.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|n| n.contains(ip))
+    }
+}
+
+impl ClientIpHeader {
+    pub fn from_str(data: &str) -> Result<ClientIpHeader, &'static str> {
+        match data.to_lowercase().as_str() {
+            "x-forwarded-for" => Ok(ClientIpHeader::XForwardedFor),
+            "forwarded" => Ok(ClientIpHeader::Forwarded),
+            _ => Err("Unknown client ip header")
+        }
+    }
+}
+
+impl ClientIpResolver {
+    pub fn new(trusted_proxies: Vec<IpNetwork>, header: Option<ClientIpHeader>, proxy_protocol: bool) -> ClientIpResolver {
+        ClientIpResolver { trusted_proxies, header, proxy_protocol }
+    }
+
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|n| n.contains(ip))
+    }
+
+    /// True if a PROXY protocol line should be accepted from this peer.
+    pub fn accepts_proxy_protocol(&self, peer: &IpAddr) -> bool {
+        self.proxy_protocol && self.is_trusted(peer)
+    }
+
+    /// Resolve the client address. Forwarding headers are only read when the connection
+    /// comes from a trusted proxy, and are walked right to left skipping further trusted proxies.
+    pub fn resolve(&self, peer: IpAddr, proxied: Option<IpAddr>, request: &HttpRequest) -> IpAddr {
+        // A PROXY protocol address has already been checked to come from a trusted peer.
+        let peer = normalize(proxied.unwrap_or(peer));
+
+        if !self.is_trusted(&peer) {
+            return peer;
+        }
+
+        let chain =
+            match &self.header {
+                None => return peer,
+                Some(ClientIpHeader::XForwardedFor) => {
+                    match request.get_header("X-Forwarded-For") {
+                        None => return peer,
+                        Some(v) => v.split(',').filter_map(parse_forwarded_address).collect::<Vec<IpAddr>>()
+                    }
+                }
+                Some(ClientIpHeader::Forwarded) => {
+                    match request.get_header("Forwarded") {
+                        None => return peer,
+                        Some(v) => parse_forwarded_header(v)
+                    }
+                }
+            };
+
+        chain
+            .iter()
+            .rev()
+            .find(|ip| !self.is_trusted(ip))
+            .or(chain.first())
+            .copied()
+            .unwrap_or(peer)
+    }
+}
+
+/// Parse a PROXY protocol v1 line (without the trailing `\r\n`) and return the source address.
+pub fn parse_proxy_protocol(line: &str) -> Result<Option<IpAddr>, &'static str> {
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4", source, _, _, _] | ["PROXY", "TCP6", source, _, _, _] => {
+            IpAddr::from_str(source)
+                .map(|ip| Some(normalize(ip)))
+                .map_err(|_| "Invalid PROXY protocol source address")
+        }
+        _ => Err("Invalid PROXY protocol header")
+    }
+}
+
+/// Collect the `for=` addresses from a `Forwarded` header, in order.
+fn parse_forwarded_header(value: &str) -> Vec<IpAddr> {
+    value
+        .split(',')
+        .filter_map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(k, _)| k.eq_ignore_ascii_case("for"))
+                .and_then(|(_, v)| parse_forwarded_address(v))
+        })
+        .collect()
+}
+
+/// Parse an address that may be quoted, bracketed and/or have a port.
+fn parse_forwarded_address(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+
+    if let Ok(ip) = IpAddr::from_str(value) {
+        return Some(normalize(ip));
+    }
+
+    let host =
+        match value.strip_prefix('[') {
+            Some(v6) => v6.split(']').next()?,
+            None => value.split(':').next()?
+        };
+
+    IpAddr::from_str(host).ok().map(normalize)
+}
+
+/// Treat IPv4-mapped IPv6 addresses as the IPv4 address.
+fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => {
+            match to_ipv4_mapped(&v6) {
+                Some(v4) => IpAddr::V4(v4),
+                None => ip
+            }
+        }
+        _ => ip
+    }
+}
+
+fn to_ipv4_mapped(ip: &Ipv6Addr) -> Option<std::net::Ipv4Addr> {
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0xffff, _, _] => ip.to_ipv4(),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::HttpRequestHeader;
+    use super::*;
+
+    fn ip(data: &str) -> IpAddr {
+        IpAddr::from_str(data).unwrap()
+    }
+
+    fn network(data: &str) -> IpNetwork {
+        IpNetwork::parse(data).unwrap()
+    }
+
+    fn request(headers: &[(&str, &str)]) -> HttpRequest {
+        let lines: String = headers.iter().map(|(k, v)| format!("{}: {}\r\n", k, v)).collect();
+        let header = HttpRequestHeader::parse_from_string(format!("GET / HTTP/1.1\r\n{}", lines)).unwrap();
+        HttpRequest::create(header, None).unwrap()
+    }
+
+    fn resolver(header: Option<ClientIpHeader>) -> ClientIpResolver {
+        ClientIpResolver::new(vec![network("10.0.0.0/8"), network("fd00::/8")], header, true)
+    }
+
+    #[test]
+    fn zero_prefix_matches_every_address_of_its_family() {
+        assert!(network("0.0.0.0/0").contains(&ip("203.0.113.7")));
+        assert!(network("0.0.0.0/0").contains(&ip("255.255.255.255")));
+        assert!(!network("0.0.0.0/0").contains(&ip("2001:db8::1")));
+        assert!(network("::/0").contains(&ip("2001:db8::1")));
+        assert!(!network("::/0").contains(&ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn full_prefix_matches_one_address() {
+        let host = network("192.0.2.10/32");
+        assert!(host.contains(&ip("192.0.2.10")));
+        assert!(!host.contains(&ip("192.0.2.11")));
+        assert!(network("192.0.2.10").contains(&ip("192.0.2.10")));
+
+        let host = network("2001:db8::1/128");
+        assert!(host.contains(&ip("2001:db8::1")));
+        assert!(!host.contains(&ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn matches_prefixes() {
+        let n = network("192.168.4.0/22");
+        assert!(n.contains(&ip("192.168.4.0")));
+        assert!(n.contains(&ip("192.168.7.255")));
+        assert!(!n.contains(&ip("192.168.8.0")));
+        assert!(!n.contains(&ip("192.168.3.255")));
+
+        let n = network("2001:db8:abcd::/48");
+        assert!(n.contains(&ip("2001:db8:abcd:ffff::1")));
+        assert!(!n.contains(&ip("2001:db8:abce::1")));
+    }
+
+    #[test]
+    fn rejects_invalid_networks() {
+        assert!(IpNetwork::parse("10.0.0.0/33").is_err());
+        assert!(IpNetwork::parse("::/129").is_err());
+        assert!(IpNetwork::parse("10.0.0.0/-1").is_err());
+        assert!(IpNetwork::parse("10.0.0/8").is_err());
+        assert!(IpNetwork::parse("example.com").is_err());
+    }
+
+    #[test]
+    fn treats_ipv4_mapped_addresses_as_ipv4() {
+        assert!(network("10.0.0.0/8").contains(&ip("::ffff:10.1.2.3")));
+        assert!(!network("10.0.0.0/8").contains(&ip("::ffff:11.1.2.3")));
+        // A mapped network is an ipv4 network, its prefix is out of 32.
+        assert!(network("::ffff:10.0.0.0/8").contains(&ip("10.9.9.9")));
+        assert!(IpNetwork::parse("::ffff:10.0.0.0/104").is_err());
+        // Only the mapped form, not the deprecated compatible one.
+        assert!(!network("10.0.0.0/8").contains(&ip("::10.1.2.3")));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let list = AccessList::new(vec![network("10.0.0.0/8")], vec![network("10.0.0.5")]);
+        assert!(list.is_allowed(&ip("10.0.0.4")));
+        assert!(!list.is_allowed(&ip("10.0.0.5")));
+        assert!(!list.is_allowed(&ip("192.0.2.1")));
+        assert!(AccessList::new(vec![], vec![network("10.0.0.5")]).is_allowed(&ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let r = resolver(Some(ClientIpHeader::XForwardedFor));
+        let spoofed = request(&[("X-Forwarded-For", "1.2.3.4")]);
+        assert_eq!(r.resolve(ip("203.0.113.7"), None, &spoofed), ip("203.0.113.7"));
+
+        let r = resolver(Some(ClientIpHeader::Forwarded));
+        let spoofed = request(&[("Forwarded", "for=1.2.3.4")]);
+        assert_eq!(r.resolve(ip("203.0.113.7"), None, &spoofed), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn ignores_headers_unless_configured() {
+        let r = resolver(None);
+        assert_eq!(r.resolve(ip("10.0.0.1"), None, &request(&[("X-Forwarded-For", "1.2.3.4")])), ip("10.0.0.1"));
+
+        let r = resolver(Some(ClientIpHeader::Forwarded));
+        assert_eq!(r.resolve(ip("10.0.0.1"), None, &request(&[("X-Forwarded-For", "1.2.3.4")])), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn resolves_forwarded_for_from_trusted_proxy() {
+        let r = resolver(Some(ClientIpHeader::XForwardedFor));
+        assert_eq!(r.resolve(ip("10.0.0.1"), None, &request(&[("X-Forwarded-For", "203.0.113.7")])), ip("203.0.113.7"));
+        assert_eq!(r.resolve(ip("10.0.0.1"), None, &request(&[])), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn walks_multi_hop_chains_from_the_right() {
+        let r = resolver(Some(ClientIpHeader::XForwardedFor));
+        // The client prepended a spoofed address, the first untrusted hop from the right is the real client.
+        let chain = request(&[("X-Forwarded-For", "1.2.3.4, 203.0.113.7, 10.0.0.2, 10.0.0.3")]);
+        assert_eq!(r.resolve(ip("10.0.0.1"), None, &chain), ip("203.0.113.7"));
+
+        // Every hop trusted, the leftmost is the furthest known.
+        let chain = request(&[("X-Forwarded-For", "10.0.0.9, 10.0.0.2")]);
+        assert_eq!(r.resolve(ip("10.0.0.1"), None, &chain), ip("10.0.0.9"));
+
+        // Entries that are not addresses are skipped.
+        let chain = request(&[("X-Forwarded-For", "203.0.113.7, garbage, 10.0.0.2")]);
+        assert_eq!(r.resolve(ip("10.0.0.1"), None, &chain), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn resolves_forwarded_header() {
+        let r = resolver(Some(ClientIpHeader::Forwarded));
+        let chain = request(&[("Forwarded", "for=1.2.3.4, for=\"[2001:db8::7]:4711\";proto=https, for=10.0.0.2;by=10.0.0.1")]);
+        assert_eq!(r.resolve(ip("10.0.0.1"), None, &chain), ip("2001:db8::7"));
+
+        let chain = request(&[("Forwarded", "proto=https;For=\"203.0.113.7:8080\"")]);
+        assert_eq!(r.resolve(ip("fd00::1"), None, &chain), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn trusts_mapped_proxy_addresses() {
+        let r = resolver(Some(ClientIpHeader::XForwardedFor));
+        let chain = request(&[("X-Forwarded-For", "::ffff:203.0.113.7")]);
+        assert_eq!(r.resolve(ip("::ffff:10.0.0.1"), None, &chain), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn resolves_proxy_protocol_source() {
+        assert_eq!(parse_proxy_protocol("PROXY TCP4 203.0.113.7 10.0.0.1 56324 443"), Ok(Some(ip("203.0.113.7"))));
+        assert_eq!(parse_proxy_protocol("PROXY TCP6 2001:db8::7 fd00::1 56324 443"), Ok(Some(ip("2001:db8::7"))));
+        assert_eq!(parse_proxy_protocol("PROXY TCP6 ::ffff:203.0.113.7 fd00::1 56324 443"), Ok(Some(ip("203.0.113.7"))));
+        assert_eq!(parse_proxy_protocol("PROXY UNKNOWN"), Ok(None));
+        assert!(parse_proxy_protocol("PROXY TCP4 example.com 10.0.0.1 56324 443").is_err());
+        assert!(parse_proxy_protocol("PROXY TCP4 203.0.113.7 10.0.0.1").is_err());
+        assert!(parse_proxy_protocol("GET / HTTP/1.1").is_err());
+    }
+
+    #[test]
+    fn proxy_protocol_only_from_trusted_peers() {
+        let r = resolver(Some(ClientIpHeader::XForwardedFor));
+        assert!(r.accepts_proxy_protocol(&ip("10.0.0.1")));
+        assert!(!r.accepts_proxy_protocol(&ip("203.0.113.7")));
+        assert!(!ClientIpResolver::new(vec![network("10.0.0.0/8")], None, false).accepts_proxy_protocol(&ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn resolves_headers_behind_proxy_protocol() {
+        let r = resolver(Some(ClientIpHeader::XForwardedFor));
+        let chain = request(&[("X-Forwarded-For", "1.2.3.4")]);
+        // The PROXY source is an untrusted client, so its header is ignored.
+        assert_eq!(r.resolve(ip("10.0.0.1"), Some(ip("203.0.113.7")), &chain), ip("203.0.113.7"));
+        // The PROXY source is another trusted proxy, so its header is read.
+        assert_eq!(r.resolve(ip("10.0.0.1"), Some(ip("10.0.0.2")), &chain), ip("1.2.3.4"));
+    }
+}
@@ -46,7 +46,7 @@ impl<T: 'static + Send> MailBox<T> {
     pub fn post_and_reply(&self, value: T) -> T {
         //println!("Mailbox - sending value: {}", value);
         let (sender, reply) = mpsc::channel();
-        self.sender.send(MessageType::PostAndReply(value, sender));
+        self.sender.send(MessageType::PostAndReply(value, sender)).ok();
         reply.recv().unwrap()
     }
 }
\ No newline at end of file
@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::sync::mpsc::channel;
+use serde_json::{json, Value};
+use uuid::Uuid;
+use crate::auth::{Authorizer, JobPermission, Principal};
+use crate::http::{HttpRequest, HttpResponse, HttpVerb};
+use crate::configuration::{get_scalar_string, DebounceSettings};
+use crate::orchestration::{expand_actions, Aggregator, JobCommand, JobContext, JobRunState, JobRunStatus, QueueError, QueuedJobInfo};
+use crate::parameters::resolve_parameters;
+
+/// Handle the job status api:
+///
+/// * `GET /jobs` - list the job runs the principal can view.
+/// * `POST /jobs` - trigger a job, the body is `{ "job": name, "parameters": { ... }, "priority": n }`.
+/// * `GET /jobs/queues` - get the statistics of each job queue.
+/// * `GET /jobs/queues/{name}` - list the jobs waiting in a queue, in the order they will start.
+/// * `GET /jobs/locks` - list the held and awaited locks, and the runs of jobs with a concurrency limit.
+/// * `GET /jobs/{id}` - get the status of a job run.
+/// * `GET /jobs/{id}/logs` - follow a run's output, action boundaries and state changes as Server-Sent Events,
+///   resuming after the `Last-Event-ID` header or `last_event_id` query parameter. The stream ends with the run,
+///   or early if the client falls behind, it can then resume from the last event it received.
+/// * `DELETE /jobs/{id}` - remove a job run that has not started from its queue.
+/// * `POST /jobs/{id}/cancel` - cancel a queued or running job run.
+/// * `POST /jobs/{id}/priority` - change the priority of a run's queued actions, the body is `{ "priority": n }`.
+pub fn handle_jobs_api(jobs: &JobContext, authorizer: &Authorizer, request: &HttpRequest, principal: Option<&Principal>) -> Result<HttpResponse, &'static str> {
+    let aggregator = &jobs.aggregator;
+    let segments: Vec<&str> = request.path().trim_matches('/').split('/').collect();
+
+    match (&request.header.verb, segments.as_slice()) {
+        (HttpVerb::GET, ["jobs"]) => {
+            let runs: Vec<JobRunStatus> =
+                aggregator
+                    .get_progress()
+                    .into_iter()
+                    .filter(|r| authorizer.authorize_job(principal, &r.name, JobPermission::View).is_ok())
+                    .collect();
+            Ok(json_response(200, &json!({ "runs": runs })))
+        }
+        (HttpVerb::POST, ["jobs"]) => {
+            let body: Value =
+                match serde_json::from_slice(request.body()) {
+                    Ok(b) => b,
+                    Err(_) => return Ok(json_response(400, &json!({ "message": "Body is not valid json" })))
+                };
+            let name =
+                match body["job"].as_str() {
+                    None => return Ok(json_response(400, &json!({ "message": "Body must name the `job` to trigger" }))),
+                    Some(n) => n
+                };
+            let priority =
+                match &body["priority"] {
+                    Value::Null => None,
+                    p => match p.as_i64() {
+                        None => return Ok(json_response(400, &json!({ "message": "Invalid priority" }))),
+                        Some(p) => Some(p)
+                    }
+                };
+            let parameters =
+                match parameters_from_body(request.body()) {
+                    Ok(p) => p,
+                    Err(e) => return Ok(json_response(400, &json!({ "message": e })))
+                };
+            if let Err(failure) = authorizer.authorize_job(principal, name, JobPermission::Trigger) {
+                return Ok(failure.to_response());
+            }
+            queue_job(jobs, name, principal.map(|p| p.name.clone()), parameters, priority, true, None)
+        }
+        (HttpVerb::GET, ["jobs", "queues"]) => {
+            Ok(json_response(200, &json!({ "queues": jobs.pools.stats() })))
+        }
+        (HttpVerb::GET, ["jobs", "queues", name]) => {
+            match jobs.pools.get(name) {
+                None => Ok(json_response(404, &json!({ "message": "Queue not found" }))),
+                Some(pool) => {
+                    let queued: Vec<QueuedJobInfo> =
+                        pool.queued()
+                            .into_iter()
+                            .filter(|q| authorizer.authorize_job(principal, &q.job, JobPermission::View).is_ok())
+                            .collect();
+                    Ok(json_response(200, &json!({ "name": name, "jobs": queued })))
+                }
+            }
+        }
+        (HttpVerb::GET, ["jobs", "locks"]) => {
+            let (mut locks, mut limited) = jobs.pools.locks();
+            let can_view = |job: &str| authorizer.authorize_job(principal, job, JobPermission::View).is_ok();
+            for lock in locks.iter_mut() {
+                lock.holder = lock.holder.take().filter(|h| can_view(&h.job));
+                lock.waiting.retain(|w| can_view(&w.job));
+            }
+            limited.retain(|j| can_view(&j.name));
+            Ok(json_response(200, &json!({ "locks": locks, "jobs": limited })))
+        }
+        (HttpVerb::GET, ["jobs", id]) => {
+            match get_run(aggregator, id) {
+                None => Ok(json_response(404, &json!({ "message": "Job run not found" }))),
+                Some(run) => {
+                    if let Err(failure) = authorizer.authorize_job(principal, &run.name, JobPermission::View) {
+                        return Ok(failure.to_response());
+                    }
+                    Ok(json_response(200, &json!(run)))
+                }
+            }
+        }
+        (HttpVerb::GET, ["jobs", id, "logs"]) => {
+            let after =
+                match request.get_header("Last-Event-ID").map(|h| h.as_str()).or(request.get_query_param("last_event_id")) {
+                    None => None,
+                    Some(a) => match a.trim().parse::<u64>() {
+                        Ok(a) => Some(a),
+                        Err(_) => return Ok(json_response(400, &json!({ "message": "Invalid last event id" })))
+                    }
+                };
+            match get_run(aggregator, id) {
+                None => Ok(json_response(404, &json!({ "message": "Job run not found" }))),
+                Some(run) => {
+                    if let Err(failure) = authorizer.authorize_job(principal, &run.name, JobPermission::View) {
+                        return Ok(failure.to_response());
+                    }
+                    let (mut response, sender) = HttpResponse::create_stream(200, String::from("text/event-stream"));
+                    response.add_header(String::from("Cache-Control"), String::from("no-cache"));
+                    match aggregator.subscribe_logs(run.id, after, Box::new(move |e| sender.send(e.to_sse().into_bytes()).is_ok())) {
+                        Ok(_) => Ok(response),
+                        Err(e) => Ok(json_response(404, &json!({ "message": e })))
+                    }
+                }
+            }
+        }
+        (HttpVerb::POST, ["jobs", id, "cancel"]) => {
+            match get_run(aggregator, id) {
+                None => Ok(json_response(404, &json!({ "message": "Job run not found" }))),
+                Some(run) => {
+                    if let Err(failure) = authorizer.authorize_job(principal, &run.name, JobPermission::Cancel) {
+                        return Ok(failure.to_response());
+                    }
+                    match aggregator.cancel(run.id) {
+                        Ok(run) => {
+                            // A run waiting for its concurrency limit or locks is not in a queue, so would never be skipped.
+                            jobs.pools.remove(run.id);
+                            Ok(json_response(200, &json!(aggregator.get_run(run.id).unwrap_or(run))))
+                        }
+                        Err(e) => Ok(json_response(409, &json!({ "message": e })))
+                    }
+                }
+            }
+        }
+        (HttpVerb::DELETE, ["jobs", id]) => {
+            match get_run(aggregator, id) {
+                None => Ok(json_response(404, &json!({ "message": "Job run not found" }))),
+                Some(run) => {
+                    if let Err(failure) = authorizer.authorize_job(principal, &run.name, JobPermission::Cancel) {
+                        return Ok(failure.to_response());
+                    }
+                    if run.state != JobRunState::Queued {
+                        return Ok(json_response(409, &json!({ "message": "Job run has already started." })));
+                    }
+                    match jobs.pools.remove(run.id) {
+                        0 => Ok(json_response(409, &json!({ "message": "Job run has already started." }))),
+                        _ => Ok(json_response(200, &json!(aggregator.get_run(run.id))))
+                    }
+                }
+            }
+        }
+        (HttpVerb::POST, ["jobs", id, "priority"]) => {
+            let priority =
+                match serde_json::from_slice::<Value>(request.body()).ok().and_then(|b| b["priority"].as_i64()) {
+                    None => return Ok(json_response(400, &json!({ "message": "Body must be an object with an integer `priority`" }))),
+                    Some(p) => p
+                };
+            match get_run(aggregator, id) {
+                None => Ok(json_response(404, &json!({ "message": "Job run not found" }))),
+                Some(run) => {
+                    if let Err(failure) = authorizer.authorize_job(principal, &run.name, JobPermission::Trigger) {
+                        return Ok(failure.to_response());
+                    }
+                    match jobs.pools.set_priority(run.id, priority) {
+                        0 => Ok(json_response(409, &json!({ "message": "Job run has no queued actions." }))),
+                        _ => {
+                            aggregator.set_priority(run.id, priority);
+                            Ok(json_response(200, &json!(aggregator.get_run(run.id))))
+                        }
+                    }
+                }
+            }
+        }
+        (_, ["jobs"]) | (_, ["jobs", _]) | (_, ["jobs", _, "cancel"]) | (_, ["jobs", _, "logs"]) | (_, ["jobs", _, "priority"]) | (_, ["jobs", "queues", _]) => {
+            Ok(json_response(405, &json!({ "message": "Method not allowed" })))
+        }
+        _ => Ok(json_response(404, &json!({ "message": "Not found" })))
+    }
+}
+
+/// Check the parameters against the job's declarations, then send a job command to the orchestrator
+/// and wait for the run id. Invalid parameters, or ones that expand a matrix to nothing, are rejected
+/// with a 400 listing every problem.
+/// If the route or else the job is debounced the trigger is held back, and a 202 with the trigger id returned.
+pub fn queue_job(jobs: &JobContext, name: &str, principal: Option<String>, parameters: HashMap<String, String>, priority: Option<i64>, strict: bool, debounce: Option<&DebounceSettings>) -> Result<HttpResponse, &'static str> {
+    let job =
+        match jobs.config.get_job(name) {
+            None => return Ok(json_response(404, &json!({ "message": "Job not found." }))),
+            Some(j) => j
+        };
+    let resolved =
+        match resolve_parameters(&job.parameters, &parameters, strict) {
+            Ok(p) => p,
+            Err(errors) => return Ok(json_response(400, &json!({ "message": "Invalid parameters", "errors": errors })))
+        };
+    if let Err(errors) = expand_actions(job, &resolved) {
+        return Ok(json_response(400, &json!({ "message": "Invalid parameters", "errors": errors })));
+    }
+
+    // Merged triggers must not carry each other's defaults, so the parameters are held as supplied.
+    if let Some(settings) = debounce.or(job.debounce.as_ref()) {
+        let debounced = jobs.debouncer.trigger(settings, name, principal, parameters, priority)?;
+        return Ok(json_response(202, &json!({
+            "message": "Job trigger debounced",
+            "trigger_id": debounced.trigger_id.to_string(),
+            "run_after_ms": debounced.run_after.as_millis() as u64,
+            "triggers": debounced.triggers,
+        })));
+    }
+    let parameters = resolved;
+
+    let (sender, reply_channel) = channel();
+    let command = JobCommand {
+        name: name.to_string(),
+        principal,
+        parameters,
+        priority,
+        resume: None,
+        parent: None,
+        triggers: vec![],
+        reply_channel: sender
+    };
+    if jobs.sender.send(command).is_err() {
+        return Err("Orchestrator not running");
+    }
+    match reply_channel.recv() {
+        Ok(Ok(id)) => Ok(json_response(201, &json!({ "message": "Job queued", "id": id.to_string() }))),
+        Ok(Err(QueueError::Rejected(e))) => Ok(json_response(409, &json!({ "message": e }))),
+        Ok(Err(QueueError::Failed(e))) => Ok(json_response(404, &json!({ "message": e }))),
+        Ok(Err(QueueError::Invalid(e))) => Ok(json_response(400, &json!({ "message": e }))),
+        Ok(Err(QueueError::InvalidParameters(errors))) => Ok(json_response(400, &json!({ "message": "Invalid parameters", "errors": errors }))),
+        Err(_) => Err("Orchestrator did not reply")
+    }
+}
+
+/// Read job parameters from a request body of the form `{ "parameters": { "name": value } }`.
+/// An empty body has no parameters.
+pub fn parameters_from_body(body: &[u8]) -> Result<HashMap<String, String>, &'static str> {
+    if body.iter().all(|b| b.is_ascii_whitespace()) {
+        return Ok(HashMap::new());
+    }
+    let json: Value = serde_json::from_slice(body).map_err(|_| "Body is not valid json")?;
+    match json.get("parameters") {
+        None => Ok(HashMap::new()),
+        Some(Value::Object(po)) => Ok(po.iter().map(|(k, v)| (k.clone(), get_scalar_string(v))).collect()),
+        Some(_) => Err("Parameters must be an object")
+    }
+}
+
+/// Look up a job run by the id in the path.
+fn get_run(aggregator: &Aggregator, id: &str) -> Option<JobRunStatus> {
+    Uuid::parse_str(id)
+        .ok()
+        .and_then(|id| aggregator.get_run(id))
+}
+
+fn json_response(code: i16, body: &Value) -> HttpResponse {
+    HttpResponse::create(code, String::from("application/json"), Some(body.to_string().into_bytes()))
+}
@@ -0,0 +1,638 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use regex::Regex;
+use serde_json::{json, Value};
+use sha2::{Sha256, Sha384, Sha512};
+use crate::configuration::JobsConfiguration;
+use crate::http::{HttpRequest, HttpResponse};
+
+/// The identity a request was authenticated as.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct Principal {
+    pub name: String,
+    pub credential_set: String,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct ApiKeyCredentials {
+    header: String,
+    /// Pairs of `(key, principal)`.
+    keys: Vec<(String, String)>,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct BasicCredentials {
+    realm: String,
+    /// Username to argon2 or bcrypt password hash.
+    users: HashMap<String, String>,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct BearerCredentials {
+    realm: String,
+    /// Pairs of `(token, principal)`.
+    tokens: Vec<(String, String)>,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct JwtCredentials {
+    realm: String,
+    algorithm: JwtAlgorithm,
+    secret: Vec<u8>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    principal_claim: String,
+    leeway: i64,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum JwtAlgorithm {
+    HS256,
+    HS384,
+    HS512,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum CredentialSet {
+    ApiKey(ApiKeyCredentials),
+    Basic(BasicCredentials),
+    Bearer(BearerCredentials),
+    Jwt(JwtCredentials),
+}
+
+/// The authentication requirements attached to a route.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct RouteAuth {
+    credential_sets: Vec<String>,
+    /// If empty any principal from the credential sets is accepted.
+    principals: Vec<String>,
+}
+
+/// All named credential sets from the `auth` section of the configuration.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct Authenticator {
+    credential_sets: HashMap<String, CredentialSet>,
+}
+
+/// A named set of route patterns a principal may access.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct Role {
+    name: String,
+    routes: Vec<Regex>,
+}
+
+pub enum JobPermission {
+    View,
+    Trigger,
+    Cancel,
+}
+
+/// Maps principals to roles and checks them against routes and job permissions.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct Authorizer {
+    roles: HashMap<String, Role>,
+    principal_roles: HashMap<String, Vec<String>>,
+    jobs: Arc<JobsConfiguration>,
+}
+
+pub enum AuthFailure {
+    /// No valid credentials were supplied, holds the `WWW-Authenticate` challenges.
+    Unauthorized(Vec<String>),
+    /// Valid credentials were supplied but the principal is not allowed on the route.
+    Forbidden(Principal),
+    /// The principal's roles do not grant a permission on a resource.
+    MissingPermission { permission: String, resource: String },
+}
+
+impl JwtAlgorithm {
+    pub fn from_str(data: &str) -> Result<JwtAlgorithm, &'static str> {
+        match data.to_uppercase().as_str() {
+            "HS256" => Ok(JwtAlgorithm::HS256),
+            "HS384" => Ok(JwtAlgorithm::HS384),
+            "HS512" => Ok(JwtAlgorithm::HS512),
+            _ => Err("Unsupported jwt algorithm")
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            JwtAlgorithm::HS256 => "HS256",
+            JwtAlgorithm::HS384 => "HS384",
+            JwtAlgorithm::HS512 => "HS512",
+        }
+    }
+
+    fn verify(&self, secret: &[u8], data: &[u8], signature: &[u8]) -> bool {
+        match self {
+            JwtAlgorithm::HS256 => verify_hmac::<Hmac<Sha256>>(secret, data, signature),
+            JwtAlgorithm::HS384 => verify_hmac::<Hmac<Sha384>>(secret, data, signature),
+            JwtAlgorithm::HS512 => verify_hmac::<Hmac<Sha512>>(secret, data, signature),
+        }
+    }
+}
+
+impl CredentialSet {
+    pub fn create_api_key(header: String, keys: Vec<(String, String)>) -> CredentialSet {
+        CredentialSet::ApiKey(ApiKeyCredentials { header, keys })
+    }
+
+    pub fn create_basic(realm: String, users: HashMap<String, String>) -> Result<CredentialSet, &'static str> {
+        if users.values().any(|h| !is_supported_hash(h)) {
+            return Err("Basic auth password hashes must be argon2 or bcrypt");
+        }
+        Ok(CredentialSet::Basic(BasicCredentials { realm, users }))
+    }
+
+    pub fn create_bearer(realm: String, tokens: Vec<(String, String)>) -> CredentialSet {
+        CredentialSet::Bearer(BearerCredentials { realm, tokens })
+    }
+
+    pub fn create_jwt(realm: String, algorithm: JwtAlgorithm, secret: Vec<u8>, issuer: Option<String>, audience: Option<String>, principal_claim: String, leeway: i64) -> CredentialSet {
+        CredentialSet::Jwt(JwtCredentials { realm, algorithm, secret, issuer, audience, principal_claim, leeway })
+    }
+
+    /// Returns the principal name if the request carries valid credentials for this set.
+    fn authenticate(&self, request: &HttpRequest) -> Option<String> {
+        match self {
+            CredentialSet::ApiKey(ak) => {
+                let supplied = request.get_header(&ak.header)?;
+                find_secret(&ak.keys, supplied)
+            }
+            CredentialSet::Basic(bc) => {
+                let encoded = get_authorization(request, "Basic")?;
+                let decoded = STANDARD.decode(encoded).ok()?;
+                let decoded = String::from_utf8(decoded).ok()?;
+                let (username, password) = decoded.split_once(':')?;
+                let hash = bc.users.get(username)?;
+                match verify_password(hash, password) {
+                    true => Some(username.to_string()),
+                    false => None
+                }
+            }
+            CredentialSet::Bearer(bc) => {
+                let supplied = get_authorization(request, "Bearer")?;
+                find_secret(&bc.tokens, supplied)
+            }
+            CredentialSet::Jwt(jc) => {
+                let token = get_authorization(request, "Bearer")?;
+                verify_jwt(jc, token)
+            }
+        }
+    }
+
+    fn challenge(&self) -> String {
+        match self {
+            CredentialSet::ApiKey(ak) => format!("ApiKey header=\"{}\"", ak.header),
+            CredentialSet::Basic(bc) => format!("Basic realm=\"{}\", charset=\"UTF-8\"", bc.realm),
+            CredentialSet::Bearer(bc) => format!("Bearer realm=\"{}\"", bc.realm),
+            CredentialSet::Jwt(jc) => format!("Bearer realm=\"{}\"", jc.realm),
+        }
+    }
+}
+
+impl RouteAuth {
+    pub fn new(credential_sets: Vec<String>, principals: Vec<String>) -> RouteAuth {
+        RouteAuth { credential_sets, principals }
+    }
+}
+
+impl Authenticator {
+    pub fn new(credential_sets: HashMap<String, CredentialSet>) -> Authenticator {
+        Authenticator { credential_sets }
+    }
+
+    pub fn empty() -> Authenticator {
+        Authenticator { credential_sets: HashMap::new() }
+    }
+
+    /// True if authenticating against the route may verify a password hash, which is slow by design.
+    pub fn is_expensive(&self, route_auth: &RouteAuth) -> bool {
+        route_auth.credential_sets
+            .iter()
+            .any(|name| matches!(self.credential_sets.get(name), Some(CredentialSet::Basic(_))))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.credential_sets.contains_key(name)
+    }
+
+    /// Check the request against each credential set the route accepts, in order.
+    pub fn authenticate(&self, route_auth: &RouteAuth, request: &HttpRequest) -> Result<Principal, AuthFailure> {
+        let mut challenges = Vec::new();
+
+        for name in &route_auth.credential_sets {
+            let set = match self.credential_sets.get(name) {
+                None => continue,
+                Some(s) => s
+            };
+
+            if let Some(principal) = set.authenticate(request) {
+                let principal = Principal { name: principal, credential_set: name.clone() };
+
+                return match route_auth.principals.is_empty() || route_auth.principals.contains(&principal.name) {
+                    true => Ok(principal),
+                    false => Err(AuthFailure::Forbidden(principal))
+                };
+            }
+
+            let challenge = set.challenge();
+            if !challenges.contains(&challenge) {
+                challenges.push(challenge);
+            }
+        }
+
+        Err(AuthFailure::Unauthorized(challenges))
+    }
+}
+
+impl Role {
+    pub fn new(name: String, routes: Vec<Regex>) -> Role {
+        Role { name, routes }
+    }
+}
+
+impl JobPermission {
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobPermission::View => "view",
+            JobPermission::Trigger => "trigger",
+            JobPermission::Cancel => "cancel",
+        }
+    }
+}
+
+impl Authorizer {
+    pub fn new(roles: HashMap<String, Role>, principal_roles: HashMap<String, Vec<String>>, jobs: Arc<JobsConfiguration>) -> Authorizer {
+        Authorizer { roles, principal_roles, jobs }
+    }
+
+    fn get_roles(&self, principal: &Principal) -> Vec<&Role> {
+        match self.principal_roles.get(&principal.name) {
+            None => vec![],
+            Some(names) => names.iter().filter_map(|n| self.roles.get(n)).collect()
+        }
+    }
+
+    /// Check a principal's roles grant access to a route.
+    /// If no roles are configured authentication alone is enough.
+    pub fn authorize_route(&self, principal: &Principal, route: &str) -> Result<(), AuthFailure> {
+        if self.roles.is_empty() {
+            return Ok(());
+        }
+
+        match self.get_roles(principal).iter().any(|r| r.routes.iter().any(|rr| rr.is_match(route))) {
+            true => Ok(()),
+            false => Err(AuthFailure::MissingPermission { permission: "route".to_string(), resource: route.to_string() })
+        }
+    }
+
+    /// Check a principal has a permission on a job.
+    /// Jobs without permissions are open to any principal (or anonymous requests).
+    pub fn authorize_job(&self, principal: Option<&Principal>, job_name: &str, permission: JobPermission) -> Result<(), AuthFailure> {
+        let allowed =
+            match self.jobs.get_job(job_name).and_then(|j| j.permissions.as_ref()) {
+                None => return Ok(()),
+                Some(p) => p.get_roles(&permission)
+            };
+
+        let granted =
+            match principal {
+                None => false,
+                Some(p) => self.get_roles(p).iter().any(|r| allowed.contains(&r.name))
+            };
+
+        match granted {
+            true => Ok(()),
+            false => Err(AuthFailure::MissingPermission { permission: permission.name().to_string(), resource: format!("job:{}", job_name) })
+        }
+    }
+}
+
+impl AuthFailure {
+    pub fn to_response(&self) -> HttpResponse {
+        match self {
+            AuthFailure::Unauthorized(challenges) => {
+                let body = " { \"message\": \"Unauthorized\"}".as_bytes().to_vec();
+                let mut response = HttpResponse::create(401, String::from("application/json"), Some(body));
+                response.add_header("WWW-Authenticate".to_string(), challenges.join(", "));
+                response
+            }
+            AuthFailure::Forbidden(_) => {
+                let body = " { \"message\": \"Forbidden\"}".as_bytes().to_vec();
+                HttpResponse::create(403, String::from("application/json"), Some(body))
+            }
+            AuthFailure::MissingPermission { permission, resource } => {
+                let body = json!({
+                    "message": "Forbidden",
+                    "missing_permission": permission,
+                    "resource": resource
+                });
+                HttpResponse::create(403, String::from("application/json"), Some(body.to_string().into_bytes()))
+            }
+        }
+    }
+}
+
+/// Get the credentials from the `Authorization` header if it uses the given scheme.
+fn get_authorization<'a>(request: &'a HttpRequest, scheme: &str) -> Option<&'a str> {
+    let value = request.get_header("Authorization")?;
+    let (s, credentials) = value.trim().split_once(' ')?;
+    match s.eq_ignore_ascii_case(scheme) {
+        true => Some(credentials.trim()),
+        false => None
+    }
+}
+
+/// Look up a supplied key or token, comparing against every entry so timing does not leak a match.
+fn find_secret(secrets: &[(String, String)], supplied: &str) -> Option<String> {
+    secrets
+        .iter()
+        .fold(None, |acc, (secret, principal)| {
+            match constant_time_eq(secret.as_bytes(), supplied.as_bytes()) {
+                true => Some(principal.clone()),
+                false => acc
+            }
+        })
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn is_supported_hash(hash: &str) -> bool {
+    hash.starts_with("$argon2") || hash.starts_with("$2a$") || hash.starts_with("$2b$") || hash.starts_with("$2y$")
+}
+
+fn verify_password(hash: &str, password: &str) -> bool {
+    match hash.starts_with("$argon2") {
+        true => {
+            match PasswordHash::new(hash) {
+                Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+                Err(_) => false
+            }
+        }
+        false => bcrypt::verify(password, hash).unwrap_or(false)
+    }
+}
+
+/// Verify a HMAC-SHA256 signature over the data.
+pub(crate) fn verify_hmac_sha256(secret: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    verify_hmac::<Hmac<Sha256>>(secret, data, signature)
+}
+
+fn verify_hmac<M: Mac + hmac::digest::KeyInit>(secret: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    match <M as Mac>::new_from_slice(secret) {
+        Ok(mut mac) => {
+            mac.update(data);
+            mac.verify_slice(signature).is_ok()
+        }
+        Err(_) => false
+    }
+}
+
+/// Verify a HMAC signed JWT locally and return the principal claim.
+fn verify_jwt(jc: &JwtCredentials, token: &str) -> Option<String> {
+    let parts: Vec<&str> = token.split('.').collect();
+    if parts.len() != 3 {
+        return None;
+    }
+
+    let header: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(parts[0]).ok()?).ok()?;
+    // Only the configured algorithm is accepted, never the one the token asks for.
+    if header.get("alg")?.as_str()? != jc.algorithm.name() {
+        return None;
+    }
+
+    let signature = URL_SAFE_NO_PAD.decode(parts[2]).ok()?;
+    let signed = format!("{}.{}", parts[0], parts[1]);
+    if !jc.algorithm.verify(&jc.secret, signed.as_bytes(), &signature) {
+        return None;
+    }
+
+    let claims: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(parts[1]).ok()?).ok()?;
+    let now = Utc::now().timestamp();
+
+    if let Some(exp) = claims.get("exp") {
+        if now > exp.as_i64()? + jc.leeway {
+            return None;
+        }
+    }
+
+    if let Some(nbf) = claims.get("nbf") {
+        if now + jc.leeway < nbf.as_i64()? {
+            return None;
+        }
+    }
+
+    if let Some(issuer) = &jc.issuer {
+        if claims.get("iss")?.as_str()? != issuer {
+            return None;
+        }
+    }
+
+    if let Some(audience) = &jc.audience {
+        let matched = match claims.get("aud")? {
+            Value::String(a) => a == audience,
+            Value::Array(a) => a.iter().any(|v| v.as_str() == Some(audience.as_str())),
+            _ => false
+        };
+        if !matched {
+            return None;
+        }
+    }
+
+    claims.get(&jc.principal_claim)?.as_str().map(|s| s.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::password_hash::{PasswordHasher, SaltString};
+    use crate::http::HttpRequestHeader;
+    use super::*;
+
+    const SECRET: &[u8] = b"test-secret";
+
+    fn jwt_credentials(leeway: i64) -> JwtCredentials {
+        JwtCredentials {
+            realm: "test".to_string(),
+            algorithm: JwtAlgorithm::HS256,
+            secret: SECRET.to_vec(),
+            issuer: Some("issuer".to_string()),
+            audience: Some("waiter".to_string()),
+            principal_claim: "sub".to_string(),
+            leeway,
+        }
+    }
+
+    fn encode(value: &Value) -> String {
+        URL_SAFE_NO_PAD.encode(value.to_string())
+    }
+
+    /// A token signed with HMAC-SHA256 over the header and claims given.
+    fn token(header: &Value, claims: &Value, secret: &[u8]) -> String {
+        let signed = format!("{}.{}", encode(header), encode(claims));
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret).unwrap();
+        mac.update(signed.as_bytes());
+        format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes()))
+    }
+
+    fn claims() -> Value {
+        json!({ "sub": "ci", "iss": "issuer", "aud": "waiter", "exp": Utc::now().timestamp() + 60 })
+    }
+
+    fn request(authorization: &str) -> HttpRequest {
+        let header = HttpRequestHeader::parse_from_string(format!("GET / HTTP/1.1\r\nAuthorization: {}\r\n", authorization)).unwrap();
+        HttpRequest::create(header, None).unwrap()
+    }
+
+    #[test]
+    fn accepts_valid_jwt() {
+        let t = token(&json!({ "alg": "HS256", "typ": "JWT" }), &claims(), SECRET);
+        assert_eq!(verify_jwt(&jwt_credentials(0), &t), Some("ci".to_string()));
+    }
+
+    #[test]
+    fn rejects_other_algorithms() {
+        let t = token(&json!({ "alg": "HS512" }), &claims(), SECRET);
+        assert_eq!(verify_jwt(&jwt_credentials(0), &t), None);
+
+        // An unsigned token is refused even with an empty signature.
+        let unsigned = format!("{}.{}.", encode(&json!({ "alg": "none" })), encode(&claims()));
+        assert_eq!(verify_jwt(&jwt_credentials(0), &unsigned), None);
+
+        let missing = token(&json!({ "typ": "JWT" }), &claims(), SECRET);
+        assert_eq!(verify_jwt(&jwt_credentials(0), &missing), None);
+    }
+
+    #[test]
+    fn rejects_bad_signature() {
+        let t = token(&json!({ "alg": "HS256" }), &claims(), b"other-secret");
+        assert_eq!(verify_jwt(&jwt_credentials(0), &t), None);
+
+        // Claims changed after signing.
+        let t = token(&json!({ "alg": "HS256" }), &claims(), SECRET);
+        let parts: Vec<&str> = t.split('.').collect();
+        let forged = format!("{}.{}.{}", parts[0], encode(&json!({ "sub": "admin", "iss": "issuer", "aud": "waiter" })), parts[2]);
+        assert_eq!(verify_jwt(&jwt_credentials(0), &forged), None);
+    }
+
+    #[test]
+    fn checks_expiry_with_leeway() {
+        let now = Utc::now().timestamp();
+        let mut expired = claims();
+        expired["exp"] = json!(now - 30);
+        let t = token(&json!({ "alg": "HS256" }), &expired, SECRET);
+        assert_eq!(verify_jwt(&jwt_credentials(0), &t), None);
+        assert_eq!(verify_jwt(&jwt_credentials(60), &t), Some("ci".to_string()));
+
+        let mut invalid = claims();
+        invalid["exp"] = json!("tomorrow");
+        assert_eq!(verify_jwt(&jwt_credentials(0), &token(&json!({ "alg": "HS256" }), &invalid, SECRET)), None);
+    }
+
+    #[test]
+    fn checks_not_before_with_leeway() {
+        let now = Utc::now().timestamp();
+        let mut early = claims();
+        early["nbf"] = json!(now + 30);
+        let t = token(&json!({ "alg": "HS256" }), &early, SECRET);
+        assert_eq!(verify_jwt(&jwt_credentials(0), &t), None);
+        assert_eq!(verify_jwt(&jwt_credentials(60), &t), Some("ci".to_string()));
+
+        let mut started = claims();
+        started["nbf"] = json!(now - 30);
+        assert_eq!(verify_jwt(&jwt_credentials(0), &token(&json!({ "alg": "HS256" }), &started, SECRET)), Some("ci".to_string()));
+    }
+
+    #[test]
+    fn checks_issuer_and_audience() {
+        let mut other = claims();
+        other["iss"] = json!("someone-else");
+        assert_eq!(verify_jwt(&jwt_credentials(0), &token(&json!({ "alg": "HS256" }), &other, SECRET)), None);
+
+        let mut audiences = claims();
+        audiences["aud"] = json!(["other", "waiter"]);
+        assert_eq!(verify_jwt(&jwt_credentials(0), &token(&json!({ "alg": "HS256" }), &audiences, SECRET)), Some("ci".to_string()));
+
+        audiences["aud"] = json!(["other"]);
+        assert_eq!(verify_jwt(&jwt_credentials(0), &token(&json!({ "alg": "HS256" }), &audiences, SECRET)), None);
+    }
+
+    #[test]
+    fn rejects_malformed_tokens() {
+        let t = token(&json!({ "alg": "HS256" }), &claims(), SECRET);
+        let parts: Vec<&str> = t.split('.').collect();
+        let credentials = jwt_credentials(0);
+
+        assert_eq!(verify_jwt(&credentials, &format!("{}.{}", parts[0], parts[1])), None);
+        assert_eq!(verify_jwt(&credentials, &format!("{}.{}", t, parts[2])), None);
+        assert_eq!(verify_jwt(&credentials, &format!("{}!.{}.{}", parts[0], parts[1], parts[2])), None);
+        assert_eq!(verify_jwt(&credentials, &format!("{}.{}.{}*", parts[0], parts[1], parts[2])), None);
+        // Standard base64 padding is not part of the encoding.
+        assert_eq!(verify_jwt(&credentials, &format!("{}==.{}.{}", parts[0], parts[1], parts[2])), None);
+        assert_eq!(verify_jwt(&credentials, ""), None);
+    }
+
+    fn basic(users: Vec<(&str, String)>) -> CredentialSet {
+        CredentialSet::create_basic("test".to_string(), users.into_iter().map(|(u, h)| (u.to_string(), h)).collect()).unwrap()
+    }
+
+    fn basic_request(username: &str, password: &str) -> HttpRequest {
+        request(&format!("Basic {}", STANDARD.encode(format!("{}:{}", username, password))))
+    }
+
+    #[test]
+    fn checks_argon2_passwords() {
+        let salt = SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").unwrap();
+        let hash = Argon2::default().hash_password(b"correct horse", &salt).unwrap().to_string();
+        let set = basic(vec![("alice", hash)]);
+
+        assert_eq!(set.authenticate(&basic_request("alice", "correct horse")), Some("alice".to_string()));
+        assert_eq!(set.authenticate(&basic_request("alice", "wrong horse")), None);
+        assert_eq!(set.authenticate(&basic_request("bob", "correct horse")), None);
+    }
+
+    #[test]
+    fn checks_bcrypt_passwords() {
+        let hash = bcrypt::hash("correct horse", 4).unwrap();
+        let set = basic(vec![("alice", hash)]);
+
+        assert_eq!(set.authenticate(&basic_request("alice", "correct horse")), Some("alice".to_string()));
+        assert_eq!(set.authenticate(&basic_request("alice", "wrong horse")), None);
+    }
+
+    #[test]
+    fn rejects_malformed_basic_credentials() {
+        let set = basic(vec![("alice", bcrypt::hash("pw", 4).unwrap())]);
+
+        assert_eq!(set.authenticate(&request("Basic !!!not-base64")), None);
+        assert_eq!(set.authenticate(&request(&format!("Basic {}", STANDARD.encode("alice")))), None);
+        assert_eq!(set.authenticate(&request(&format!("Bearer {}", STANDARD.encode("alice:pw")))), None);
+    }
+
+    #[test]
+    fn refuses_unsupported_hashes() {
+        assert!(CredentialSet::create_basic("test".to_string(), HashMap::from([("alice".to_string(), "plaintext".to_string())])).is_err());
+        assert!(!verify_password("$argon2id$broken", "pw"));
+        assert!(!verify_password("$2b$broken", "pw"));
+    }
+}
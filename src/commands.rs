@@ -1,8 +1,36 @@
 ﻿use std::str;
-use std::process::{Command, ExitStatus, Output};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Command, ExitStatus, Output, Stdio};
 use std::str::Utf8Error;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::Serialize;
 use serde_json::Value;
 
+/// How often a command with a timeout is checked for having exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+pub enum CommandError {
+    Failed(&'static str),
+    /// The command ran past its timeout and was killed.
+    TimedOut,
+}
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// Called with each line a command writes, as it is written.
+pub type OutputSink = Arc<dyn Fn(OutputStream, &str) + Send + Sync>;
+
 pub fn run_command(name: &String, args: &Vec<String>) -> Result<Output, &'static str> {
     let mut command = Command::new(name);
     let output =
@@ -19,22 +47,68 @@ pub fn run_command(name: &String, args: &Vec<String>) -> Result<Output, &'static
     }
 }
 
-pub fn run_command_static(name: String, args: Vec<String>) -> Result<Output, &'static str> {
+/// Run a command, killing it if it has not exited within `timeout`. Each line of output is passed to the sink as it arrives.
+pub fn run_command_with_timeout(name: &str, args: &[String], cwd: Option<&str>, env: &HashMap<String, String>, timeout: Option<Duration>, sink: Option<&OutputSink>) -> Result<Output, CommandError> {
     let mut command = Command::new(name);
-    let output =
-        args
-            .iter()
-            .fold(&mut command, |mut acc, arg| acc.arg(arg))
-            .output();
-
-    match output {
-        Ok(output) => Ok(output),
-        Err(e) => {
-            Err("Error running command.")
-        }
+    if let Some(dir) = cwd {
+        command.current_dir(dir);
     }
+    let mut child =
+        command
+            .args(args)
+            .envs(env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|_| CommandError::Failed("Error running command."))?;
+
+    // Read the pipes while waiting so a command with a lot of output can not fill them and block.
+    let stdout = child.stdout.take().map(|p| read_pipe(p, OutputStream::Stdout, sink.cloned()));
+    let stderr = child.stderr.take().map(|p| read_pipe(p, OutputStream::Stderr, sink.cloned()));
+
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let status =
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) => break status,
+                Ok(None) => {}
+                Err(_) => return Err(CommandError::Failed("Error waiting for command."))
+            }
+            if deadline.map(|d| Instant::now() >= d).unwrap_or(false) {
+                child.kill().ok();
+                child.wait().ok();
+                return Err(CommandError::TimedOut);
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+
+    Ok(Output {
+        status,
+        stdout: stdout.and_then(|h| h.join().ok()).unwrap_or_default(),
+        stderr: stderr.and_then(|h| h.join().ok()).unwrap_or_default(),
+    })
 }
 
+fn read_pipe<R: Read + Send + 'static>(pipe: R, stream: OutputStream, sink: Option<OutputSink>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut data = vec![];
+        let mut reader = BufReader::new(pipe);
+        loop {
+            let start = data.len();
+            match reader.read_until(b'\n', &mut data) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if let Some(sink) = &sink {
+                        let line = String::from_utf8_lossy(&data[start..]);
+                        sink(stream, line.trim_end_matches(['\r', '\n']));
+                    }
+                }
+            }
+        }
+        data
+    })
+}
 
 pub fn format_output(output: Output) -> Result<String, &'static str> {
     match output.status.success() {
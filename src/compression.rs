@@ -0,0 +1,194 @@
+use std::fs::File;
+use std::io::Write;
+use flate2::Compression;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use crate::http::HttpResponse;
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum ContentEncoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+/// Response compression settings. Encodings are listed in server preference order,
+/// which breaks ties between encodings the client weights equally.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct CompressionPolicy {
+    encodings: Vec<ContentEncoding>,
+    min_size: usize,
+    /// Content types to compress, entries ending in `/` match a whole type (e.g. `text/`).
+    mime_types: Vec<String>,
+    /// Serve `.br`/`.gz` siblings of static files when they exist.
+    precompressed: bool,
+    level: u32,
+}
+
+impl ContentEncoding {
+    pub fn from_str(data: &str) -> Result<ContentEncoding, &'static str> {
+        match data.to_lowercase().as_str() {
+            "br" => Ok(ContentEncoding::Brotli),
+            "gzip" => Ok(ContentEncoding::Gzip),
+            "deflate" => Ok(ContentEncoding::Deflate),
+            _ => Err("Unknown content encoding")
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+        }
+    }
+
+    /// The file extension used for precompressed static files.
+    fn extension(&self) -> Option<&'static str> {
+        match self {
+            ContentEncoding::Brotli => Some("br"),
+            ContentEncoding::Gzip => Some("gz"),
+            ContentEncoding::Deflate => None,
+        }
+    }
+
+    fn encode(&self, data: &[u8], level: u32) -> Result<Vec<u8>, &'static str> {
+        match self {
+            ContentEncoding::Brotli => {
+                let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, level.min(11), 22);
+                writer.write_all(data).map_err(|_| "Brotli compression failed")?;
+                Ok(writer.into_inner())
+            }
+            ContentEncoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level.min(9)));
+                encoder.write_all(data).map_err(|_| "Gzip compression failed")?;
+                encoder.finish().map_err(|_| "Gzip compression failed")
+            }
+            // The http `deflate` coding is the zlib format.
+            ContentEncoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level.min(9)));
+                encoder.write_all(data).map_err(|_| "Deflate compression failed")?;
+                encoder.finish().map_err(|_| "Deflate compression failed")
+            }
+        }
+    }
+}
+
+impl CompressionPolicy {
+    pub fn new(encodings: Vec<ContentEncoding>, min_size: usize, mime_types: Vec<String>, precompressed: bool, level: u32) -> CompressionPolicy {
+        CompressionPolicy { encodings, min_size, mime_types, precompressed, level }
+    }
+
+    /// Pick the encoding to use from an `Accept-Encoding` header value.
+    pub fn negotiate(&self, accept_encoding: Option<&String>) -> Option<ContentEncoding> {
+        let accepted: Vec<(String, f32)> =
+            accept_encoding?
+                .split(',')
+                .filter_map(|item| {
+                    let mut parts = item.split(';');
+                    let name = parts.next()?.trim().to_lowercase();
+                    let q =
+                        parts
+                            .filter_map(|p| p.trim().strip_prefix("q="))
+                            .filter_map(|q| q.trim().parse::<f32>().ok())
+                            .next()
+                            .unwrap_or(1.0);
+                    Some((name, q))
+                })
+                .collect();
+
+        let weight = |encoding: &ContentEncoding| -> f32 {
+            accepted
+                .iter()
+                .find(|(n, _)| n == encoding.name())
+                .or(accepted.iter().find(|(n, _)| n == "*"))
+                .map(|(_, q)| *q)
+                .unwrap_or(0.0)
+        };
+
+        self.encodings
+            .iter()
+            .filter(|e| weight(e) > 0.0)
+            .fold(None, |acc: Option<&ContentEncoding>, e| match acc {
+                Some(best) if weight(best) >= weight(e) => Some(best),
+                _ => Some(e)
+            })
+            .cloned()
+    }
+
+    fn is_compressible(&self, content_type: &str) -> bool {
+        let mime = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+        self.mime_types
+            .iter()
+            .any(|m| match m.ends_with('/') {
+                true => mime.starts_with(m.as_str()),
+                false => &mime == m
+            })
+    }
+
+    /// Open a precompressed sibling of a static file in an encoding the client accepts, if there is one.
+    pub fn open_static(&self, path: &str, content_type: &str, accept_encoding: Option<&String>) -> Option<(File, ContentEncoding)> {
+        if !self.precompressed || !self.is_compressible(content_type) {
+            return None;
+        }
+
+        let encoding = self.negotiate(accept_encoding)?;
+        let extension = encoding.extension()?;
+        File::open(format!("{}.{}", path, extension)).ok().map(|file| (file, encoding))
+    }
+
+    /// True if a response of this type and length would be compressed for the client.
+    pub fn would_compress(&self, content_type: &str, accept_encoding: Option<&String>, length: u64) -> bool {
+        self.is_compressible(content_type)
+            && length >= self.min_size as u64
+            && self.negotiate(accept_encoding).is_some()
+    }
+
+    /// Compress the response body in place if it is eligible and the client accepts an encoding.
+    pub fn apply(&self, accept_encoding: Option<&String>, response: &mut HttpResponse) {
+        // File bodies are sent as they are, precompressed or not.
+        if response.file.is_some() {
+            if self.is_compressible(&response.content_type) {
+                response.add_vary("Accept-Encoding");
+            }
+            return;
+        }
+
+        let length =
+            match &response.body {
+                None => return,
+                Some(b) => b.len()
+            };
+
+        if !self.is_compressible(&response.content_type) {
+            return;
+        }
+
+        // Compressible responses vary by `Accept-Encoding` even when this client gets them raw.
+        response.add_vary("Accept-Encoding");
+
+        if response.headers.contains_key("Content-Encoding") || length < self.min_size {
+            return;
+        }
+
+        let encoding =
+            match self.negotiate(accept_encoding) {
+                None => return,
+                Some(e) => e
+            };
+
+        let compressed =
+            match response.body.as_ref().map(|b| encoding.encode(b, self.level)) {
+                Some(Ok(c)) => c,
+                _ => return
+            };
+
+        // Only worth sending if it actually saved something.
+        if compressed.len() < length {
+            response.set_body(Some(compressed));
+            response.add_header("Content-Encoding".to_string(), encoding.name().to_string());
+        }
+    }
+}
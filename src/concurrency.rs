@@ -0,0 +1,195 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use serde::Serialize;
+use uuid::Uuid;
+
+/// Holds back job runs until their job's concurrency limit and named locks allow them to start.
+/// Runs are admitted in the order they arrive, a run never starts ahead of an earlier one waiting
+/// for the same job or lock. `T` is what is handed back when a run is admitted. Clones share the same state.
+pub struct RunGate<T> {
+    state: Arc<Mutex<GateState<T>>>,
+}
+
+struct GateState<T> {
+    /// Admitted runs that have not finished.
+    active: Vec<RunRequest>,
+    waiting: VecDeque<(RunRequest, T)>,
+}
+
+/// A run asking to start.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct RunRequest {
+    pub id: Uuid,
+    pub job: String,
+    /// The most runs of the job admitted at once, unlimited if not set.
+    pub limit: Option<usize>,
+    /// Locks held from when the run is admitted until it finishes.
+    pub locks: Vec<String>,
+}
+
+pub enum Admission<T> {
+    Start(T),
+    Wait,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Serialize)]
+pub struct RunRef {
+    pub run_id: Uuid,
+    pub job: String,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Serialize)]
+pub struct LockStatus {
+    pub name: String,
+    pub holder: Option<RunRef>,
+    /// Runs waiting for the lock, in the order they will get it.
+    pub waiting: Vec<RunRef>,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Serialize)]
+pub struct JobConcurrency {
+    pub name: String,
+    pub limit: usize,
+    pub active: Vec<RunRef>,
+    pub waiting: Vec<RunRef>,
+}
+
+impl<T> Clone for RunGate<T> {
+    fn clone(&self) -> Self {
+        RunGate { state: self.state.clone() }
+    }
+}
+
+impl RunRequest {
+    fn to_ref(&self) -> RunRef {
+        RunRef { run_id: self.id, job: self.job.clone() }
+    }
+
+    /// True if the runs can not be admitted side by side, or one must not overtake the other.
+    fn conflicts(&self, other: &RunRequest) -> bool {
+        (self.job == other.job && self.limit.is_some()) || self.locks.iter().any(|l| other.locks.contains(l))
+    }
+}
+
+impl<T> RunGate<T> {
+    pub fn new() -> RunGate<T> {
+        RunGate { state: Arc::new(Mutex::new(GateState { active: vec![], waiting: VecDeque::new() })) }
+    }
+
+    /// Admit the run if it can start now, otherwise hold it until it can.
+    pub fn admit(&self, request: RunRequest, payload: T) -> Admission<T> {
+        let mut state = self.lock();
+        let blocked = state.waiting.iter().any(|(w, _)| w.conflicts(&request));
+        match !blocked && state.can_start(&request) {
+            true => {
+                state.active.push(request);
+                Admission::Start(payload)
+            }
+            false => {
+                state.waiting.push_back((request, payload));
+                Admission::Wait
+            }
+        }
+    }
+
+    /// Release a finished run's slot and locks, returning the runs that can now start.
+    pub fn release(&self, id: Uuid) -> Vec<T> {
+        let mut state = self.lock();
+        state.active.retain(|r| r.id != id);
+
+        let mut admitted = vec![];
+        let mut still_waiting: VecDeque<(RunRequest, T)> = VecDeque::new();
+        while let Some((request, payload)) = state.waiting.pop_front() {
+            let blocked = still_waiting.iter().any(|(w, _)| w.conflicts(&request));
+            if !blocked && state.can_start(&request) {
+                state.active.push(request);
+                admitted.push(payload);
+            } else {
+                still_waiting.push_back((request, payload));
+            }
+        }
+        state.waiting = still_waiting;
+        admitted
+    }
+
+    /// Take a run that has not been admitted out of the gate.
+    pub fn withdraw(&self, id: Uuid) -> Option<T> {
+        let mut state = self.lock();
+        let position = state.waiting.iter().position(|(r, _)| r.id == id)?;
+        state.waiting.remove(position).map(|(_, p)| p)
+    }
+
+    /// The admitted and waiting runs of a job, oldest first.
+    pub fn runs_of(&self, job: &str) -> Vec<Uuid> {
+        let state = self.lock();
+        state.active.iter().chain(state.waiting.iter().map(|(r, _)| r)).filter(|r| r.job == job).map(|r| r.id).collect()
+    }
+
+    /// The oldest run of a job that has not been admitted.
+    pub fn waiting_run(&self, job: &str) -> Option<Uuid> {
+        self.lock().waiting.iter().find(|(r, _)| r.job == job).map(|(r, _)| r.id)
+    }
+
+    /// Every lock that is held or waited for, ordered by name.
+    pub fn locks(&self) -> Vec<LockStatus> {
+        let state = self.lock();
+        let mut names: Vec<&String> = state.active.iter().chain(state.waiting.iter().map(|(r, _)| r)).flat_map(|r| r.locks.iter()).collect();
+        names.sort();
+        names.dedup();
+
+        names
+            .into_iter()
+            .map(|name| LockStatus {
+                name: name.clone(),
+                holder: state.active.iter().find(|r| r.locks.contains(name)).map(|r| r.to_ref()),
+                waiting: state.waiting.iter().filter(|(r, _)| r.locks.contains(name)).map(|(r, _)| r.to_ref()).collect(),
+            })
+            .collect()
+    }
+
+    /// The admitted and waiting runs of every job with a concurrency limit, ordered by name.
+    pub fn jobs(&self) -> Vec<JobConcurrency> {
+        let state = self.lock();
+        let mut jobs: Vec<JobConcurrency> = vec![];
+        for request in state.active.iter().chain(state.waiting.iter().map(|(r, _)| r)) {
+            let limit =
+                match request.limit {
+                    None => continue,
+                    Some(l) => l
+                };
+            if !jobs.iter().any(|j| j.name == request.job) {
+                jobs.push(JobConcurrency {
+                    name: request.job.clone(),
+                    limit,
+                    active: state.active.iter().filter(|r| r.job == request.job).map(|r| r.to_ref()).collect(),
+                    waiting: state.waiting.iter().filter(|(r, _)| r.job == request.job).map(|(r, _)| r.to_ref()).collect(),
+                });
+            }
+        }
+        jobs.sort_by(|a, b| a.name.cmp(&b.name));
+        jobs
+    }
+
+    fn lock(&self) -> MutexGuard<'_, GateState<T>> {
+        self.state.lock().unwrap_or_else(|p| p.into_inner())
+    }
+}
+
+impl<T> GateState<T> {
+    /// True if the run is under its job's limit and none of its locks are held.
+    fn can_start(&self, request: &RunRequest) -> bool {
+        let under_limit =
+            match request.limit {
+                None => true,
+                Some(limit) => self.active.iter().filter(|r| r.job == request.job).count() < limit
+            };
+        under_limit && !self.active.iter().any(|r| r.locks.iter().any(|l| request.locks.contains(l)))
+    }
+}
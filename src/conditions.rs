@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+
+/// A `when` condition deciding whether an action runs, for example
+/// `actions.build.state == 'completed' && branch == 'main'`.
+/// Names are looked up in the run's values and compare as strings, a name on its own is true if its value is `true`.
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum Condition {
+    Equals(Operand, Operand),
+    NotEquals(Operand, Operand),
+    Truthy(Operand),
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum Operand {
+    Literal(String),
+    Name(String),
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Not,
+    And,
+    Or,
+    Equals,
+    NotEquals,
+    Literal(String),
+    Name(String),
+}
+
+impl Condition {
+    pub fn parse(data: &str) -> Result<Condition, &'static str> {
+        let tokens = tokenize(data)?;
+        let mut position = 0;
+        let condition = parse_or(&tokens, &mut position)?;
+        match position == tokens.len() {
+            true => Ok(condition),
+            false => Err("Unexpected token in condition")
+        }
+    }
+
+    pub fn evaluate(&self, values: &HashMap<String, String>) -> bool {
+        match self {
+            Condition::Equals(a, b) => a.value(values) == b.value(values),
+            Condition::NotEquals(a, b) => a.value(values) != b.value(values),
+            Condition::Truthy(a) => a.value(values) == "true",
+            Condition::Not(c) => !c.evaluate(values),
+            Condition::And(a, b) => a.evaluate(values) && b.evaluate(values),
+            Condition::Or(a, b) => a.evaluate(values) || b.evaluate(values),
+        }
+    }
+
+    /// Every name the condition looks up.
+    pub fn names(&self) -> Vec<&String> {
+        match self {
+            Condition::Equals(a, b) | Condition::NotEquals(a, b) => a.name().into_iter().chain(b.name()).collect(),
+            Condition::Truthy(a) => a.name().into_iter().collect(),
+            Condition::Not(c) => c.names(),
+            Condition::And(a, b) | Condition::Or(a, b) => a.names().into_iter().chain(b.names()).collect(),
+        }
+    }
+}
+
+impl Operand {
+    /// The operand's value, names without a value are empty.
+    fn value<'a>(&'a self, values: &'a HashMap<String, String>) -> &'a str {
+        match self {
+            Operand::Literal(l) => l,
+            Operand::Name(n) => values.get(n).map(|v| v.as_str()).unwrap_or("")
+        }
+    }
+
+    fn name(&self) -> Option<&String> {
+        match self {
+            Operand::Literal(_) => None,
+            Operand::Name(n) => Some(n)
+        }
+    }
+}
+
+fn tokenize(data: &str) -> Result<Vec<Token>, &'static str> {
+    let chars: Vec<char> = data.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match (c, next) {
+            (c, _) if c.is_whitespace() => i += 1,
+            ('(', _) => { tokens.push(Token::Open); i += 1; }
+            (')', _) => { tokens.push(Token::Close); i += 1; }
+            ('&', Some('&')) => { tokens.push(Token::And); i += 2; }
+            ('|', Some('|')) => { tokens.push(Token::Or); i += 2; }
+            ('=', Some('=')) => { tokens.push(Token::Equals); i += 2; }
+            ('!', Some('=')) => { tokens.push(Token::NotEquals); i += 2; }
+            ('!', _) => { tokens.push(Token::Not); i += 1; }
+            ('\'', _) | ('"', _) => {
+                let end =
+                    match chars[i + 1..].iter().position(|q| *q == c) {
+                        None => return Err("Unterminated string in condition"),
+                        Some(e) => i + 1 + e
+                    };
+                tokens.push(Token::Literal(chars[i + 1..end].iter().collect()));
+                i = end + 1;
+            }
+            (c, _) if is_name_char(c) => {
+                let end = chars[i..].iter().position(|n| !is_name_char(*n)).map(|e| i + e).unwrap_or(chars.len());
+                let word: String = chars[i..end].iter().collect();
+                // Numbers and bools are literals, anything else is looked up.
+                let token =
+                    match word.as_str() {
+                        "true" | "false" => Token::Literal(word),
+                        w if w.starts_with(|d: char| d.is_ascii_digit()) => Token::Literal(word),
+                        _ => Token::Name(word)
+                    };
+                tokens.push(token);
+                i = end;
+            }
+            _ => return Err("Unexpected character in condition")
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-' || c == '.'
+}
+
+fn parse_or(tokens: &[Token], position: &mut usize) -> Result<Condition, &'static str> {
+    let mut condition = parse_and(tokens, position)?;
+    while tokens.get(*position) == Some(&Token::Or) {
+        *position += 1;
+        condition = Condition::Or(Box::new(condition), Box::new(parse_and(tokens, position)?));
+    }
+    Ok(condition)
+}
+
+fn parse_and(tokens: &[Token], position: &mut usize) -> Result<Condition, &'static str> {
+    let mut condition = parse_unary(tokens, position)?;
+    while tokens.get(*position) == Some(&Token::And) {
+        *position += 1;
+        condition = Condition::And(Box::new(condition), Box::new(parse_unary(tokens, position)?));
+    }
+    Ok(condition)
+}
+
+fn parse_unary(tokens: &[Token], position: &mut usize) -> Result<Condition, &'static str> {
+    match tokens.get(*position) {
+        Some(Token::Not) => {
+            *position += 1;
+            Ok(Condition::Not(Box::new(parse_unary(tokens, position)?)))
+        }
+        Some(Token::Open) => {
+            *position += 1;
+            let condition = parse_or(tokens, position)?;
+            match tokens.get(*position) {
+                Some(Token::Close) => {
+                    *position += 1;
+                    Ok(condition)
+                }
+                _ => Err("Missing closing bracket in condition")
+            }
+        }
+        _ => {
+            let left = parse_operand(tokens, position)?;
+            match tokens.get(*position) {
+                Some(Token::Equals) => {
+                    *position += 1;
+                    Ok(Condition::Equals(left, parse_operand(tokens, position)?))
+                }
+                Some(Token::NotEquals) => {
+                    *position += 1;
+                    Ok(Condition::NotEquals(left, parse_operand(tokens, position)?))
+                }
+                _ => Ok(Condition::Truthy(left))
+            }
+        }
+    }
+}
+
+fn parse_operand(tokens: &[Token], position: &mut usize) -> Result<Operand, &'static str> {
+    let operand =
+        match tokens.get(*position) {
+            Some(Token::Literal(l)) => Operand::Literal(l.clone()),
+            Some(Token::Name(n)) => Operand::Name(n.clone()),
+            _ => return Err("Expected a name or value in condition")
+        };
+    *position += 1;
+    Ok(operand)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn holds(condition: &str, pairs: &[(&str, &str)]) -> bool {
+        Condition::parse(condition).unwrap().evaluate(&values(pairs))
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        // Read as `a || (b && c)`.
+        assert!(holds("a || b && c", &[("a", "true")]));
+        assert!(!holds("(a || b) && c", &[("a", "true")]));
+        assert!(holds("a && b || c", &[("c", "true")]));
+        assert!(!holds("a && (b || c)", &[("c", "true")]));
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and() {
+        assert!(holds("!a && b", &[("b", "true")]));
+        assert!(!holds("!(a && b)", &[("a", "true"), ("b", "true")]));
+        assert!(holds("!!a", &[("a", "true")]));
+        assert!(holds("!a == 'x'", &[("a", "y")]));
+    }
+
+    #[test]
+    fn compares_as_strings() {
+        let pairs = [("branch", "main"), ("count", "3"), ("actions.build.state", "completed")];
+        assert!(holds("branch == 'main'", &pairs));
+        assert!(holds("branch != \"dev\"", &pairs));
+        assert!(holds("count == 3", &pairs));
+        assert!(!holds("count == 03", &pairs));
+        assert!(holds("actions.build.state == 'completed' && branch == 'main'", &pairs));
+        assert!(holds("'main' == branch", &pairs));
+    }
+
+    #[test]
+    fn quoted_values_are_literals() {
+        let pairs = [("branch", "main"), ("main", "other")];
+        assert!(holds("branch == 'main'", &pairs));
+        assert!(!holds("branch == main", &pairs));
+        // Operators and the other quote inside a string are part of it.
+        assert!(holds("title == 'a && \"b\" || !c'", &[("title", "a && \"b\" || !c")]));
+        assert!(holds("empty == ''", &[]));
+        assert!(holds("'true'", &[]));
+    }
+
+    #[test]
+    fn only_true_is_truthy() {
+        assert!(holds("flag", &[("flag", "true")]));
+        assert!(!holds("flag", &[("flag", "yes")]));
+        assert!(!holds("flag", &[("flag", "1")]));
+        assert!(holds("true", &[]));
+        assert!(!holds("false", &[]));
+    }
+
+    #[test]
+    fn unknown_names_are_empty_and_reported() {
+        assert!(!holds("missing", &[]));
+        assert!(holds("missing == ''", &[]));
+        assert!(!holds("missing == 'x'", &[]));
+
+        let condition = Condition::parse("(a == 'x' || !b) && c != d && 'lit' == 1").unwrap();
+        let names: Vec<&str> = condition.names().into_iter().map(|n| n.as_str()).collect();
+        assert_eq!(names, vec!["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn rejects_malformed_conditions() {
+        for condition in ["", "a ==", "== a", "a && ", "(a", "a)", "a b", "'open", "a = b", "a & b", "a | b", "a == b == c", "a > b", "()"] {
+            assert!(Condition::parse(condition).is_err(), "`{}` should not parse", condition);
+        }
+    }
+}
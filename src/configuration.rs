@@ -1,29 +1,255 @@
-﻿use std::collections::HashMap;
+﻿use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::process::Output;
-use std::sync::mpsc::Sender;
+use std::time::Duration;
+use std::sync::Arc;
 use regex::Regex;
 use serde_json::{Map, Value};
+use uuid::Uuid;
+use crate::access::{AccessList, AccessPolicy, ClientIpHeader, ClientIpResolver, IpNetwork};
+use crate::auth::{Authenticator, Authorizer, CredentialSet, JobPermission, JwtAlgorithm, Role, RouteAuth};
 use crate::commands::format_output;
+use crate::compression::{CompressionPolicy, ContentEncoding};
+use crate::conditions::Condition;
+use crate::cors::CorsPolicy;
 use crate::http::HttpResponse;
-use crate::orchestration::{Job, JobCommand, JobHandler};
-use crate::routing::{Route, RouteHandler, RouteMap};
+use crate::job_log::JobLogSettings;
+use crate::metrics::Metrics;
+use crate::orchestration::{ActionFailure, Job, JobContext, JobHandler, JobRunState};
+use crate::parameters::{check_value, matrix_key, output_key, placeholders, state_key};
+use crate::rate_limiting::{RateLimitKey, RateLimiter};
+use crate::routing::{Route, RouteDefaults, RouteHandler, RouteMap};
+use crate::server::Timeouts;
+use crate::connection_pool::ConnectionLimits;
+use crate::shutdown::{JobShutdownMode, ShutdownPolicy};
+use crate::webhooks::{WebhookProvider, WebhookRoute};
+
+/// The queue for jobs that do not name one.
+pub const DEFAULT_QUEUE: &str = "default";
+
+const DEFAULT_QUEUE_IDLE_TIMEOUT_MS: u64 = 30000;
 
 pub struct Configuration {
     pub name: String,
     pub address: String,
+    pub access: AccessPolicy,
+    pub timeouts: Timeouts,
+    pub limits: ConnectionLimits,
+    pub shutdown: ShutdownPolicy,
     pub routes: RouteMap,
+    pub metrics: Metrics,
 }
 
 #[derive(Debug)]
 pub struct JobsConfiguration {
     jobs: HashMap<String, JobConfiguration>,
+    queues: Vec<QueueConfiguration>,
+    job_log: Option<JobLogSettings>,
 }
 
 #[derive(Debug)]
 pub struct JobConfiguration {
     pub name: String,
-    pub actions: Vec<ActionType>,
+    /// The queue whose workers run the job's actions.
+    pub queue: String,
+    /// The priority of runs not given one when triggered, higher runs first.
+    pub priority: i64,
+    /// What happens to a run found part way through in the job log at startup.
+    pub on_interrupt: InterruptPolicy,
+    pub parameters: Vec<JobParameter>,
+    pub actions: Vec<ActionConfiguration>,
+    pub permissions: Option<JobPermissions>,
+    /// Jobs queued when a run of this one finishes.
+    pub triggers: Vec<JobTrigger>,
+    pub concurrency: Option<ConcurrencyLimit>,
+    /// Named locks shared with other jobs, held by a run from when it starts until it finishes.
+    pub locks: Vec<String>,
+    /// Collapses triggers of the job arriving close together into one run.
+    pub debounce: Option<DebounceSettings>,
+}
+
+/// Triggers arriving within the window of each other collapse into a single run, started once the window
+/// passes without another trigger, or once `max_wait` has passed since the first.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct DebounceSettings {
+    /// Triggers with the same scope collapse together, the job's name or the route's pattern.
+    pub scope: String,
+    pub window: Duration,
+    pub max_wait: Option<Duration>,
+    pub merge: DebounceMerge,
+}
+
+/// How the parameters of collapsed triggers are combined.
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum DebounceMerge {
+    /// The parameters of the latest trigger are used.
+    Latest,
+    /// The parameters of every trigger are used, later values replacing earlier ones.
+    Merge,
+}
+
+/// How many runs of a job may run at once and what happens to a run triggered over the limit.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct ConcurrencyLimit {
+    pub limit: usize,
+    pub policy: ConcurrencyPolicy,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum ConcurrencyPolicy {
+    /// Wait for an earlier run to finish.
+    Queue,
+    /// Refuse the new run.
+    Reject,
+    /// Cancel the earlier runs, then wait for them to stop.
+    CancelPrevious,
+    /// Return a run that is already waiting rather than queuing another.
+    Coalesce,
+}
+
+/// Another job queued when a run finishes, from the `on_success`, `on_failure` and `on_complete` lists.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct JobTrigger {
+    pub job: String,
+    pub on: TriggerEvent,
+    /// Values for the triggered job's parameters, which may use the run's parameters and outputs.
+    pub parameters: HashMap<String, String>,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum TriggerEvent {
+    /// The run completed.
+    Success,
+    /// The run failed.
+    Failure,
+    /// The run completed or failed. Cancelled and interrupted runs trigger nothing.
+    Complete,
+}
+
+/// A named value supplied when a job is triggered and substituted into its actions as `{{name}}`.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct JobParameter {
+    pub name: String,
+    pub parameter_type: ParameterType,
+    /// Parameters without a default must be supplied.
+    pub default: Option<String>,
+    /// The whole value must match.
+    pub pattern: Option<Regex>,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum ParameterType {
+    String,
+    Int,
+    Bool,
+    /// One of the listed values.
+    Enum(Vec<String>),
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum InterruptPolicy {
+    /// Mark the run as interrupted.
+    Fail,
+    /// Run the actions that had not completed.
+    Resume,
+}
+
+/// A named job queue. Its worker threads grow towards `max_workers` while jobs are waiting
+/// and shrink back to `min_workers` once they have been idle for `idle_timeout`.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct QueueConfiguration {
+    pub name: String,
+    pub min_workers: usize,
+    pub max_workers: usize,
+    pub idle_timeout: Duration,
+}
+
+#[derive(Debug)]
+pub struct ActionConfiguration {
+    pub name: String,
+    pub action_type: ActionType,
+    /// The action's own retry policy, or the job's if it has none.
+    pub retry: Option<RetryPolicy>,
+    /// An attempt running longer than this is stopped and counts as failed.
+    pub timeout: Option<Duration>,
+    /// Values published for the actions after this one in the same run.
+    pub outputs: Vec<ActionOutput>,
+    /// Only run the action if this holds. Without one the action runs unless an earlier action failed.
+    pub when: Option<Condition>,
+    /// Run an instance of the action for each combination of these values, side by side.
+    pub matrix: Vec<MatrixDimension>,
+}
+
+/// One dimension of an action's matrix, its current value is referenced as `{{matrix.<name>}}`.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct MatrixDimension {
+    pub name: String,
+    /// Values may use parameter placeholders, a value rendering to a comma separated list adds each item.
+    pub values: Vec<String>,
+}
+
+/// A value an action publishes once it completes, referenced by later actions as `{{outputs.<action>.<name>}}`.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct ActionOutput {
+    pub name: String,
+    pub source: OutputSource,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum OutputSource {
+    /// The whole of standard output, trimmed.
+    Stdout,
+    /// The value of the last `<name>=<value>` line on standard output.
+    KeyValue,
+    /// The contents of a file, relative to the action's working directory.
+    File(String),
+}
+
+/// How failed action attempts are retried.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct RetryPolicy {
+    /// The most attempts made, including the first.
+    pub max_attempts: u32,
+    pub backoff: Backoff,
+    /// The wait before the first retry.
+    pub delay: Duration,
+    pub max_delay: Duration,
+    /// Wait a random time up to the backoff delay rather than the full delay.
+    pub jitter: bool,
+    /// Only retry commands exiting with one of these codes. Any failure is retried if not set.
+    pub on_exit_codes: Option<Vec<i32>>,
+    pub on_timeout: bool,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum Backoff {
+    Fixed,
+    /// The delay doubles with each retry.
+    Exponential,
+}
+
+/// The roles allowed to view, trigger and cancel a job.
+/// Jobs without permissions are not restricted beyond route authentication.
+#[derive(Debug)]
+pub struct JobPermissions {
+    pub view: Vec<String>,
+    pub trigger: Vec<String>,
+    pub cancel: Vec<String>,
 }
 
 #[derive(Debug)]
@@ -32,10 +258,14 @@ pub enum ActionType {
     Test(TestActionType),
 }
 
+#[derive(Clone)]
 #[derive(Debug)]
 pub struct CommandActionType {
     pub command_name: String,
     pub args: Vec<String>,
+    /// The working directory, the server's if not set.
+    pub cwd: Option<String>,
+    pub env: HashMap<String, String>,
 }
 
 #[derive(Debug)]
@@ -44,8 +274,8 @@ pub struct TestActionType {
 }
 
 impl Configuration {
-    pub fn load(path: String, job_handler: Sender<JobCommand>) -> Result<Configuration, &'static str> {
-        load_config(path, job_handler)
+    pub fn load(path: String, job_context: JobContext, metrics: Metrics) -> Result<Configuration, &'static str> {
+        load_config(path, job_context, metrics)
     }
 }
 
@@ -57,11 +287,134 @@ impl JobsConfiguration {
     pub fn get_job(&self, name: &str) -> Option<&JobConfiguration> {
         self.jobs.get(name)
     }
+
+    pub fn queues(&self) -> &[QueueConfiguration] {
+        &self.queues
+    }
+
+    pub fn job_log(&self) -> Option<&JobLogSettings> {
+        self.job_log.as_ref()
+    }
+}
+
+impl RetryPolicy {
+    /// True if the failure is one the policy retries.
+    pub fn should_retry(&self, failure: &ActionFailure) -> bool {
+        match (failure.timed_out, &self.on_exit_codes) {
+            (true, _) => self.on_timeout,
+            (false, None) => true,
+            (false, Some(codes)) => failure.exit_code.map(|c| codes.contains(&c)).unwrap_or(false)
+        }
+    }
+
+    /// The wait before making the given attempt, the second attempt being the first retry.
+    pub fn delay_before(&self, attempt: u32) -> Duration {
+        let delay =
+            match self.backoff {
+                Backoff::Fixed => self.delay,
+                Backoff::Exponential => self.delay.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(2)))
+            }
+            .min(self.max_delay);
+
+        match self.jitter {
+            // Full jitter, spreading out retries of actions that failed together.
+            true => Duration::from_millis((Uuid::new_v4().as_u128() % (delay.as_millis() + 1)) as u64),
+            false => delay
+        }
+    }
+}
+
+impl Backoff {
+    pub fn from_str(data: &str) -> Result<Backoff, &'static str> {
+        match data.to_lowercase().as_str() {
+            "fixed" => Ok(Backoff::Fixed),
+            "exponential" => Ok(Backoff::Exponential),
+            _ => Err("Unknown backoff")
+        }
+    }
+}
+
+impl TriggerEvent {
+    pub fn matches(&self, state: &JobRunState) -> bool {
+        match self {
+            TriggerEvent::Success => *state == JobRunState::Completed,
+            TriggerEvent::Failure => *state == JobRunState::Failed,
+            TriggerEvent::Complete => matches!(state, JobRunState::Completed | JobRunState::Failed),
+        }
+    }
+}
+
+impl ConcurrencyPolicy {
+    pub fn from_str(data: &str) -> Result<ConcurrencyPolicy, &'static str> {
+        match data.to_lowercase().as_str() {
+            "queue" => Ok(ConcurrencyPolicy::Queue),
+            "reject" => Ok(ConcurrencyPolicy::Reject),
+            "cancel_previous" => Ok(ConcurrencyPolicy::CancelPrevious),
+            "coalesce" => Ok(ConcurrencyPolicy::Coalesce),
+            _ => Err("Unknown concurrency policy")
+        }
+    }
+}
+
+impl DebounceMerge {
+    pub fn from_str(data: &str) -> Result<DebounceMerge, &'static str> {
+        match data.to_lowercase().as_str() {
+            "latest" => Ok(DebounceMerge::Latest),
+            "merge" => Ok(DebounceMerge::Merge),
+            _ => Err("Unknown debounce merge")
+        }
+    }
+}
+
+impl InterruptPolicy {
+    pub fn from_str(data: &str) -> Result<InterruptPolicy, &'static str> {
+        match data.to_lowercase().as_str() {
+            "fail" => Ok(InterruptPolicy::Fail),
+            "resume" => Ok(InterruptPolicy::Resume),
+            _ => Err("Unknown interrupt policy")
+        }
+    }
+}
+
+impl JobPermissions {
+    pub fn get_roles(&self, permission: &JobPermission) -> &Vec<String> {
+        match permission {
+            JobPermission::View => &self.view,
+            JobPermission::Trigger => &self.trigger,
+            JobPermission::Cancel => &self.cancel,
+        }
+    }
+}
+
+impl ActionConfiguration {
+    /// Every string in the action that may hold placeholders.
+    pub fn templates(&self) -> Vec<&String> {
+        let mut templates = self.action_type.templates();
+        templates.extend(self.outputs.iter().filter_map(|o| match &o.source {
+            OutputSource::File(path) => Some(path),
+            _ => None
+        }));
+        templates
+    }
 }
 
 impl ActionType {
-    pub fn create_command(name: String, args: Vec<String>) -> ActionType {
-        ActionType::Command(CommandActionType { command_name: name, args })
+    pub fn create_command(name: String, args: Vec<String>, cwd: Option<String>, env: HashMap<String, String>) -> ActionType {
+        ActionType::Command(CommandActionType { command_name: name, args, cwd, env })
+    }
+
+    /// Every string in the action that may hold parameter placeholders.
+    pub fn templates(&self) -> Vec<&String> {
+        match self {
+            ActionType::Command(c) => {
+                std::iter::once(&c.command_name)
+                    .chain(c.args.iter())
+                    .chain(c.cwd.iter())
+                    .chain(c.env.values())
+                    .collect()
+            }
+            ActionType::Test(_) => vec![]
+        }
     }
 
     pub fn create_test(wait_time: i64) -> ActionType {
@@ -76,7 +429,28 @@ fn get_string(value: &Value) -> String {
     }
 }
 
-fn load_config(path: String, job_handler: Sender<JobCommand>) -> Result<Configuration, &'static str> {
+fn get_string_array(value: &Value) -> Vec<String> {
+    match value.as_array() {
+        None => vec![],
+        Some(arr) => arr.iter().map(get_string).collect()
+    }
+}
+
+fn get_optional_string(value: Option<&Value>) -> Option<String> {
+    value.and_then(|v| v.as_str()).map(|v| v.to_string())
+}
+
+/// A string, number or bool as a string, anything else as an empty string.
+pub fn get_scalar_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Number(n) => n.to_string(),
+        Value::Bool(b) => b.to_string(),
+        _ => String::new()
+    }
+}
+
+fn load_config(path: String, job_context: JobContext, metrics: Metrics) -> Result<Configuration, &'static str> {
     let config_json = fs::read_to_string(path).expect("Fail");
     let config_json = config_json.trim_start_matches('﻿');
     let parse_result: Result<Value, serde_json::Error> = serde_json::from_str(&config_json.clone());
@@ -85,9 +459,31 @@ fn load_config(path: String, job_handler: Sender<JobCommand>) -> Result<Configur
         Ok(json) => {
             let name = get_string(&json["name"]);
             let address = get_string(&json["address"]);
+            let access = create_access_policy(&json["access"])?;
+            let timeouts = create_timeouts(&json["timeouts"]);
+            let limits = create_connection_limits(&json["connections"])?;
+            let shutdown = create_shutdown_policy(&json["shutdown"])?;
+            let authenticator = create_authenticator(&json["auth"])?;
+            let authorizer = create_authorizer(&json["auth"], job_context.config.clone())?;
             let routes_obj = json["routes"].clone();
-            let routes = create_route_map(routes_obj, job_handler)?;
-            Ok(Configuration { name, address, routes })
+            let rate_limiter =
+                match json.get("rate_limit") {
+                    None => None,
+                    Some(rl) => Some(create_rate_limiter("global".to_string(), rl, &metrics)?)
+                };
+            let cors =
+                match json.get("cors") {
+                    None => None,
+                    Some(co) => Some(create_cors_policy(co)?)
+                };
+            let compression =
+                match json.get("compression") {
+                    None => None,
+                    Some(co) => Some(create_compression_policy(co)?)
+                };
+            let defaults = RouteDefaults { rate_limiter, cors, compression };
+            let routes = create_route_map(routes_obj, job_context, authenticator, authorizer, defaults, &metrics)?;
+            Ok(Configuration { name, address, access, timeouts, limits, shutdown, routes, metrics })
         }
         Err(e) => {
             //println!("Error parsing config.json: {}", e);
@@ -96,18 +492,292 @@ fn load_config(path: String, job_handler: Sender<JobCommand>) -> Result<Configur
     }
 }
 
-fn create_route_map(mut routes_array: Value, job_handler: Sender<JobCommand>) -> Result<RouteMap, &'static str> {
+fn create_networks(value: &Value) -> Result<Vec<IpNetwork>, &'static str> {
+    get_string_array(value).iter().map(|n| IpNetwork::parse(n)).collect()
+}
+
+fn create_access_list(access_obj: &Value) -> Result<AccessList, &'static str> {
+    Ok(AccessList::new(create_networks(&access_obj["allow"])?, create_networks(&access_obj["deny"])?))
+}
+
+fn create_access_policy(access_obj: &Value) -> Result<AccessPolicy, &'static str> {
+    let header =
+        match get_optional_string(access_obj.get("client_ip_header")) {
+            None => None,
+            Some(h) => Some(ClientIpHeader::from_str(&h)?)
+        };
+    let proxy_protocol = access_obj["proxy_protocol"].as_bool().unwrap_or(false);
+    let resolver = ClientIpResolver::new(create_networks(&access_obj["trusted_proxies"])?, header, proxy_protocol);
+
+    Ok(AccessPolicy { access_list: create_access_list(access_obj)?, resolver })
+}
+
+fn create_timeouts(timeouts_obj: &Value) -> Timeouts {
+    let millis = |name: &str, default: u64| Duration::from_millis(timeouts_obj[name].as_u64().unwrap_or(default).max(1));
+    Timeouts {
+        header_read: millis("header_read_ms", 10000),
+        body_read: millis("body_read_ms", 15000),
+        write: millis("write_ms", 15000),
+        large_body_size: timeouts_obj["large_body_size"].as_u64().unwrap_or(65536) as usize,
+        min_body_rate: timeouts_obj["min_body_rate"].as_u64().unwrap_or(1024),
+        keep_alive: Duration::from_millis(timeouts_obj["keep_alive_ms"].as_u64().unwrap_or(5000)),
+    }
+}
+
+fn create_connection_limits(connections_obj: &Value) -> Result<ConnectionLimits, &'static str> {
+    let pool_size = connections_obj["pool_size"].as_u64().unwrap_or(4) as usize;
+    if pool_size == 0 {
+        return Err("Connection pool size must be at least 1");
+    }
+
+    Ok(ConnectionLimits {
+        pool_size,
+        queue_depth: connections_obj["queue_depth"].as_u64().unwrap_or(64) as usize,
+        max_per_client: connections_obj["max_per_client"].as_u64().unwrap_or(0) as usize,
+        retry_after: connections_obj["retry_after"].as_u64().unwrap_or(1),
+    })
+}
+
+fn create_shutdown_policy(shutdown_obj: &Value) -> Result<ShutdownPolicy, &'static str> {
+    let jobs =
+        match shutdown_obj["jobs"].as_str() {
+            None => JobShutdownMode::Wait,
+            Some(m) => JobShutdownMode::from_str(m)?
+        };
+
+    Ok(ShutdownPolicy {
+        drain_timeout: Duration::from_millis(shutdown_obj["drain_timeout_ms"].as_u64().unwrap_or(10000)),
+        jobs,
+        job_timeout: Duration::from_millis(shutdown_obj["job_timeout_ms"].as_u64().unwrap_or(60000)),
+        queue_path: get_optional_string(shutdown_obj.get("queue_path")),
+    })
+}
+
+fn create_rate_limiter(name: String, limit_obj: &Value, metrics: &Metrics) -> Result<RateLimiter, &'static str> {
+    let key = RateLimitKey::from_str(&get_optional_string(limit_obj.get("key")).unwrap_or_else(|| "ip".to_string()))?;
+    let values = (limit_obj["capacity"].as_f64(), limit_obj["refill_per_second"].as_f64());
+    match values {
+        (Some(capacity), Some(refill_per_second)) => {
+            let limiter = RateLimiter::new(name, key, capacity, refill_per_second)?;
+            metrics.register_rate_limiter(limiter.clone());
+            Ok(limiter)
+        }
+        (None, _) => Err("Missing rate limit capacity"),
+        (_, None) => Err("Missing rate limit refill rate")
+    }
+}
+
+fn create_cors_policy(cors_obj: &Value) -> Result<CorsPolicy, &'static str> {
+    let allowed_methods =
+        match cors_obj.get("allowed_methods") {
+            None => vec!["GET".to_string(), "HEAD".to_string(), "POST".to_string()],
+            Some(m) => get_string_array(m)
+        };
+    CorsPolicy::new(
+        get_string_array(&cors_obj["allowed_origins"]),
+        allowed_methods,
+        get_string_array(&cors_obj["allowed_headers"]),
+        get_string_array(&cors_obj["exposed_headers"]),
+        cors_obj["allow_credentials"].as_bool().unwrap_or(false),
+        cors_obj["max_age"].as_u64())
+}
+
+fn create_compression_policy(compression_obj: &Value) -> Result<CompressionPolicy, &'static str> {
+    let encodings =
+        match compression_obj.get("encodings") {
+            None => vec![ContentEncoding::Brotli, ContentEncoding::Gzip, ContentEncoding::Deflate],
+            Some(e) => {
+                get_string_array(e)
+                    .iter()
+                    .map(|n| ContentEncoding::from_str(n))
+                    .collect::<Result<Vec<ContentEncoding>, &'static str>>()?
+            }
+        };
+    let mime_types =
+        match compression_obj.get("mime_types") {
+            None => vec!["text/".to_string(), "application/json".to_string(), "application/javascript".to_string(), "image/svg+xml".to_string()],
+            Some(m) => get_string_array(m)
+        };
+    Ok(CompressionPolicy::new(
+        encodings,
+        compression_obj["min_size"].as_u64().unwrap_or(1024) as usize,
+        mime_types,
+        compression_obj["precompressed"].as_bool().unwrap_or(false),
+        compression_obj["level"].as_u64().unwrap_or(6) as u32))
+}
+
+fn create_authenticator(auth_obj: &Value) -> Result<Authenticator, &'static str> {
+    // The auth section is optional, without it no routes can require authentication.
+    if auth_obj.is_null() {
+        return Ok(Authenticator::empty());
+    }
+
+    let credentials =
+        match auth_obj.get("credentials").and_then(|c| c.as_array()) {
+            None => return Err("Auth credentials value is not an array"),
+            Some(c) => c
+        };
+
+    let mut credential_sets: HashMap<String, CredentialSet> = HashMap::new();
+
+    for co in credentials {
+        let (name, set) = create_credential_set(co)?;
+        if credential_sets.insert(name, set).is_some() {
+            return Err("Duplicate credential set name");
+        }
+    }
+
+    Ok(Authenticator::new(credential_sets))
+}
+
+fn create_credential_set(credential_obj: &Value) -> Result<(String, CredentialSet), &'static str> {
+    match credential_obj.as_object() {
+        None => Err("Credential set value is not an object."),
+        Some(co) => {
+            let get_values = (co.get("name"), co.get("type"));
+
+            match get_values {
+                (Some(name), Some(set_type)) => {
+                    let realm = get_optional_string(co.get("realm")).unwrap_or_else(|| "waiter".to_string());
+                    let set =
+                        match get_string(set_type).as_str() {
+                            "api_key" => {
+                                let header = get_optional_string(co.get("header")).unwrap_or_else(|| "X-Api-Key".to_string());
+                                match co.get("keys") {
+                                    None => Err("Missing api keys"),
+                                    Some(keys) => Ok(CredentialSet::create_api_key(header, get_secret_pairs(keys, "key")?))
+                                }
+                            }
+                            "basic" => {
+                                match co.get("users").and_then(|u| u.as_array()) {
+                                    None => Err("Missing basic auth users"),
+                                    Some(users) => {
+                                        let mut user_map = HashMap::new();
+                                        for u in users {
+                                            match (u.get("username"), u.get("password_hash")) {
+                                                (Some(username), Some(hash)) => {
+                                                    user_map.insert(get_string(username), get_string(hash));
+                                                }
+                                                (None, _) => return Err("Missing basic auth username"),
+                                                (_, None) => return Err("Missing basic auth password hash")
+                                            }
+                                        }
+                                        CredentialSet::create_basic(realm, user_map)
+                                    }
+                                }
+                            }
+                            "bearer" => {
+                                match co.get("tokens") {
+                                    None => Err("Missing bearer tokens"),
+                                    Some(tokens) => Ok(CredentialSet::create_bearer(realm, get_secret_pairs(tokens, "token")?))
+                                }
+                            }
+                            "jwt" => {
+                                let algorithm = JwtAlgorithm::from_str(&get_optional_string(co.get("algorithm")).unwrap_or_else(|| "HS256".to_string()))?;
+                                match co.get("secret") {
+                                    None => Err("Missing jwt secret"),
+                                    Some(secret) => {
+                                        Ok(CredentialSet::create_jwt(
+                                            realm,
+                                            algorithm,
+                                            get_string(secret).into_bytes(),
+                                            get_optional_string(co.get("issuer")),
+                                            get_optional_string(co.get("audience")),
+                                            get_optional_string(co.get("principal_claim")).unwrap_or_else(|| "sub".to_string()),
+                                            co.get("leeway").and_then(|l| l.as_i64()).unwrap_or(0)))
+                                    }
+                                }
+                            }
+                            _ => Err("Unknown credential set type")
+                        }?;
+                    Ok((get_string(name), set))
+                }
+                (None, _) => Err("Missing credential set name"),
+                (_, None) => Err("Missing credential set type")
+            }
+        }
+    }
+}
+
+fn create_authorizer(auth_obj: &Value, jobs: Arc<JobsConfiguration>) -> Result<Authorizer, &'static str> {
+    let mut roles: HashMap<String, Role> = HashMap::new();
+    let mut principal_roles: HashMap<String, Vec<String>> = HashMap::new();
+
+    if let Some(roles_arr) = auth_obj["roles"].as_array() {
+        for ro in roles_arr {
+            let name =
+                match ro.get("name") {
+                    None => return Err("Missing role name"),
+                    Some(n) => get_string(n)
+                };
+            let routes =
+                get_string_array(&ro["routes"])
+                    .iter()
+                    .map(|r| Regex::new(r).map_err(|_| "Invalid role route regex"))
+                    .collect::<Result<Vec<Regex>, &'static str>>()?;
+            roles.insert(name.clone(), Role::new(name, routes));
+        }
+    }
+
+    if let Some(principals_arr) = auth_obj["principals"].as_array() {
+        for po in principals_arr {
+            let name =
+                match po.get("name") {
+                    None => return Err("Missing principal name"),
+                    Some(n) => get_string(n)
+                };
+            let assigned = get_string_array(&po["roles"]);
+            if assigned.iter().any(|r| !roles.contains_key(r)) {
+                return Err("Principal references unknown role");
+            }
+            principal_roles.insert(name, assigned);
+        }
+    }
+
+    Ok(Authorizer::new(roles, principal_roles, jobs))
+}
+
+/// Read an array of `{ "<secret_key>": "...", "principal": "..." }` objects.
+fn get_secret_pairs(value: &Value, secret_key: &str) -> Result<Vec<(String, String)>, &'static str> {
+    match value.as_array() {
+        None => Err("Credentials value is not an array"),
+        Some(arr) => {
+            arr.iter()
+                .map(|v| {
+                    match (v.get(secret_key), v.get("principal")) {
+                        (Some(secret), Some(principal)) => Ok((get_string(secret), get_string(principal))),
+                        (None, _) => Err("Missing credential secret"),
+                        (_, None) => Err("Missing credential principal")
+                    }
+                })
+                .collect()
+        }
+    }
+}
+
+fn create_route_auth(auth_obj: &Value, authenticator: &Authenticator) -> Result<RouteAuth, &'static str> {
+    let credential_sets = get_string_array(&auth_obj["credentials"]);
+    if credential_sets.is_empty() {
+        return Err("Route auth must list at least one credential set");
+    }
+    if credential_sets.iter().any(|c| !authenticator.contains(c)) {
+        return Err("Route auth references unknown credential set");
+    }
+    Ok(RouteAuth::new(credential_sets, get_string_array(&auth_obj["principals"])))
+}
+
+fn create_route_map(mut routes_array: Value, job_context: JobContext, authenticator: Authenticator, authorizer: Authorizer, defaults: RouteDefaults, metrics: &Metrics) -> Result<RouteMap, &'static str> {
     let routes =
         match routes_array.as_array() {
             None => Err("Routes value is not an array"),
             Some(ra) => {
-                ra.iter().map(|mut ro| create_route_from_value(&mut ro.clone())).collect()
+                ra.iter().map(|mut ro| create_route_from_value(&mut ro.clone(), &job_context.config, &authenticator, metrics)).collect()
             }
         }?;
-    Ok(RouteMap::new(job_handler, routes))
+    Ok(RouteMap::new(job_context, routes, authenticator, authorizer, defaults, metrics.clone()))
 }
 
-fn create_route_from_value(route_obj: &mut Value) -> Result<Route, &'static str> {
+fn create_route_from_value(route_obj: &mut Value, jobs: &JobsConfiguration, authenticator: &Authenticator, metrics: &Metrics) -> Result<Route, &'static str> {
     match route_obj.as_object_mut() {
         None => Err("Json value is not a object."),
         Some(vm) => {
@@ -156,30 +826,74 @@ fn create_route_from_value(route_obj: &mut Value) -> Result<Route, &'static str>
                                 }
                             }
                             "job" => {
-                                let command_values = (vm.get("name"), vm.get("args"));
-                                match command_values {
-                                    (Some(name), Some(args)) => {
-                                        let argv =
-                                            match args.as_array() {
-                                                None => vec![],
-                                                Some(argv) => {
-                                                    argv
-                                                        .iter()
-                                                        .map(|s| {
-                                                            match s.as_str() {
-                                                                None => String::new(),
-                                                                Some(v) => v.to_string()
-                                                            }
-                                                        })
-                                                        .collect()
-                                                }
+                                match vm.get("name") {
+                                    Some(name) => {
+                                        let parameters =
+                                            match vm.get("parameters").and_then(|p| p.as_object()) {
+                                                None => HashMap::new(),
+                                                Some(po) => po.iter().map(|(k, v)| (k.clone(), get_scalar_string(v))).collect()
+                                            };
+                                        let debounce =
+                                            match vm.get("debounce") {
+                                                None => None,
+                                                Some(d) => Some(create_debounce(d, format!("route:{}", get_string(regex)))?)
                                             };
                                         Ok(RouteHandler::create_job(
                                             get_string(name),
-                                            argv))
+                                            parameters,
+                                            debounce))
                                     }
-                                    (None, _) => Err("Missing command name"),
-                                    (_, None) => Err("Missing args")
+                                    None => Err("Missing job name")
+                                }
+                            }
+                            "jobs" => {
+                                Ok(RouteHandler::create_jobs())
+                            }
+                            "metrics" => {
+                                Ok(RouteHandler::create_metrics())
+                            }
+                            "events" => {
+                                Ok(RouteHandler::create_events())
+                            }
+                            "webhook" => {
+                                let webhook_values = (vm.get("job"), vm.get("provider"), vm.get("secret"));
+                                match webhook_values {
+                                    (Some(job), Some(provider), Some(secret)) => {
+                                        let provider = WebhookProvider::from_str(
+                                            &get_string(provider),
+                                            get_optional_string(vm.get("signature_header")),
+                                            get_optional_string(vm.get("event_header")))?;
+                                        let parameters =
+                                            match vm.get("parameters").and_then(|p| p.as_object()) {
+                                                None => vec![],
+                                                Some(po) => po.iter().map(|(k, v)| (k.clone(), get_string(v))).collect()
+                                            };
+                                        // Payload values are checked against the job's declarations, so every mapped one must be declared.
+                                        let declared = jobs.get_job(&get_string(job)).map(|j| j.parameters.iter().map(|p| p.name.clone()).collect::<Vec<String>>());
+                                        match declared {
+                                            None => return Err("Unknown webhook job"),
+                                            Some(d) if parameters.iter().any(|(name, _)| !d.contains(name)) => return Err("Webhook parameters must be declared by the job"),
+                                            Some(_) => {}
+                                        };
+                                        let debounce =
+                                            match vm.get("debounce") {
+                                                None => None,
+                                                Some(d) => Some(create_debounce(d, format!("route:{}", get_string(regex)))?)
+                                            };
+                                        let mut webhook = WebhookRoute::new(
+                                            get_string(job),
+                                            provider,
+                                            get_string(secret).into_bytes(),
+                                            get_string_array(&vm["events"]),
+                                            get_string_array(&vm["branches"]),
+                                            parameters,
+                                            debounce);
+                                        webhook.principal = get_optional_string(vm.get("principal"));
+                                        Ok(RouteHandler::create_webhook(webhook))
+                                    }
+                                    (None, _, _) => Err("Missing webhook job"),
+                                    (_, None, _) => Err("Missing webhook provider"),
+                                    (_, _, None) => Err("Missing webhook secret")
                                 }
                             }
                             _ => {
@@ -188,11 +902,35 @@ fn create_route_from_value(route_obj: &mut Value) -> Result<Route, &'static str>
                             }
                         };
 
+                    let auth =
+                        match vm.get("auth") {
+                            None => None,
+                            Some(ao) => Some(create_route_auth(ao, authenticator)?)
+                        };
+
+                    let access =
+                        match vm.get("access") {
+                            None => None,
+                            Some(ao) => Some(create_access_list(ao)?)
+                        };
+
+                    let rate_limiter =
+                        match vm.get("rate_limit") {
+                            None => None,
+                            Some(rl) => Some(create_rate_limiter(get_string(regex), rl, metrics)?)
+                        };
+
+                    let cors =
+                        match vm.get("cors") {
+                            None => None,
+                            Some(co) => Some(create_cors_policy(co)?)
+                        };
+
                     match route_handler {
                         Ok(handler) =>
                             {
                                 let r = Regex::new(&*get_string(regex)).unwrap();
-                                Ok(Route::new(r, handler))
+                                Ok(Route::new(r, handler, auth, access, rate_limiter, cors))
                             }
                         Err(e) => Err(e)
                     }
@@ -228,6 +966,12 @@ fn load_jobs_config(path: String) -> Result<JobsConfiguration, &'static str> {
                 Some(jobs_arr) => {
                     match jobs_arr.as_array() {
                         Some(jobs) => {
+                            let queues = create_queues(json.get("queues"))?;
+                            let job_log =
+                                match json.get("job_log") {
+                                    None => None,
+                                    Some(jl) => Some(create_job_log_settings(jl)?)
+                                };
                             let mut jobs_map: HashMap<String, JobConfiguration> = HashMap::new();
 
                             let _ =
@@ -244,7 +988,12 @@ fn load_jobs_config(path: String) -> Result<JobsConfiguration, &'static str> {
                             //.map(|job| { ; 1})
                             //.collect<i32>();
 
-                            Ok(JobsConfiguration { jobs: jobs_map })
+                            if jobs_map.values().any(|j| !queues.iter().any(|q| q.name == j.queue)) {
+                                return Err("Job references unknown queue");
+                            }
+                            check_triggers(&jobs_map)?;
+
+                            Ok(JobsConfiguration { jobs: jobs_map, queues, job_log })
                         }
                         None => Err("Jobs value is not an array.")
                     }
@@ -266,6 +1015,66 @@ fn load_jobs_config(path: String) -> Result<JobsConfiguration, &'static str> {
     }
 }
 
+fn create_job_log_settings(job_log_obj: &Value) -> Result<JobLogSettings, &'static str> {
+    let path =
+        match job_log_obj["path"].as_str() {
+            None => return Err("Missing job log path"),
+            Some(p) => p.to_string()
+        };
+    let compact_after = job_log_obj["compact_after"].as_u64().unwrap_or(100).max(1) as usize;
+
+    Ok(JobLogSettings { path, compact_after })
+}
+
+/// Create the job queues, adding the default queue if it is not declared.
+fn create_queues(queues_value: Option<&Value>) -> Result<Vec<QueueConfiguration>, &'static str> {
+    let mut queues =
+        match queues_value {
+            None => vec![],
+            Some(qv) => {
+                match qv.as_array() {
+                    None => return Err("Queues value is not an array."),
+                    Some(qa) => qa.iter().map(create_queue).collect::<Result<Vec<QueueConfiguration>, &'static str>>()?
+                }
+            }
+        };
+
+    if queues.iter().enumerate().any(|(i, q)| queues[..i].iter().any(|o| o.name == q.name)) {
+        return Err("Duplicate queue name");
+    }
+
+    if !queues.iter().any(|q| q.name == DEFAULT_QUEUE) {
+        queues.push(QueueConfiguration {
+            name: DEFAULT_QUEUE.to_string(),
+            min_workers: 1,
+            max_workers: 4,
+            idle_timeout: Duration::from_millis(DEFAULT_QUEUE_IDLE_TIMEOUT_MS),
+        });
+    }
+
+    Ok(queues)
+}
+
+fn create_queue(queue_obj: &Value) -> Result<QueueConfiguration, &'static str> {
+    let name =
+        match queue_obj["name"].as_str() {
+            None => return Err("Missing queue name"),
+            Some(n) => n.to_string()
+        };
+    let min_workers = queue_obj["min_workers"].as_u64().unwrap_or(1) as usize;
+    let max_workers = queue_obj["max_workers"].as_u64().unwrap_or(4) as usize;
+    let idle_timeout = Duration::from_millis(queue_obj["idle_timeout_ms"].as_u64().unwrap_or(DEFAULT_QUEUE_IDLE_TIMEOUT_MS));
+
+    if max_workers == 0 {
+        return Err("Queue max_workers must be greater than zero");
+    }
+    if min_workers > max_workers {
+        return Err("Queue min_workers must not be greater than max_workers");
+    }
+
+    Ok(QueueConfiguration { name, min_workers, max_workers, idle_timeout })
+}
+
 fn create_job_config(mut job_obj: &mut Value) -> Result<JobConfiguration, &'static str> {
     match job_obj.as_object_mut() {
         None => Err("Job value is not and object"),
@@ -278,12 +1087,87 @@ fn create_job_config(mut job_obj: &mut Value) -> Result<JobConfiguration, &'stat
                         Some(av) => {
                             let name = get_string(name_value);
 
+                            let retry =
+                                match jo.get("retry") {
+                                    None => None,
+                                    Some(r) => Some(create_retry_policy(r)?)
+                                };
+
                             let actions =
                                 av.iter()
-                                    .map(|a| create_action(a).unwrap())
-                                    .collect();
+                                    .map(|a| create_action(a, retry.as_ref()))
+                                    .collect::<Result<Vec<ActionConfiguration>, &'static str>>()?;
+
+                            let permissions =
+                                jo.get("permissions").map(|p| JobPermissions {
+                                    view: get_string_array(&p["view"]),
+                                    trigger: get_string_array(&p["trigger"]),
+                                    cancel: get_string_array(&p["cancel"]),
+                                });
 
-                            Ok(JobConfiguration { name, actions })
+                            let queue = get_optional_string(jo.get("queue")).unwrap_or_else(|| DEFAULT_QUEUE.to_string());
+
+                            let priority = jo.get("priority").and_then(|p| p.as_i64()).unwrap_or(0);
+
+                            let on_interrupt =
+                                match jo.get("on_interrupt").and_then(|p| p.as_str()) {
+                                    None => InterruptPolicy::Fail,
+                                    Some(p) => InterruptPolicy::from_str(p)?
+                                };
+
+                            let parameters =
+                                match jo.get("parameters") {
+                                    None => vec![],
+                                    Some(pv) => {
+                                        match pv.as_array() {
+                                            None => return Err("Parameters value is not an array."),
+                                            Some(pa) => pa.iter().map(create_parameter).collect::<Result<Vec<JobParameter>, &'static str>>()?
+                                        }
+                                    }
+                                };
+
+                            check_placeholders(&parameters, &actions)?;
+
+                            let mut triggers = vec![];
+                            for (key, on) in [("on_success", TriggerEvent::Success), ("on_failure", TriggerEvent::Failure), ("on_complete", TriggerEvent::Complete)] {
+                                if let Some(tv) = jo.get(key) {
+                                    match tv.as_array() {
+                                        None => return Err("Job triggers value is not an array."),
+                                        Some(ta) => {
+                                            for t in ta {
+                                                triggers.push(create_trigger(t, on.clone())?);
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+
+                            let unknown_value =
+                                triggers
+                                    .iter()
+                                    .flat_map(|t| t.parameters.values())
+                                    .flat_map(|v| placeholders(v))
+                                    .any(|n| {
+                                        !parameters.iter().any(|p| p.name == n)
+                                            && !actions.iter().any(|a| a.outputs.iter().any(|o| output_key(&a.name, &o.name) == n))
+                                    });
+                            if unknown_value {
+                                return Err("Trigger uses a parameter or output the job does not have");
+                            }
+
+                            let concurrency =
+                                match jo.get("concurrency") {
+                                    None => None,
+                                    Some(c) => Some(create_concurrency_limit(c)?)
+                                };
+                            let locks = jo.get("locks").map(get_string_array).unwrap_or_default();
+                            let debounce =
+                                match jo.get("debounce") {
+                                    None => None,
+                                    Some(d) => Some(create_debounce(d, format!("job:{}", name))?)
+                                };
+
+                            Ok(JobConfiguration { name, queue, priority, on_interrupt, parameters, actions, permissions, triggers, concurrency, locks, debounce })
                         }
                         None => Err("Actions value is not an array.")
                     }
@@ -295,7 +1179,206 @@ fn create_job_config(mut job_obj: &mut Value) -> Result<JobConfiguration, &'stat
     }
 }
 
-fn create_action(mut action_obj: &Value) -> Result<ActionType, &'static str> {
+/// Check every placeholder and condition in the job's actions names a declared parameter, a value of the
+/// action's matrix, or the state or an output of an earlier action. Matrix values may only use parameters.
+fn check_placeholders(parameters: &[JobParameter], actions: &[ActionConfiguration]) -> Result<(), &'static str> {
+    for (i, action) in actions.iter().enumerate() {
+        if !action.outputs.is_empty() && actions.iter().filter(|a| a.name == action.name).count() > 1 {
+            return Err("Actions publishing outputs must have unique names");
+        }
+        if !action.outputs.is_empty() && !action.matrix.is_empty() {
+            return Err("Matrix actions can not publish outputs");
+        }
+
+        let matrix_uses_parameters =
+            action.matrix
+                .iter()
+                .flat_map(|d| d.values.iter())
+                .flat_map(|v| placeholders(v))
+                .all(|n| parameters.iter().any(|p| p.name == n));
+        if !matrix_uses_parameters {
+            return Err("Matrix values may only use parameters the job declares");
+        }
+
+        let templates = action.templates().into_iter().flat_map(|t| placeholders(t));
+        let conditions = action.when.iter().flat_map(|c| c.names()).cloned();
+        for name in templates.chain(conditions) {
+            let known =
+                parameters.iter().any(|p| p.name == name)
+                    || action.matrix.iter().any(|d| matrix_key(&d.name) == name)
+                    || actions[..i].iter().any(|a| a.outputs.iter().any(|o| output_key(&a.name, &o.name) == name))
+                    || actions[..i].iter().any(|a| state_key(&a.name) == name);
+            if known && name.starts_with("actions.") && actions.iter().filter(|a| state_key(&a.name) == name).count() > 1 {
+                return Err("Actions whose state is used must have unique names");
+            }
+            match (known, name.split('.').next()) {
+                (true, _) => {}
+                (false, Some("outputs")) => return Err("Action uses an output no earlier action publishes"),
+                (false, Some("actions")) => return Err("Action uses the state of an action that does not run before it"),
+                (false, Some("matrix")) => return Err("Action uses a matrix value it does not define"),
+                (false, _) => return Err("Action uses a parameter the job does not declare")
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn create_concurrency_limit(concurrency_obj: &Value) -> Result<ConcurrencyLimit, &'static str> {
+    let limit = concurrency_obj["limit"].as_u64().unwrap_or(1) as usize;
+    if limit == 0 {
+        return Err("Concurrency limit must be greater than zero");
+    }
+    let policy =
+        match concurrency_obj["policy"].as_str() {
+            None => ConcurrencyPolicy::Queue,
+            Some(p) => ConcurrencyPolicy::from_str(p)?
+        };
+
+    Ok(ConcurrencyLimit { limit, policy })
+}
+
+fn create_debounce(debounce_obj: &Value, scope: String) -> Result<DebounceSettings, &'static str> {
+    let window =
+        match debounce_obj["window_ms"].as_u64() {
+            None | Some(0) => return Err("Debounce window must be a number of milliseconds greater than zero"),
+            Some(w) => Duration::from_millis(w)
+        };
+    let max_wait = debounce_obj["max_wait_ms"].as_u64().map(Duration::from_millis);
+    if max_wait.map(|m| m < window).unwrap_or(false) {
+        return Err("Debounce max wait must not be shorter than its window");
+    }
+    let merge =
+        match debounce_obj["merge"].as_str() {
+            None => DebounceMerge::Latest,
+            Some(m) => DebounceMerge::from_str(m)?
+        };
+
+    Ok(DebounceSettings { scope, window, max_wait, merge })
+}
+
+fn create_trigger(trigger_obj: &Value, on: TriggerEvent) -> Result<JobTrigger, &'static str> {
+    let job =
+        match trigger_obj["job"].as_str() {
+            None => return Err("Missing trigger job"),
+            Some(j) => j.to_string()
+        };
+    let parameters =
+        match trigger_obj.get("parameters").and_then(|p| p.as_object()) {
+            None => HashMap::new(),
+            Some(po) => po.iter().map(|(k, v)| (k.clone(), get_scalar_string(v))).collect()
+        };
+
+    Ok(JobTrigger { job, on, parameters })
+}
+
+/// Check every trigger names a job, only passes parameters that job declares, and that no job can trigger itself.
+fn check_triggers(jobs: &HashMap<String, JobConfiguration>) -> Result<(), &'static str> {
+    for trigger in jobs.values().flat_map(|j| j.triggers.iter()) {
+        match jobs.get(&trigger.job) {
+            None => return Err("Trigger names an unknown job"),
+            Some(target) => {
+                if trigger.parameters.keys().any(|k| !target.parameters.iter().any(|p| &p.name == k)) {
+                    return Err("Trigger passes a parameter the triggered job does not declare");
+                }
+            }
+        }
+    }
+
+    let mut checked = HashSet::new();
+    for name in jobs.keys() {
+        if trigger_cycle(name, jobs, &mut vec![], &mut checked) {
+            return Err("Job triggers form a cycle");
+        }
+    }
+    Ok(())
+}
+
+/// Follow the job's triggers depth first, returning true on reaching a job already on the path.
+fn trigger_cycle<'a>(name: &'a str, jobs: &'a HashMap<String, JobConfiguration>, path: &mut Vec<&'a str>, checked: &mut HashSet<&'a str>) -> bool {
+    if path.contains(&name) {
+        return true;
+    }
+    if !checked.insert(name) {
+        return false;
+    }
+
+    path.push(name);
+    let cycle =
+        jobs.get(name)
+            .map(|j| j.triggers.iter().any(|t| trigger_cycle(&t.job, jobs, path, checked)))
+            .unwrap_or(false);
+    path.pop();
+    cycle
+}
+
+fn create_parameter(parameter_obj: &Value) -> Result<JobParameter, &'static str> {
+    let name =
+        match parameter_obj["name"].as_str() {
+            None => return Err("Missing parameter name"),
+            Some(n) => n.to_string()
+        };
+    let parameter_type =
+        match parameter_obj["type"].as_str().unwrap_or("string") {
+            "string" => ParameterType::String,
+            "int" => ParameterType::Int,
+            "bool" => ParameterType::Bool,
+            "enum" => {
+                let values = get_string_array(&parameter_obj["values"]);
+                if values.is_empty() {
+                    return Err("Enum parameter must list its values");
+                }
+                ParameterType::Enum(values)
+            }
+            _ => return Err("Unknown parameter type")
+        };
+    let pattern =
+        match parameter_obj["pattern"].as_str() {
+            None => None,
+            Some(p) => Some(Regex::new(&format!("^(?:{})$", p)).map_err(|_| "Invalid parameter pattern")?)
+        };
+
+    let mut parameter = JobParameter { name, parameter_type, default: None, pattern };
+    if let Some(d) = parameter_obj.get("default") {
+        parameter.default = Some(check_value(&parameter, &get_scalar_string(d)).map_err(|_| "Parameter default is not a valid value")?);
+    }
+
+    Ok(parameter)
+}
+
+fn create_retry_policy(retry_obj: &Value) -> Result<RetryPolicy, &'static str> {
+    let max_attempts = retry_obj["max_attempts"].as_u64().unwrap_or(3) as u32;
+    if max_attempts == 0 {
+        return Err("Retry max_attempts must be greater than zero");
+    }
+    let backoff =
+        match retry_obj["backoff"].as_str() {
+            None => Backoff::Exponential,
+            Some(b) => Backoff::from_str(b)?
+        };
+    let on_exit_codes =
+        match retry_obj.get("on_exit_codes") {
+            None => None,
+            Some(codes) => {
+                match codes.as_array() {
+                    None => return Err("Retry on_exit_codes is not an array"),
+                    Some(ca) => Some(ca.iter().filter_map(|c| c.as_i64()).map(|c| c as i32).collect())
+                }
+            }
+        };
+
+    Ok(RetryPolicy {
+        max_attempts,
+        backoff,
+        delay: Duration::from_millis(retry_obj["delay_ms"].as_u64().unwrap_or(1000)),
+        max_delay: Duration::from_millis(retry_obj["max_delay_ms"].as_u64().unwrap_or(60000)),
+        jitter: retry_obj["jitter"].as_bool().unwrap_or(true),
+        on_exit_codes,
+        on_timeout: retry_obj["on_timeout"].as_bool().unwrap_or(true),
+    })
+}
+
+fn create_action(mut action_obj: &Value, job_retry: Option<&RetryPolicy>) -> Result<ActionConfiguration, &'static str> {
     match action_obj.clone().as_object_mut() {
         None => Err("Action value is not and object"),
         Some(ao) => {
@@ -306,6 +1389,7 @@ fn create_action(mut action_obj: &Value) -> Result<ActionType, &'static str> {
                     let name = get_string(name_value);
                     let type_name = get_string(type_value).as_str();
 
+                    let action_type =
                     match get_string(type_value).as_str() {
                         "command" => {
                             let command_values = (ao.get("command_name"), ao.get("args"));
@@ -326,9 +1410,16 @@ fn create_action(mut action_obj: &Value) -> Result<ActionType, &'static str> {
                                                     .collect()
                                             }
                                         };
+                                    let env =
+                                        match ao.get("env").and_then(|e| e.as_object()) {
+                                            None => HashMap::new(),
+                                            Some(eo) => eo.iter().map(|(k, v)| (k.clone(), get_string(v))).collect()
+                                        };
                                     Ok(ActionType::create_command(
                                         get_string(name),
-                                        argv))
+                                        argv,
+                                        get_optional_string(ao.get("cwd")),
+                                        env))
                                 }
                                 (None, _) => Err("Missing command name"),
                                 (_, None) => Err("Missing args")
@@ -346,11 +1437,86 @@ fn create_action(mut action_obj: &Value) -> Result<ActionType, &'static str> {
                             }
                         }
                         _ => Err("Unknown job type.")
-                    }
+                    }?;
+
+                    let retry =
+                        match ao.get("retry") {
+                            None => job_retry.cloned(),
+                            Some(r) => Some(create_retry_policy(r)?)
+                        };
+                    let timeout = ao.get("timeout_ms").and_then(|t| t.as_u64()).map(Duration::from_millis);
+                    let outputs =
+                        match ao.get("outputs") {
+                            None => vec![],
+                            Some(ov) => {
+                                match ov.as_array() {
+                                    None => return Err("Outputs value is not an array."),
+                                    Some(oa) => oa.iter().map(create_output).collect::<Result<Vec<ActionOutput>, &'static str>>()?
+                                }
+                            }
+                        };
+
+                    let when =
+                        match ao.get("when").and_then(|w| w.as_str()) {
+                            None => None,
+                            Some(w) => Some(Condition::parse(w)?)
+                        };
+                    let matrix =
+                        match ao.get("matrix") {
+                            None => vec![],
+                            Some(mv) => create_matrix(mv)?
+                        };
+
+                    Ok(ActionConfiguration { name, action_type, retry, timeout, outputs, when, matrix })
                 }
                 (None, _) => Err("Missing name value"),
                 (_, None) => Err("Missing type value"),
             }
         }
     }
+}
+
+fn create_output(output_obj: &Value) -> Result<ActionOutput, &'static str> {
+    let name =
+        match output_obj["name"].as_str() {
+            None => return Err("Missing output name"),
+            Some(n) => n.to_string()
+        };
+    let source =
+        match output_obj["from"].as_str().unwrap_or("stdout") {
+            "stdout" => OutputSource::Stdout,
+            "key_value" => OutputSource::KeyValue,
+            "file" => {
+                match output_obj["path"].as_str() {
+                    None => return Err("File output must have a path"),
+                    Some(p) => OutputSource::File(p.to_string())
+                }
+            }
+            _ => return Err("Unknown output source")
+        };
+
+    Ok(ActionOutput { name, source })
+}
+
+fn create_matrix(matrix_obj: &Value) -> Result<Vec<MatrixDimension>, &'static str> {
+    let dimensions =
+        match matrix_obj.as_object() {
+            None => return Err("Matrix value is not an object."),
+            Some(mo) => mo
+        };
+
+    dimensions
+        .iter()
+        .map(|(name, v)| {
+            let values =
+                match v {
+                    Value::Array(arr) => arr.iter().map(get_scalar_string).collect(),
+                    other => vec![get_scalar_string(other)]
+                };
+            match values.is_empty() {
+                true => Err("Matrix dimension must have values"),
+                false => Ok(MatrixDimension { name: name.clone(), values })
+            }
+        })
+        .collect()
 }
\ No newline at end of file
@@ -1,39 +1,88 @@
-﻿use std::thread;
+﻿use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::Instant;
 use std::sync::{Arc, mpsc, Mutex};
+use std::sync::mpsc::TrySendError;
 use crate::logging::logging::Logger;
 use crate::orchestration::{Job, JobHandler};
 use crate::routing::RouteMap;
+use crate::shutdown::join_with_deadline;
 
+/// Threads for route handlers that block, such as reading files or running commands,
+/// so they do not stall the server's event loop.
 pub struct ConnectionPool {
-    workers: Vec<ConnectionHandler>,
-    sender: mpsc::Sender<Connection>,
+    workers: Arc<Mutex<Vec<ConnectionHandler>>>,
+    sender: mpsc::SyncSender<Connection>,
 }
 
 type Connection = Box<dyn FnOnce() + Send + 'static>;
 
+/// The connection pool settings.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct ConnectionLimits {
+    pub pool_size: usize,
+    /// Requests waiting for a free handler, beyond this new requests are rejected.
+    pub queue_depth: usize,
+    /// Open connections from a single client address, 0 for no limit.
+    pub max_per_client: usize,
+    /// The `Retry-After` value sent with rejections, in seconds.
+    pub retry_after: u64,
+}
+
+/// Why a connection or request was turned away.
+pub enum Rejection {
+    QueueFull,
+    ClientLimit,
+}
+
 impl ConnectionPool {
-    pub fn new(size: usize, logger: Logger) -> ConnectionPool {
+    pub fn new(limits: &ConnectionLimits, logger: Logger) -> ConnectionPool {
+        let size = limits.pool_size;
         assert!(size > 0);
-        let (sender, receiver) = mpsc::channel();
+        let (sender, receiver) = mpsc::sync_channel(limits.queue_depth);
 
         let receiver = Arc::new(Mutex::new(receiver));
 
-        let mut workers = Vec::with_capacity(size);
+        let workers = Arc::new(Mutex::new(Vec::with_capacity(size)));
 
         for id in 0..size {
-            workers.push(ConnectionHandler::new(id, Arc::clone(&receiver), logger.clone()));
+            ConnectionHandler::start(id, Arc::clone(&receiver), logger.clone(), workers.clone());
         }
 
         ConnectionPool { workers, sender }
     }
 
-    pub fn execute<F>(&self, f: F)
+    /// Queue work without blocking, rejecting it if the queue is full.
+    pub fn execute<F>(&self, f: F) -> Result<(), Rejection>
         where
             F: FnOnce() + Send + 'static,
     {
         let job = Box::new(f);
 
-        self.sender.send(job).unwrap();
+        match self.sender.try_send(job) {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(Rejection::QueueFull),
+            Err(TrySendError::Disconnected(_)) => Err(Rejection::QueueFull)
+        }
+    }
+
+    /// Stop accepting work and wait for queued and running handlers to finish, up to the deadline.
+    /// Returns false if some handlers were still busy at the deadline.
+    pub fn shutdown(self, deadline: Instant) -> bool {
+        drop(self.sender);
+        loop {
+            let handler = self.workers.lock().unwrap_or_else(|p| p.into_inner()).pop();
+            match handler {
+                Some(h) => {
+                    if !join_with_deadline(h.thread, deadline) {
+                        return false;
+                    }
+                }
+                None => return true
+            }
+        }
     }
 }
 
@@ -42,14 +91,55 @@ struct ConnectionHandler {
     thread: thread::JoinHandle<()>,
 }
 
+/// Lives on a handler thread and starts a replacement if the thread dies by panicking,
+/// so the pool never silently shrinks.
+struct Sentinel {
+    id: usize,
+    receiver: Arc<Mutex<mpsc::Receiver<Connection>>>,
+    logger: Logger,
+    workers: Arc<Mutex<Vec<ConnectionHandler>>>,
+}
+
 impl ConnectionHandler {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Connection>>>, logger: Logger) -> ConnectionHandler {
-        let thread = thread::spawn(move || loop {
-            let job = receiver.lock().unwrap().recv().unwrap();
-            logger.log_info(format!("connection_handler_{}", id), format!("Connection received."));
-            job();
+    /// Start a handler thread and add it to `workers`.
+    fn start(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Connection>>>, logger: Logger, workers: Arc<Mutex<Vec<ConnectionHandler>>>) {
+        let pool = workers.clone();
+        let thread = thread::spawn(move || {
+            let sentinel = Sentinel { id, receiver: receiver.clone(), logger: logger.clone(), workers };
+            loop {
+                // A poisoned lock only means another handler panicked while waiting, the receiver is still usable.
+                let job =
+                    match receiver.lock().unwrap_or_else(|p| p.into_inner()).recv() {
+                        Ok(job) => job,
+                        // The pool has been dropped.
+                        Err(_) => break
+                    };
+                logger.log_info(format!("connection_handler_{}", id), "Request received.".to_string()).ok();
+                if let Err(e) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                    logger.log_error(format!("connection_handler_{}", id), format!("Connection handler panicked: {}", panic_message(&e))).ok();
+                }
+            }
+            drop(sentinel);
         });
 
-        ConnectionHandler { id, thread }
+        pool.lock().unwrap_or_else(|p| p.into_inner()).push(ConnectionHandler { id, thread });
+    }
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.logger.log_warning(format!("connection_handler_{}", self.id), "Handler thread died, starting a replacement.".to_string()).ok();
+            ConnectionHandler::start(self.id, self.receiver.clone(), self.logger.clone(), self.workers.clone());
+        }
+    }
+}
+
+/// Get the message from a caught panic payload.
+pub(crate) fn panic_message(payload: &Box<dyn Any + Send>) -> String {
+    match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(s), _) => s.to_string(),
+        (_, Some(s)) => s.clone(),
+        _ => "unknown panic".to_string()
     }
 }
\ No newline at end of file
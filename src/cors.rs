@@ -0,0 +1,116 @@
+use crate::http::{HttpRequest, HttpResponse, HttpVerb};
+
+/// The cross-origin resource sharing settings for a route.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct CorsPolicy {
+    /// Origins allowed to make requests, `*` allows any origin.
+    allowed_origins: Vec<String>,
+    allowed_methods: Vec<String>,
+    /// Request headers allowed in preflighted requests, `*` allows any header.
+    allowed_headers: Vec<String>,
+    exposed_headers: Vec<String>,
+    allow_credentials: bool,
+    max_age: Option<u64>,
+}
+
+impl CorsPolicy {
+    pub fn new(allowed_origins: Vec<String>, allowed_methods: Vec<String>, allowed_headers: Vec<String>, exposed_headers: Vec<String>, allow_credentials: bool, max_age: Option<u64>) -> Result<CorsPolicy, &'static str> {
+        if allow_credentials && allowed_origins.iter().any(|o| o == "*") {
+            return Err("Cors credentials can not be allowed for any origin");
+        }
+
+        Ok(CorsPolicy {
+            allowed_origins,
+            allowed_methods: allowed_methods.iter().map(|m| m.to_uppercase()).collect(),
+            allowed_headers,
+            exposed_headers,
+            allow_credentials,
+            max_age,
+        })
+    }
+
+    /// True if the request is a CORS preflight rather than an actual request.
+    pub fn is_preflight(request: &HttpRequest) -> bool {
+        matches!(request.header.verb, HttpVerb::OPTIONS)
+            && request.get_header("Origin").is_some()
+            && request.get_header("Access-Control-Request-Method").is_some()
+    }
+
+    fn is_origin_allowed(&self, origin: &str) -> bool {
+        self.allowed_origins.iter().any(|o| o == "*" || o.eq_ignore_ascii_case(origin))
+    }
+
+    /// The `Access-Control-Allow-Origin` value, the wildcard is only echoed back when credentials are not allowed.
+    fn allow_origin_value(&self, origin: &str) -> String {
+        match self.allowed_origins.iter().any(|o| o == "*") && !self.allow_credentials {
+            true => "*".to_string(),
+            false => origin.to_string()
+        }
+    }
+
+    /// Build the response to a preflight request, route handlers are never invoked for these.
+    pub fn preflight(&self, request: &HttpRequest) -> HttpResponse {
+        let origin = request.get_header("Origin").cloned().unwrap_or_default();
+        let method = request.get_header("Access-Control-Request-Method").cloned().unwrap_or_default();
+        let requested_headers: Vec<String> =
+            request
+                .get_header("Access-Control-Request-Headers")
+                .map(|h| h.split(',').map(|v| v.trim().to_string()).filter(|v| !v.is_empty()).collect())
+                .unwrap_or_default();
+
+        let method_allowed = self.allowed_methods.iter().any(|m| m == &method.to_uppercase());
+        let headers_allowed =
+            requested_headers
+                .iter()
+                .all(|h| self.allowed_headers.iter().any(|a| a == "*" || a.eq_ignore_ascii_case(h)));
+
+        let mut response =
+            match self.is_origin_allowed(&origin) && method_allowed && headers_allowed {
+                true => {
+                    let mut response = HttpResponse::create(204, String::from("text/plain"), None);
+                    response.add_header("Access-Control-Allow-Origin".to_string(), self.allow_origin_value(&origin));
+                    response.add_header("Access-Control-Allow-Methods".to_string(), self.allowed_methods.join(", "));
+                    if !requested_headers.is_empty() {
+                        response.add_header("Access-Control-Allow-Headers".to_string(), requested_headers.join(", "));
+                    }
+                    if self.allow_credentials {
+                        response.add_header("Access-Control-Allow-Credentials".to_string(), "true".to_string());
+                    }
+                    if let Some(max_age) = self.max_age {
+                        response.add_header("Access-Control-Max-Age".to_string(), max_age.to_string());
+                    }
+                    response
+                }
+                false => {
+                    let body = " { \"message\": \"Cors request not allowed\"}".as_bytes().to_vec();
+                    HttpResponse::create(403, String::from("application/json"), Some(body))
+                }
+            };
+
+        response.add_vary("Origin");
+        response.add_vary("Access-Control-Request-Method");
+        response.add_vary("Access-Control-Request-Headers");
+        response
+    }
+
+    /// Add the CORS headers for an actual request to its response.
+    pub fn apply(&self, origin: Option<&String>, response: &mut HttpResponse) {
+        // The response depends on the origin even when it is not allowed, so caches must key on it.
+        response.add_vary("Origin");
+
+        let origin =
+            match origin {
+                Some(o) if self.is_origin_allowed(o) => o,
+                _ => return
+            };
+
+        response.add_header("Access-Control-Allow-Origin".to_string(), self.allow_origin_value(origin));
+        if self.allow_credentials {
+            response.add_header("Access-Control-Allow-Credentials".to_string(), "true".to_string());
+        }
+        if !self.exposed_headers.is_empty() {
+            response.add_header("Access-Control-Expose-Headers".to_string(), self.exposed_headers.join(", "));
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::configuration::{DebounceMerge, DebounceSettings, JobsConfiguration};
+use crate::job_log::JobLog;
+use crate::logging::logging::Logger;
+use crate::orchestration::JobCommand;
+use crate::parameters::resolve_parameters;
+
+/// A trigger collapsed into a run by debouncing.
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Serialize)]
+#[derive(Deserialize)]
+pub struct DebouncedTrigger {
+    pub id: Uuid,
+    pub triggered_by: Option<String>,
+    pub received_at: DateTime<Utc>,
+}
+
+/// Holds back the triggers of debounced jobs and routes, queuing one run for the triggers
+/// of each scope once they stop arriving. Clones share the same pending triggers.
+/// With a job log each trigger is recorded before it is acknowledged, so triggers still held back
+/// when the process stops are queued on the next start.
+#[derive(Clone)]
+pub struct Debouncer {
+    state: Arc<Mutex<DebounceState>>,
+    jobs: Arc<JobsConfiguration>,
+    job_log: Option<JobLog>,
+    logger: Logger,
+}
+
+struct DebounceState {
+    pending: HashMap<String, PendingRun>,
+    /// Dropped once the debouncer is closed, so the orchestrator can stop.
+    sender: Option<Sender<JobCommand>>,
+}
+
+/// The triggers of a scope waiting to be queued as one run, as kept in the job log.
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Serialize)]
+#[derive(Deserialize)]
+pub struct DebouncedRun {
+    pub scope: String,
+    pub job: String,
+    pub principal: Option<String>,
+    /// The parameters as supplied, resolved against the job when the run is queued.
+    pub parameters: HashMap<String, String>,
+    pub priority: Option<i64>,
+    pub triggers: Vec<DebouncedTrigger>,
+}
+
+#[derive(Clone)]
+struct PendingRun {
+    run: DebouncedRun,
+    /// Pushed back by each trigger, but never past the limit.
+    deadline: Instant,
+    limit: Option<Instant>,
+}
+
+/// A trigger held back by the debouncer.
+pub struct Debounced {
+    pub trigger_id: Uuid,
+    /// How long until the run is queued, unless a later trigger pushes it back.
+    pub run_after: Duration,
+    /// The number of triggers that will share the run so far.
+    pub triggers: usize,
+}
+
+impl DebouncedRun {
+    /// The trigger that started the run, which identifies it.
+    pub fn first_trigger(&self) -> Uuid {
+        self.triggers.first().map(|t| t.id).unwrap_or_default()
+    }
+}
+
+impl Debouncer {
+    pub fn new(jobs: Arc<JobsConfiguration>, sender: Sender<JobCommand>, job_log: Option<JobLog>, logger: Logger) -> Debouncer {
+        Debouncer { state: Arc::new(Mutex::new(DebounceState { pending: HashMap::new(), sender: Some(sender) })), jobs, job_log, logger }
+    }
+
+    /// Hold back a trigger, collapsing it into the run waiting for the same scope if there is one.
+    /// The parameters should already have been checked against the job.
+    pub fn trigger(&self, settings: &DebounceSettings, job: &str, principal: Option<String>, parameters: HashMap<String, String>, priority: Option<i64>) -> Result<Debounced, &'static str> {
+        let now = Instant::now();
+        let trigger = DebouncedTrigger { id: Uuid::new_v4(), triggered_by: principal.clone(), received_at: Utc::now() };
+        let trigger_id = trigger.id;
+
+        let mut state = self.lock();
+        if state.sender.is_none() {
+            return Err("Orchestrator not running.");
+        }
+
+        let started = !state.pending.contains_key(&settings.scope);
+        let pending =
+            match state.pending.get(&settings.scope).cloned() {
+                Some(mut pending) => {
+                    let run = &mut pending.run;
+                    match settings.merge {
+                        DebounceMerge::Latest => run.parameters = parameters,
+                        DebounceMerge::Merge => run.parameters.extend(parameters),
+                    }
+                    run.principal = principal;
+                    run.priority = priority.or(run.priority);
+                    run.triggers.push(trigger);
+                    pending.deadline = pending.limit.map(|l| l.min(now + settings.window)).unwrap_or(now + settings.window);
+                    pending
+                }
+                None => {
+                    let run = DebouncedRun { scope: settings.scope.clone(), job: job.to_string(), principal, parameters, priority, triggers: vec![trigger] };
+                    PendingRun { run, deadline: now + settings.window, limit: settings.max_wait.map(|m| now + m) }
+                }
+            };
+
+        // Record the trigger before it is acknowledged, the run waiting is only changed once it is on disk.
+        if let Some(l) = &self.job_log {
+            if let Err(e) = l.debounced(&pending.run) {
+                self.logger.log_error("debounce".to_string(), format!("Could not record trigger of `{}` in the job log: {}", job, e)).ok();
+                return Err("Could not record trigger.");
+            }
+        }
+        let debounced = Debounced { trigger_id, run_after: pending.deadline.saturating_duration_since(now), triggers: pending.run.triggers.len() };
+        state.pending.insert(settings.scope.clone(), pending);
+
+        if started {
+            let debouncer = self.clone();
+            let scope = settings.scope.clone();
+            thread::Builder::new()
+                .name(format!("debounce-{}", job))
+                .spawn(move || debouncer.wait(scope, trigger_id))
+                .map_err(|_| "Could not start debounce thread")?;
+        }
+        Ok(debounced)
+    }
+
+    /// Queue the runs the job log held back when the process last stopped. Their windows have
+    /// passed, so they are not held back again.
+    pub fn resume(&self, runs: Vec<DebouncedRun>) {
+        let sender =
+            match self.lock().sender.clone() {
+                Some(s) => s,
+                None => return
+            };
+        for run in runs {
+            self.logger.log_info("debounce".to_string(), format!("Queuing `{}` for {} trigger(s) held back when last stopped.", run.job, run.triggers.len())).ok();
+            self.queue(&sender, run);
+        }
+    }
+
+    /// Queue every waiting run now and stop accepting triggers.
+    pub fn close(&self) {
+        let (runs, sender) = {
+            let mut state = self.lock();
+            let runs: Vec<DebouncedRun> = state.pending.drain().map(|(_, p)| p.run).collect();
+            (runs, state.sender.take())
+        };
+        if let Some(sender) = sender {
+            runs.into_iter().for_each(|r| self.queue(&sender, r));
+        }
+    }
+
+    /// Wait for the deadline of the run started by the `first` trigger, then queue it.
+    fn wait(&self, scope: String, first: Uuid) {
+        let (run, sender) = loop {
+            let mut state = self.lock();
+            let deadline =
+                match state.pending.get(&scope) {
+                    Some(p) if p.run.first_trigger() == first => p.deadline,
+                    // Queued early by `close`.
+                    _ => return
+                };
+            let now = Instant::now();
+            if now >= deadline {
+                match (state.pending.remove(&scope), state.sender.clone()) {
+                    (Some(p), Some(s)) => break (p.run, s),
+                    _ => return
+                }
+            }
+            drop(state);
+            thread::sleep(deadline - now);
+        };
+        self.queue(&sender, run);
+    }
+
+    /// Queue the run, recording in the job log that its triggers are done with unless the orchestrator
+    /// has stopped, in which case they are queued on the next start.
+    fn queue(&self, sender: &Sender<JobCommand>, run: DebouncedRun) {
+        let count = run.triggers.len();
+        let first = run.first_trigger();
+        let parameters =
+            match self.jobs.get_job(&run.job).map(|j| resolve_parameters(&j.parameters, &run.parameters, false)) {
+                Some(Ok(p)) => p,
+                _ => {
+                    self.logger.log_error("debounce".to_string(), format!("Could not queue `{}` for {} trigger(s): invalid parameters.", run.job, count)).ok();
+                    self.finished(first);
+                    return;
+                }
+            };
+
+        let (reply_channel, reply) = channel();
+        let command = JobCommand {
+            name: run.job.clone(),
+            principal: run.principal,
+            parameters,
+            priority: run.priority,
+            resume: None,
+            parent: None,
+            triggers: run.triggers,
+            reply_channel,
+        };
+        let result = sender.send(command).ok().and_then(|_| reply.recv().ok());
+        match result {
+            Some(Ok(id)) => self.logger.log_info("debounce".to_string(), format!("Queued run {} of `{}` for {} trigger(s).", id, run.job, count)).ok(),
+            Some(Err(e)) => self.logger.log_error("debounce".to_string(), format!("Could not queue `{}` for {} trigger(s): {}", run.job, count, e.message())).ok(),
+            None => {
+                self.logger.log_error("debounce".to_string(), format!("Could not queue `{}` for {} trigger(s): Orchestrator not running.", run.job, count)).ok();
+                return;
+            }
+        };
+        self.finished(first);
+    }
+
+    fn finished(&self, first: Uuid) {
+        if let Some(Err(e)) = self.job_log.as_ref().map(|l| l.debounce_finished(first)) {
+            self.logger.log_error("debounce".to_string(), format!("Could not record queued triggers in the job log: {}", e)).ok();
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, DebounceState> {
+        self.state.lock().unwrap_or_else(|p| p.into_inner())
+    }
+}
+
+impl fmt::Debug for Debouncer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Debouncer").field("scopes", &self.lock().pending.keys().collect::<Vec<&String>>()).finish()
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, MutexGuard};
+use serde_json::{json, Value};
+use crate::auth::{Authorizer, JobPermission, Principal};
+use crate::http::{HttpRequest, HttpResponse};
+use crate::orchestration::JobContext;
+use crate::run_logs::{LogEntry, LogSubscriber, RunEvent};
+use crate::websocket::{upgrade, MessageHandler};
+
+/// The events a client of `/events` has asked for.
+#[derive(Clone)]
+#[derive(Debug)]
+struct EventFilter {
+    /// If none the events of every job are sent.
+    jobs: Option<HashSet<String>>,
+    /// Whether lines of action output are sent, as well as state changes.
+    output: bool,
+}
+
+impl EventFilter {
+    /// The filter from the query string, `?jobs=a,b&output=true`.
+    fn from_query(request: &HttpRequest) -> EventFilter {
+        let jobs = request.get_query_param("jobs").map(|j| j.split(',').filter(|n| !n.is_empty()).map(String::from).collect());
+        EventFilter { jobs, output: request.get_query_param("output") == Some("true") }
+    }
+
+    /// The filter from a client message, `{ "jobs": [names] or null, "output": bool }`.
+    fn from_message(message: &str) -> Result<EventFilter, &'static str> {
+        let value: Value = serde_json::from_str(message).map_err(|_| "Message is not valid json")?;
+        let jobs =
+            match &value["jobs"] {
+                Value::Null => None,
+                Value::Array(names) => {
+                    let names: Option<HashSet<String>> = names.iter().map(|n| n.as_str().map(String::from)).collect();
+                    Some(names.ok_or("Job names must be strings")?)
+                }
+                _ => return Err("`jobs` must be a list of job names")
+            };
+        let output =
+            match &value["output"] {
+                Value::Null => false,
+                Value::Bool(b) => *b,
+                _ => return Err("`output` must be true or false")
+            };
+        Ok(EventFilter { jobs, output })
+    }
+
+    fn matches(&self, entry: &LogEntry) -> bool {
+        let wanted = self.jobs.as_ref().map(|j| j.contains(&entry.job)).unwrap_or(true);
+        wanted && (self.output || !matches!(entry.event, RunEvent::Output { .. }))
+    }
+
+    fn to_message(&self) -> String {
+        json!({ "type": "subscribed", "jobs": self.jobs, "output": self.output }).to_string()
+    }
+}
+
+/// Handle `GET /events`, a WebSocket sending the log entries of every run as they happen: state changes
+/// as the run is queued, starts and finishes, each action starting and finishing and, if asked for, its output.
+///
+/// Only the jobs the principal can view are sent. The initial filter comes from the query string and
+/// the client replaces it by sending `{ "jobs": [names], "output": true }`, each filter is confirmed
+/// with a `subscribed` message. A client that falls behind the events is disconnected.
+pub fn handle_events(jobs: &JobContext, authorizer: &Authorizer, request: &HttpRequest, principal: Option<&Principal>) -> Result<HttpResponse, &'static str> {
+    let filter = Arc::new(Mutex::new(EventFilter::from_query(request)));
+
+    let client_filter = filter.clone();
+    let on_message: MessageHandler = Box::new(move |message| {
+        match EventFilter::from_message(message) {
+            Ok(f) => {
+                let reply = f.to_message();
+                *lock(&client_filter) = f;
+                Some(reply)
+            }
+            Err(e) => Some(json!({ "type": "error", "message": e }).to_string())
+        }
+    });
+
+    let (response, sender) =
+        match upgrade(request, on_message) {
+            Ok(u) => u,
+            Err(e) => {
+                let body = json!({ "message": e });
+                return Ok(HttpResponse::create(400, "application/json".to_string(), Some(body.to_string().into_bytes())));
+            }
+        };
+    sender.send_text(&lock(&filter).to_message())?;
+
+    let (authorizer, principal) = (authorizer.clone(), principal.cloned());
+    let watcher: LogSubscriber = Box::new(move |entry| {
+        // Checked for every entry, not only those sent, so a closed socket with a narrow filter is dropped.
+        if sender.is_closed() {
+            return false;
+        }
+        if !lock(&filter).matches(entry) || authorizer.authorize_job(principal.as_ref(), &entry.job, JobPermission::View).is_err() {
+            return true;
+        }
+        match serde_json::to_string(entry) {
+            Ok(message) => sender.send_text(&message).is_ok(),
+            Err(_) => true
+        }
+    });
+    jobs.aggregator.watch_events(watcher)?;
+    Ok(response)
+}
+
+fn lock(filter: &Mutex<EventFilter>) -> MutexGuard<'_, EventFilter> {
+    filter.lock().unwrap_or_else(|p| p.into_inner())
+}
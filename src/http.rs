@@ -1,5 +1,15 @@
 ﻿use std::collections::HashMap;
+use std::fs::File;
 use std::num::ParseIntError;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use mio::Waker;
+use crate::websocket::WebSocket;
+
+/// The most bytes of a streamed body waiting to be written before sending more fails,
+/// so a client reading slower than the body is produced is dropped rather than buffered.
+const MAX_PENDING_STREAM_BYTES: usize = 4 * 1024 * 1024;
 
 pub enum HttpVerb {
     GET,
@@ -34,6 +44,39 @@ pub struct HttpResponse {
     pub content_type: String,
     pub headers: HashMap<String, String>,
     pub body: Option<Vec<u8>>,
+    /// Sent after the headers instead of `body`, straight from disk.
+    pub file: Option<FileBody>,
+    /// Sent after the headers instead of `body`, as it is produced.
+    pub stream: Option<StreamBody>,
+    /// Set on a `101 Switching Protocols` response, the connection then carries WebSocket frames.
+    /// The frames the server sends arrive through `stream`.
+    pub websocket: Option<WebSocket>,
+}
+
+/// A response body sent from an open file rather than from memory.
+pub struct FileBody {
+    pub file: File,
+    pub length: u64,
+}
+
+/// A response body sent as it is produced. The body, and the connection, ends once every `BodySender` is dropped.
+pub struct StreamBody {
+    chunks: Receiver<Vec<u8>>,
+    /// The bytes sent but not yet received.
+    pending: Arc<AtomicUsize>,
+    /// Set once the body is dropped, as its connection closes.
+    closed: Arc<AtomicBool>,
+    waker: Arc<Mutex<Option<Arc<Waker>>>>,
+}
+
+/// Sends the chunks of a streamed response body. Clones feed the same body.
+#[derive(Clone)]
+pub struct BodySender {
+    sender: Sender<Vec<u8>>,
+    pending: Arc<AtomicUsize>,
+    closed: Arc<AtomicBool>,
+    /// Wakes the server's event loop to write each chunk.
+    waker: Arc<Mutex<Option<Arc<Waker>>>>,
 }
 
 
@@ -62,6 +105,37 @@ impl HttpRequest {
             body
         })
     }
+
+    /// The request body, empty if none was sent.
+    pub fn body(&self) -> &[u8] {
+        match &self.body {
+            None => &[],
+            Some(b) => b
+        }
+    }
+
+    /// The request route without any query string.
+    pub fn path(&self) -> &str {
+        match self.header.route.split_once('?') {
+            None => &self.header.route,
+            Some((path, _)) => path
+        }
+    }
+
+    /// Get a query string parameter by name, the value is not decoded.
+    pub fn get_query_param(&self, name: &str) -> Option<&str> {
+        self.header.route
+            .split_once('?')
+            .and_then(|(_, query)| query.split('&').find_map(|p| match p.split_once('=') {
+                Some((k, v)) if k == name => Some(v),
+                _ => None
+            }))
+    }
+
+    /// Get a request header by name, header names are matched case insensitively.
+    pub fn get_header(&self, name: &str) -> Option<&String> {
+        self.header.headers.get(&name.to_uppercase())
+    }
 }
 
 impl HttpRequestHeader {
@@ -79,7 +153,7 @@ impl HttpRequestHeader {
 
                 let request = HttpRequestHeader::parse_from_string(header)?;
 
-                return Ok((request, i));
+                return Ok((request, i + 1));
             }
         }
 
@@ -95,6 +169,10 @@ impl HttpRequestHeader {
 
         let split_status_line: Vec<&str> = split_header[0].split(" ").collect();
 
+        if split_status_line.len() < 3 {
+            return Err("Invalid request line");
+        }
+
         let verb = HttpVerb::from_str(split_status_line[0])?;
         let route = String::from(split_status_line[1]);
         let http_version = String::from(split_status_line[2]);
@@ -164,10 +242,61 @@ impl HttpResponse {
             content_type,
             headers: mapped_headers,
             body,
+            file: None,
+            stream: None,
+            websocket: None,
         }
     }
 
-    pub fn to_bytes(&mut self) -> Vec<u8> {
+    /// Create a response whose body is sent from a file of the given length.
+    pub fn create_file(code: i16, content_type: String, file: File, length: u64) -> HttpResponse {
+        let mut response = HttpResponse::create(code, content_type, None);
+        response.headers.insert("Content-Length".to_string(), format!("{}", length));
+        response.file = Some(FileBody { file, length });
+        response
+    }
+
+    /// Create a response whose body is sent in chunks as they are produced, until the returned sender is dropped.
+    /// The body is delimited by the connection closing, so it has no `Content-Length`.
+    pub fn create_stream(code: i16, content_type: String) -> (HttpResponse, BodySender) {
+        let (sender, chunks) = channel();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let closed = Arc::new(AtomicBool::new(false));
+        let waker = Arc::new(Mutex::new(None));
+        let mut response = HttpResponse::create(code, content_type, None);
+        response.headers.remove("Content-Length");
+        response.stream = Some(StreamBody { chunks, pending: pending.clone(), closed: closed.clone(), waker: waker.clone() });
+        (response, BodySender { sender, pending, closed, waker })
+    }
+
+    /// Add a header to the response, replacing any existing value.
+    pub fn add_header(&mut self, key: String, value: String) {
+        self.headers.insert(key, value);
+    }
+
+    /// Replace the body, keeping the `Content-Length` header in step.
+    pub fn set_body(&mut self, body: Option<Vec<u8>>) {
+        let len = body.as_ref().map(|b| b.len()).unwrap_or(0);
+        self.headers.insert("Content-Length".to_string(), format!("{}", len));
+        self.body = body;
+        self.file = None;
+        self.stream = None;
+        self.websocket = None;
+    }
+
+    /// Add a header name to the `Vary` header, keeping any names already listed.
+    pub fn add_vary(&mut self, name: &str) {
+        let value =
+            match self.headers.get("Vary") {
+                None => name.to_string(),
+                Some(v) if v.split(',').any(|n| n.trim().eq_ignore_ascii_case(name)) => v.clone(),
+                Some(v) => format!("{}, {}", v, name)
+            };
+        self.headers.insert("Vary".to_string(), value);
+    }
+
+    /// The status line and headers, ending with the blank line before the body.
+    pub fn header_bytes(&self) -> Vec<u8> {
         let response_type = get_response_type_str(self.code);
 
         // Create the header.
@@ -190,14 +319,15 @@ impl HttpResponse {
 
         header_string.push_str("\r\n");
 
-        // Get the bytes for the header and append the response body.
-        let mut bytes = Vec::from(header_string.as_bytes());
-
-
-        if let Some(b) = &self.body {
+        Vec::from(header_string.as_bytes())
+    }
 
-            let mut body = b.clone();
+    /// The header followed by the in-memory body, which is moved out of the response.
+    /// A file body is not included.
+    pub fn to_bytes(&mut self) -> Vec<u8> {
+        let mut bytes = self.header_bytes();
 
+        if let Some(mut body) = self.body.take() {
             bytes.append(&mut body);
         }
 
@@ -205,14 +335,70 @@ impl HttpResponse {
     }
 }
 
+impl StreamBody {
+    /// Have each chunk sent from now on wake the event loop writing the body.
+    pub fn set_waker(&self, waker: Arc<Waker>) {
+        if let Ok(mut w) = self.waker.lock() {
+            *w = Some(waker);
+        }
+    }
+
+    /// The next chunk sent, if there is one.
+    pub fn try_recv(&self) -> Result<Vec<u8>, TryRecvError> {
+        let chunk = self.chunks.try_recv()?;
+        self.pending.fetch_sub(chunk.len(), Ordering::SeqCst);
+        Ok(chunk)
+    }
+}
+
+impl Drop for StreamBody {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+impl BodySender {
+    /// True once the connection the body was being sent on has closed, nothing sent will arrive.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Send a chunk of the body. Fails once the connection has closed, or if the client has fallen
+    /// so far behind that `MAX_PENDING_STREAM_BYTES` are waiting to be written.
+    pub fn send(&self, chunk: Vec<u8>) -> Result<(), &'static str> {
+        let length = chunk.len();
+        if self.pending.fetch_add(length, Ordering::SeqCst) + length > MAX_PENDING_STREAM_BYTES {
+            self.pending.fetch_sub(length, Ordering::SeqCst);
+            return Err("Response stream fell behind");
+        }
+        if self.sender.send(chunk).is_err() {
+            self.pending.fetch_sub(length, Ordering::SeqCst);
+            return Err("Response stream closed");
+        }
+        if let Some(waker) = self.waker.lock().ok().and_then(|w| w.clone()) {
+            waker.wake().ok();
+        }
+        Ok(())
+    }
+}
+
 fn get_response_type_str(code: i16) -> &'static str {
     match code {
+        101 => "Switching Protocols",
         200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
         400 => "Bad Request",
         401 => "Unauthorized",
+        403 => "Forbidden",
         404 => "Not Found",
         405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        429 => "Too Many Requests",
         500 => "Internal Error",
+        503 => "Service Unavailable",
         _ => "Unknown",
     }
 }
\ No newline at end of file
@@ -0,0 +1,507 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::configuration::{InterruptPolicy, JobsConfiguration};
+use crate::debounce::{DebouncedRun, DebouncedTrigger};
+use crate::logging::logging::Logger;
+use crate::orchestration::{expand_actions, ActionState, ActionStatus, Aggregator, JobCommand, JobRunState, JobRunStatus, QueueError, ResumedRun};
+use crate::shutdown::join_with_deadline;
+
+/// Where the job log is kept and how often it is compacted.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct JobLogSettings {
+    pub path: String,
+    /// Rewrite the log without finished runs after this many runs have finished.
+    pub compact_after: usize,
+}
+
+/// A write-ahead log of job runs. Runs are recorded before they are acknowledged so that
+/// runs that had not finished when the process stopped can be replayed on the next start.
+/// Entries are written by a thread of their own, which syncs each batch of waiting entries at once
+/// and compacts the file, so the threads recording runs never wait on the disk.
+/// Clones share the same file.
+#[derive(Clone)]
+pub struct JobLog {
+    path: String,
+    sender: Sender<LogWrite>,
+    handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+struct JobLogFile {
+    settings: JobLogSettings,
+    file: File,
+    finished_since_compaction: usize,
+}
+
+enum LogWrite {
+    Entry { entry: Box<JobLogEntry>, sync: bool, acknowledgement: Option<Acknowledgement> },
+    Compact(Sender<Result<(), &'static str>>),
+    /// Write and sync everything before this, then stop.
+    Stop,
+}
+
+/// Sent once an entry is on disk.
+enum Acknowledgement {
+    /// The reply to a trigger. If its run can not be recorded the run is cancelled.
+    Run { run_id: Uuid, reply: Sender<Result<Uuid, QueueError>>, cancelled: Arc<AtomicBool> },
+    /// A caller waiting for the entry to be on disk.
+    Synced(Sender<Result<(), &'static str>>),
+}
+
+#[derive(Serialize)]
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JobLogEntry {
+    Accepted {
+        run_id: Uuid,
+        name: String,
+        triggered_by: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        parent: Option<Uuid>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        triggers: Vec<DebouncedTrigger>,
+        parameters: HashMap<String, String>,
+        priority: i64,
+        queued_at: DateTime<Utc>,
+    },
+    ActionStarted {
+        run_id: Uuid,
+        action: usize,
+    },
+    ActionFinished {
+        run_id: Uuid,
+        action: usize,
+        state: ActionState,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        outputs: HashMap<String, String>,
+    },
+    RunFinished {
+        run_id: Uuid,
+        state: JobRunState,
+    },
+    /// The triggers held back for a debounced run so far, recorded again as each arrives.
+    Debounced(DebouncedRun),
+    /// The debounced run started by this trigger has been queued, or refused.
+    DebounceFinished {
+        trigger_id: Uuid,
+    },
+}
+
+/// A run that had not finished, rebuilt from the log.
+struct UnfinishedRun {
+    id: Uuid,
+    name: String,
+    triggered_by: Option<String>,
+    parent: Option<Uuid>,
+    triggers: Vec<DebouncedTrigger>,
+    parameters: HashMap<String, String>,
+    priority: i64,
+    queued_at: DateTime<Utc>,
+    started: HashSet<usize>,
+    finished: HashMap<usize, ActionState>,
+    outputs: HashMap<usize, HashMap<String, String>>,
+}
+
+impl JobLogEntry {
+    fn run_id(&self) -> Option<Uuid> {
+        match self {
+            JobLogEntry::Accepted { run_id, .. } => Some(*run_id),
+            JobLogEntry::ActionStarted { run_id, .. } => Some(*run_id),
+            JobLogEntry::ActionFinished { run_id, .. } => Some(*run_id),
+            JobLogEntry::RunFinished { run_id, .. } => Some(*run_id),
+            JobLogEntry::Debounced(_) | JobLogEntry::DebounceFinished { .. } => None,
+        }
+    }
+}
+
+impl JobLog {
+    pub fn open(settings: JobLogSettings, logger: Logger) -> Result<JobLog, &'static str> {
+        let file = open_append(&settings.path)?;
+        let path = settings.path.clone();
+        let (sender, receiver) = channel();
+        let log_file = JobLogFile { settings, file, finished_since_compaction: 0 };
+        let handle =
+            thread::Builder::new()
+                .name("job-log".to_string())
+                .spawn(move || write_entries(receiver, log_file, logger))
+                .map_err(|_| "Could not start job log thread")?;
+        Ok(JobLog { path, sender, handle: Arc::new(Mutex::new(Some(handle))) })
+    }
+
+    /// Record an accepted run. The reply is sent once the entry has been synced to disk,
+    /// if it can not be the run is cancelled and the trigger refused.
+    pub fn accepted(&self, status: &JobRunStatus, reply: Sender<Result<Uuid, QueueError>>, cancelled: Arc<AtomicBool>) {
+        let entry = JobLogEntry::Accepted {
+            run_id: status.id,
+            name: status.name.clone(),
+            triggered_by: status.triggered_by.clone(),
+            parent: status.parent,
+            triggers: status.triggers.clone(),
+            parameters: status.parameters.clone(),
+            priority: status.priority,
+            queued_at: status.queued_at,
+        };
+        let acknowledgement = Acknowledgement::Run { run_id: status.id, reply, cancelled };
+        if let Err(LogWrite::Entry { acknowledgement: Some(a), .. }) = self.sender.send(LogWrite::Entry { entry: Box::new(entry), sync: true, acknowledgement: Some(acknowledgement) }).map_err(|e| e.0) {
+            a.complete(Err("Job log stopped"));
+        }
+    }
+
+    pub fn action_started(&self, run_id: Uuid, action: usize) -> Result<(), &'static str> {
+        self.append(JobLogEntry::ActionStarted { run_id, action }, false)
+    }
+
+    pub fn action_finished(&self, run_id: Uuid, action: usize, state: ActionState, outputs: &HashMap<String, String>) -> Result<(), &'static str> {
+        self.append(JobLogEntry::ActionFinished { run_id, action, state, outputs: outputs.clone() }, false)
+    }
+
+    /// Record a finished run, the log is compacted once enough runs have finished.
+    pub fn run_finished(&self, run_id: Uuid, state: JobRunState) -> Result<(), &'static str> {
+        self.append(JobLogEntry::RunFinished { run_id, state }, true)
+    }
+
+    /// Record the triggers held back for a debounced run so far, returning once they are on disk.
+    pub fn debounced(&self, run: &DebouncedRun) -> Result<(), &'static str> {
+        let (sender, reply) = channel();
+        let entry = JobLogEntry::Debounced(run.clone());
+        self.sender.send(LogWrite::Entry { entry: Box::new(entry), sync: true, acknowledgement: Some(Acknowledgement::Synced(sender)) }).map_err(|_| "Job log stopped")?;
+        reply.recv().unwrap_or(Err("Job log stopped"))
+    }
+
+    /// Record that the debounced run started by the trigger has been queued or refused. A run that
+    /// was queued is also known by its accepted entry, so this need not be synced.
+    pub fn debounce_finished(&self, trigger_id: Uuid) -> Result<(), &'static str> {
+        self.append(JobLogEntry::DebounceFinished { trigger_id }, false)
+    }
+
+    /// Rewrite the log keeping only the entries of runs that have not finished, once everything
+    /// recorded so far has been written.
+    pub fn compact(&self) -> Result<(), &'static str> {
+        let (sender, reply) = channel();
+        self.sender.send(LogWrite::Compact(sender)).map_err(|_| "Job log stopped")?;
+        reply.recv().unwrap_or(Err("Job log stopped"))
+    }
+
+    /// Write and sync everything recorded so far, then stop the writing thread. Returns false if it
+    /// did not stop by the deadline. Anything recorded afterwards is dropped.
+    pub fn stop(&self, deadline: Instant) -> bool {
+        self.sender.send(LogWrite::Stop).ok();
+        let handle = self.handle.lock().unwrap_or_else(|p| p.into_inner()).take();
+        match handle {
+            None => true,
+            Some(h) => join_with_deadline(h, deadline)
+        }
+    }
+
+    fn append(&self, entry: JobLogEntry, sync: bool) -> Result<(), &'static str> {
+        self.sender.send(LogWrite::Entry { entry: Box::new(entry), sync, acknowledgement: None }).map_err(|_| "Job log stopped")
+    }
+
+    /// The debounced runs in the log that were never queued, with every trigger they had received.
+    pub fn unfinished_debounces(&self) -> Vec<DebouncedRun> {
+        let entries = read_entries(&self.path);
+        let finished = finished_debounces(&entries);
+        let mut runs: Vec<DebouncedRun> = vec![];
+
+        for entry in entries {
+            if let JobLogEntry::Debounced(run) = entry {
+                if finished.contains(&run.first_trigger()) {
+                    continue;
+                }
+                // Triggers are only ever added, but may be recorded out of order, so the fullest record wins.
+                match runs.iter_mut().find(|r| r.first_trigger() == run.first_trigger()) {
+                    Some(r) if r.triggers.len() < run.triggers.len() => *r = run,
+                    Some(_) => {}
+                    None => runs.push(run)
+                }
+            }
+        }
+        runs
+    }
+
+    /// The runs in the log that have not finished, in the order they were accepted.
+    fn unfinished_runs(&self) -> Vec<UnfinishedRun> {
+        let entries = read_entries(&self.path);
+        let mut runs: Vec<UnfinishedRun> = vec![];
+        let mut finished: HashSet<Uuid> = HashSet::new();
+
+        for entry in entries {
+            match entry {
+                JobLogEntry::Accepted { run_id, name, triggered_by, parent, triggers, parameters, priority, queued_at } => {
+                    runs.push(UnfinishedRun { id: run_id, name, triggered_by, parent, triggers, parameters, priority, queued_at, started: HashSet::new(), finished: HashMap::new(), outputs: HashMap::new() });
+                }
+                JobLogEntry::ActionStarted { run_id, action } => {
+                    if let Some(run) = runs.iter_mut().find(|r| r.id == run_id) {
+                        run.started.insert(action);
+                    }
+                }
+                JobLogEntry::ActionFinished { run_id, action, state, outputs } => {
+                    if let Some(run) = runs.iter_mut().find(|r| r.id == run_id) {
+                        run.finished.insert(action, state);
+                        run.outputs.insert(action, outputs);
+                    }
+                }
+                JobLogEntry::RunFinished { run_id, .. } => {
+                    finished.insert(run_id);
+                }
+                JobLogEntry::Debounced(_) | JobLogEntry::DebounceFinished { .. } => {}
+            }
+        }
+
+        runs.retain(|r| !finished.contains(&r.id));
+        runs
+    }
+}
+
+impl JobLogFile {
+    fn write(&mut self, data: &str, sync: bool) -> Result<(), &'static str> {
+        self.file.write_all(data.as_bytes()).map_err(|_| "Could not write to job log")?;
+        if sync {
+            self.file.sync_data().map_err(|_| "Could not sync job log")?;
+        }
+        Ok(())
+    }
+
+    fn compact(&mut self) -> Result<(), &'static str> {
+        let entries = read_entries(&self.settings.path);
+        let finished: HashSet<Uuid> =
+            entries
+                .iter()
+                .filter_map(|e| match e {
+                    JobLogEntry::RunFinished { run_id, .. } => Some(*run_id),
+                    _ => None
+                })
+                .collect();
+
+        let finished_debounces = finished_debounces(&entries);
+
+        let mut data = String::new();
+        let kept =
+            entries
+                .iter()
+                .filter(|e| match (e.run_id(), e) {
+                    (Some(id), _) => !finished.contains(&id),
+                    (None, JobLogEntry::Debounced(run)) => !finished_debounces.contains(&run.first_trigger()),
+                    // Dropped along with the triggers it finished.
+                    (None, _) => false
+                });
+        for entry in kept {
+            data.push_str(&serde_json::to_string(entry).map_err(|_| "Could not serialize job log entry")?);
+            data.push('\n');
+        }
+
+        // Write the new log beside the old one and swap it in, so a crash leaves one or the other intact.
+        let temp_path = format!("{}.tmp", self.settings.path);
+        let mut temp = File::create(&temp_path).map_err(|_| "Could not create compacted job log")?;
+        temp.write_all(data.as_bytes()).map_err(|_| "Could not write compacted job log")?;
+        temp.sync_all().map_err(|_| "Could not sync compacted job log")?;
+        fs::rename(&temp_path, &self.settings.path).map_err(|_| "Could not replace job log")?;
+
+        self.file = open_append(&self.settings.path)?;
+        self.finished_since_compaction = 0;
+        Ok(())
+    }
+}
+
+impl Acknowledgement {
+    fn complete(self, result: Result<(), &'static str>) {
+        match (self, result) {
+            (Acknowledgement::Run { run_id, reply, .. }, Ok(_)) => { reply.send(Ok(run_id)).ok(); }
+            (Acknowledgement::Run { reply, cancelled, .. }, Err(_)) => {
+                cancelled.store(true, Ordering::SeqCst);
+                reply.send(Err(QueueError::Failed("Could not record job."))).ok();
+            }
+            (Acknowledgement::Synced(reply), result) => { reply.send(result).ok(); }
+        }
+    }
+}
+
+/// The first trigger of each debounced run that has been queued or refused. A run whose accepted
+/// entry lists the trigger was queued, even if the process stopped before that was recorded.
+fn finished_debounces(entries: &[JobLogEntry]) -> HashSet<Uuid> {
+    entries
+        .iter()
+        .flat_map(|e| match e {
+            JobLogEntry::DebounceFinished { trigger_id } => vec![*trigger_id],
+            JobLogEntry::Accepted { triggers, .. } => triggers.iter().map(|t| t.id).collect(),
+            _ => vec![]
+        })
+        .collect()
+}
+
+/// Write the log's entries as they arrive. Entries already waiting are written together, with one sync
+/// for the batch, before the acknowledgements waiting on them are sent.
+fn write_entries(receiver: Receiver<LogWrite>, mut log: JobLogFile, logger: Logger) {
+    let mut stopping = false;
+    while !stopping {
+        let first =
+            match receiver.recv() {
+                Ok(w) => w,
+                // Every clone has been dropped.
+                Err(_) => break
+            };
+
+        let mut data = String::new();
+        let mut sync = false;
+        let mut acknowledgements = vec![];
+        let mut compactions = vec![];
+        for write in std::iter::once(first).chain(receiver.try_iter()) {
+            match write {
+                LogWrite::Entry { entry, sync: s, acknowledgement } => {
+                    if let JobLogEntry::RunFinished { .. } = *entry {
+                        log.finished_since_compaction += 1;
+                    }
+                    match serde_json::to_string(&entry) {
+                        Ok(line) => {
+                            data.push_str(&line);
+                            data.push('\n');
+                        }
+                        Err(_) => { logger.log_error("job_log".to_string(), "Could not serialize job log entry.".to_string()).ok(); }
+                    };
+                    sync |= s;
+                    acknowledgements.extend(acknowledgement);
+                }
+                LogWrite::Compact(reply) => compactions.push(reply),
+                LogWrite::Stop => {
+                    stopping = true;
+                    sync = true;
+                }
+            }
+        }
+
+        let result = log.write(&data, sync);
+        if let Err(e) = result {
+            logger.log_error("job_log".to_string(), format!("Could not write to job log: {}", e)).ok();
+        }
+        acknowledgements.into_iter().for_each(|a| a.complete(result));
+
+        if !compactions.is_empty() || log.finished_since_compaction >= log.settings.compact_after {
+            let result = log.compact();
+            if let Err(e) = result {
+                logger.log_error("job_log".to_string(), format!("Could not compact job log: {}", e)).ok();
+            }
+            compactions.into_iter().for_each(|r| { r.send(result).ok(); });
+        }
+    }
+}
+
+fn open_append(path: &str) -> Result<File, &'static str> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|_| "Could not open job log")
+}
+
+/// Read every entry in the log. A partly written last line, left by a crash, is ignored.
+fn read_entries(path: &str) -> Vec<JobLogEntry> {
+    match fs::read_to_string(path) {
+        Err(_) => vec![],
+        Ok(data) => data.lines().filter_map(|l| serde_json::from_str(l).ok()).collect()
+    }
+}
+
+/// Replay the runs that had not finished when the process last stopped. Runs that had not started
+/// are queued again. Runs that were part way through are resumed or marked as interrupted,
+/// depending on the job's `on_interrupt` policy. The log is then compacted.
+pub fn replay_job_log(job_log: &JobLog, jobs: &JobsConfiguration, job_sender: &Sender<JobCommand>, aggregator: &Aggregator, logger: &Logger) {
+    for run in job_log.unfinished_runs() {
+        let job =
+            match jobs.get_job(&run.name) {
+                Some(j) => j,
+                None => {
+                    logger.log_error("job_log".to_string(), format!("Run {} of unknown job `{}` dropped.", run.id, run.name)).ok();
+                    job_log.run_finished(run.id, JobRunState::Failed).ok();
+                    continue;
+                }
+            };
+
+        let instances =
+            match expand_actions(job, &run.parameters) {
+                Ok(i) => i,
+                Err(errors) => {
+                    logger.log_error("job_log".to_string(), format!("Run {} of `{}` dropped: {}", run.id, run.name, errors.join(" "))).ok();
+                    job_log.run_finished(run.id, JobRunState::Failed).ok();
+                    continue;
+                }
+            };
+
+        if run.finished.len() == instances.len() {
+            // Every action finished, only the end of the run was not recorded.
+            let state =
+                match run.finished.values().any(|s| *s == ActionState::Failed) {
+                    true => JobRunState::Failed,
+                    false => JobRunState::Completed
+                };
+            job_log.run_finished(run.id, state).ok();
+            continue;
+        }
+
+        if !run.started.is_empty() && matches!(job.on_interrupt, InterruptPolicy::Fail) {
+            logger.log_warning("job_log".to_string(), format!("Run {} of `{}` was interrupted.", run.id, run.name)).ok();
+            let actions =
+                instances
+                    .iter()
+                    .enumerate()
+                    .map(|(i, instance)| {
+                        let state =
+                            match (run.finished.get(&i), run.started.contains(&i)) {
+                                (Some(s), _) => s.clone(),
+                                (None, true) => ActionState::Interrupted,
+                                (None, false) => ActionState::Skipped,
+                            };
+                        let outputs = run.outputs.get(&i).cloned().unwrap_or_default();
+                        ActionStatus { id: Uuid::new_v4(), name: instance.action.name.clone(), state, error: None, attempts: vec![], outputs, matrix: instance.matrix.clone() }
+                    })
+                    .collect();
+            let status = JobRunStatus {
+                id: run.id,
+                name: run.name.clone(),
+                state: JobRunState::Interrupted,
+                triggered_by: run.triggered_by,
+                parent: run.parent,
+                children: vec![],
+                triggers: run.triggers,
+                parameters: run.parameters,
+                priority: run.priority,
+                queued_at: run.queued_at,
+                finished_at: Some(Utc::now()),
+                actions,
+            };
+            aggregator.send_jobs(status, Arc::new(AtomicBool::new(false)));
+            job_log.run_finished(run.id, JobRunState::Interrupted).ok();
+            continue;
+        }
+
+        let (reply_channel, reply) = channel();
+        let command = JobCommand {
+            name: run.name.clone(),
+            principal: run.triggered_by,
+            parameters: run.parameters,
+            priority: Some(run.priority),
+            resume: Some(ResumedRun { id: run.id, queued_at: run.queued_at, finished: run.finished, outputs: run.outputs }),
+            parent: run.parent,
+            triggers: run.triggers,
+            reply_channel,
+        };
+        let result = job_sender.send(command).map_err(|_| "Orchestrator not running.").and_then(|_| reply.recv().map_err(|_| "Orchestrator not running.").and_then(|r| r.map_err(|e| e.message())));
+        match (result, run.started.is_empty()) {
+            (Ok(id), true) => logger.log_info("job_log".to_string(), format!("Queued run {} of `{}` again.", id, run.name)).ok(),
+            (Ok(id), false) => logger.log_info("job_log".to_string(), format!("Resuming interrupted run {} of `{}`.", id, run.name)).ok(),
+            (Err(e), _) => logger.log_error("job_log".to_string(), format!("Could not replay run {} of `{}`: {}", run.id, run.name, e)).ok()
+        };
+    }
+
+    if let Err(e) = job_log.compact() {
+        logger.log_error("job_log".to_string(), format!("Could not compact job log: {}", e)).ok();
+    }
+}
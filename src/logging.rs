@@ -1,20 +1,22 @@
 ﻿pub mod logging {
 
     use std::thread;
-    use std::sync::mpsc;
+    use std::sync::{mpsc, Arc};
+    use std::sync::atomic::{AtomicBool, Ordering};
     use std::thread::JoinHandle;
+    use std::time::Duration;
     use chrono::Utc;
-    use std::sync::mpsc::{Sender, Receiver};
+    use std::sync::mpsc::{Sender, Receiver, RecvTimeoutError};
 
     #[derive(Clone)]
     pub struct Logger {
         sender: mpsc::Sender<LogItem>
     }
 
-    #[allow(dead_code)]
     pub struct Log {
         handler: JoinHandle<()>,
-        logger: Logger
+        logger: Logger,
+        stopping: Arc<AtomicBool>,
     }
 
     pub struct LogItem {
@@ -98,9 +100,17 @@
             println!("[{} info  ] {} {}", Utc::now().format("%F %H:%M:%S%.3f"), "logger", "Starting...");
             ConsoleColor::reset();
 
+            let stopping = Arc::new(AtomicBool::new(false));
+            let handler_stopping = stopping.clone();
+            // Loggers may still be held by threads that outlive shutdown,
+            // so stop once the channel is empty rather than waiting for every sender to drop.
             let handler = thread::spawn(move || loop {
-                let item = receiver.recv().unwrap();
-                item.print();
+                match receiver.recv_timeout(Duration::from_millis(50)) {
+                    Ok(item) => item.print(),
+                    Err(RecvTimeoutError::Timeout) if handler_stopping.load(Ordering::SeqCst) => break,
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
             });
 
 
@@ -108,13 +118,19 @@
             println!("[{} ok    ] {} {}", Utc::now().format("%F %H:%M:%S%.3f"), "logger", "Started successfully");
             ConsoleColor::reset();
 
-            Ok(Log { handler, logger })
+            Ok(Log { handler, logger, stopping })
         }
 
         pub fn get_logger(&self) -> Logger {
             self.logger.clone()
         }
 
+        /// Print everything already logged and stop the log thread.
+        pub fn stop(self) {
+            self.stopping.store(true, Ordering::SeqCst);
+            self.handler.join().ok();
+        }
+
         /// A static method to create and print a `info` log message.
         pub fn print_info(from: String, message: String) {
             LogItem::info(from, message).print()
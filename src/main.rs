@@ -7,11 +7,30 @@ mod routing;
 mod agents;
 mod configuration;
 mod commands;
+mod auth;
+mod api;
+mod webhooks;
+mod access;
+mod metrics;
+mod rate_limiting;
+mod cors;
+mod compression;
+mod shutdown;
+mod sendfile;
+mod job_log;
+mod parameters;
+mod conditions;
+mod concurrency;
+mod debounce;
+mod run_logs;
+mod websocket;
+mod events;
 
 use std::net::{TcpListener, TcpStream};
 use std::{fs, thread, time};
 use std::fs::read_to_string;
 use std::process::{Command, Output};
+use std::sync::Arc;
 use std::sync::mpsc::channel;
 use std::thread::Thread;
 use crate::connection_pool::ConnectionPool;
@@ -20,35 +39,91 @@ use crate::server::Server;
 use regex::{Error, Regex};
 use serde_json::{Map, Value};
 use crate::agents::{Agent, MessageType};
-use crate::commands::{format_output, run_command, run_command_static};
+use crate::commands::{format_output, run_command};
 use crate::http::HttpResponse;
 use crate::routing::{Route, RouteHandler, RouteMap};
 use crate::configuration::*;
-use crate::orchestration::{Aggregator, Orchestrator};
+use crate::metrics::Metrics;
+use crate::job_log::{replay_job_log, JobLog};
+use crate::debounce::Debouncer;
+use crate::orchestration::{Aggregator, JobContext, Orchestrator, Triggers, WorkerPools};
+use crate::shutdown::{join_with_deadline, restore_queued_jobs, stop_jobs, Shutdown};
+
+/// How long the job and aggregator threads get to stop once jobs are finished.
+const THREAD_STOP_TIMEOUT: time::Duration = time::Duration::from_secs(5);
 
 fn main() {
     
-    let jobs_config = JobsConfiguration::load("jobs.json".to_string()).unwrap();
+    let jobs_config = Arc::new(JobsConfiguration::load("jobs.json".to_string()).unwrap());
+    let orch_jobs_config = jobs_config.clone();
     
     let log = Log::create().unwrap();
     let logger = log.get_logger();
     let (job_sender, job_receiver) = channel();
 
+    let shutdown = Shutdown::new();
+    shutdown.install(log.get_logger()).unwrap();
+
+    let job_log = jobs_config.job_log().map(|s| JobLog::open(s.clone(), log.get_logger()).unwrap());
+    let orch_job_log = job_log.clone();
+
     let orch_logger = log.get_logger();
-    let aggregator = Aggregator::start(log.get_logger());
+    let triggers = Triggers::new(jobs_config.clone(), job_sender.clone());
+    let (aggregator, aggregator_handle) = Aggregator::start(log.get_logger(), job_log.clone(), triggers);
     let orch_agg = aggregator.clone();
+    let pools = WorkerPools::new(jobs_config.queues(), aggregator.clone(), log.get_logger());
+    let orch_pools = pools.clone();
 
-    let _ = thread::spawn(|| {
-        Orchestrator::run(job_receiver, orch_agg, jobs_config, orch_logger)
+    let orchestrator_handle = thread::spawn(|| {
+        Orchestrator::run(job_receiver, orch_agg, orch_jobs_config, orch_pools, orch_job_log, orch_logger)
     });
     
-    match Configuration::load("config.json".to_string(), job_sender.clone()) {
+    // Runs left unfinished when the process last stopped are queued again before new ones are accepted.
+    if let Some(l) = &job_log {
+        replay_job_log(l, &jobs_config, &job_sender, &aggregator, &logger);
+    }
+
+    let metrics = Metrics::new();
+    
+    let debouncer = Debouncer::new(jobs_config.clone(), job_sender.clone(), job_log.clone(), log.get_logger());
+    if let Some(l) = &job_log {
+        debouncer.resume(l.unfinished_debounces());
+    }
+    let job_context = JobContext { config: jobs_config, sender: job_sender.clone(), debouncer: debouncer.clone(), aggregator: aggregator.clone(), pools };
+
+    match Configuration::load("config.json".to_string(), job_context, metrics) {
         Ok(config) => {
             //println!("{:?}", jobs_config);
-            Server::start(config, log.get_logger())
+            let policy = config.shutdown.clone();
+            restore_queued_jobs(&policy, &job_sender, &logger);
+            Server::start(config, log.get_logger(), shutdown);
+            // Debounced triggers are queued now so they are saved with the other queued jobs.
+            debouncer.close();
+            stop_jobs(&policy, &aggregator, &logger);
         }
         Err(e) => {
-            println!("Error loading config: {}", e)
+            println!("Error loading config: {}", e);
+            // Lets the aggregator and debouncer drop their job senders so the orchestrator can stop.
+            aggregator.shutdown(true);
+            debouncer.close();
         }
     }
+
+    // Dropping the last senders lets the orchestrator, its workers and then the aggregator stop.
+    drop(job_sender);
+    if !join_with_deadline(orchestrator_handle, time::Instant::now() + THREAD_STOP_TIMEOUT) {
+        logger.log_warning("shutdown".to_string(), "Orchestrator did not stop in time.".to_string()).ok();
+    }
+    drop(aggregator);
+    if !join_with_deadline(aggregator_handle, time::Instant::now() + THREAD_STOP_TIMEOUT) {
+        logger.log_warning("shutdown".to_string(), "Aggregator did not stop in time.".to_string()).ok();
+    }
+    if let Some(l) = job_log {
+        if !l.stop(time::Instant::now() + THREAD_STOP_TIMEOUT) {
+            logger.log_warning("shutdown".to_string(), "Job log did not stop in time.".to_string()).ok();
+        }
+    }
+
+    logger.log_info("shutdown".to_string(), "Shutdown complete.".to_string()).ok();
+    log.stop();
 }
\ No newline at end of file
@@ -0,0 +1,56 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use serde_json::{json, Value};
+use crate::rate_limiting::RateLimiter;
+
+/// Shared server counters, cloned handles all update the same values.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct Metrics {
+    counters: Arc<Mutex<BTreeMap<&'static str, u64>>>,
+    rate_limiters: Arc<Mutex<Vec<RateLimiter>>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            counters: Arc::new(Mutex::new(BTreeMap::new())),
+            rate_limiters: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn increment(&self, name: &'static str) {
+        self.add(name, 1);
+    }
+
+    pub fn add(&self, name: &'static str, value: u64) {
+        if let Ok(mut counters) = self.counters.lock() {
+            *counters.entry(name).or_insert(0) += value;
+        }
+    }
+
+    /// Register a rate limiter so its state is included in snapshots.
+    pub fn register_rate_limiter(&self, limiter: RateLimiter) {
+        if let Ok(mut limiters) = self.rate_limiters.lock() {
+            limiters.push(limiter);
+        }
+    }
+
+    pub fn snapshot(&self) -> Value {
+        let counters =
+            match self.counters.lock() {
+                Ok(c) => c.iter().map(|(k, v)| (k.to_string(), json!(v))).collect(),
+                Err(_) => serde_json::Map::new()
+            };
+        let rate_limiters: Vec<Value> =
+            match self.rate_limiters.lock() {
+                Ok(l) => l.iter().map(|r| r.snapshot()).collect(),
+                Err(_) => vec![]
+            };
+
+        json!({
+            "counters": counters,
+            "rate_limiters": rate_limiters
+        })
+    }
+}
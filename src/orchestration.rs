@@ -1,248 +1,1590 @@
-﻿use std::{thread, time};
-use std::collections::HashMap;
+﻿use std::{fmt, thread, time};
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
 use std::process::Output;
-use std::sync::{Arc, mpsc, Mutex};
+use std::sync::{Arc, Condvar, mpsc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{Sender, Receiver, TryRecvError, SendError};
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
-use crate::commands::{format_output, run_command, run_command_static};
-use crate::configuration::{ActionType, JobConfiguration, JobsConfiguration};
+use crate::connection_pool::panic_message;
+use crate::commands::{format_output, run_command, run_command_with_timeout, CommandError, OutputSink, OutputStream};
+use crate::concurrency::{Admission, JobConcurrency, LockStatus, RunGate, RunRequest};
+use crate::conditions::Condition;
+use crate::debounce::{DebouncedTrigger, Debouncer};
+use crate::run_logs::{LogSubscriber, RunEvent, RunLogs};
+use crate::configuration::{ActionConfiguration, ActionOutput, ActionType, CommandActionType, ConcurrencyPolicy, JobConfiguration, JobsConfiguration, JobTrigger, OutputSource, QueueConfiguration, RetryPolicy};
+use crate::job_log::JobLog;
 use crate::logging::logging::{Log, Logger};
+use crate::parameters::{matrix_key, output_key, render, resolve_parameters, state_key};
+
+/// The number of finished job runs the aggregator keeps for the status api.
+const MAX_HISTORY: usize = 100;
+
+/// The largest value an action may publish as an output.
+const MAX_OUTPUT_LENGTH: usize = 64 * 1024;
 
 pub struct Orchestrator {
-    workers: WorkerPool,
+    pools: WorkerPools,
     logger: Logger,
     handler: JoinHandle<()>
 }
 
 #[derive(Clone)]
+#[derive(Debug)]
 pub struct Aggregator {
     sender: Sender<AggregatorMessage>
 }
 
+/// Queues the jobs a finished run triggers. Used by the aggregator, which knows when runs finish.
+pub struct Triggers {
+    jobs: Arc<JobsConfiguration>,
+    /// Dropped when the aggregator is told to shut down, so the orchestrator can stop.
+    sender: Option<Sender<JobCommand>>,
+}
+
 enum AggregatorMessage {
-    NewJobSet(Uuid),
-    ProgressReport(),
-    CompletedJob(Uuid)
+    NewJobSet(JobRunStatus, Arc<AtomicBool>),
+    StartedJob(Uuid, Uuid),
+    ProgressReport(Sender<Vec<JobRunStatus>>),
+    CompletedJob(Uuid, Uuid, HashMap<String, String>),
+    SkippedJob(Uuid, Uuid),
+    ConditionUnmet(Uuid, Uuid),
+    FailedJob(Uuid, Uuid, String),
+    AttemptFinished(Uuid, Uuid, AttemptStatus),
+    RetryingJob(Uuid, Uuid),
+    /// A line of output from an action's command.
+    Output(Uuid, Uuid, OutputStream, String),
+    SubscribeLogs(Uuid, Option<u64>, LogSubscriber, Sender<Result<(), &'static str>>),
+    WatchEvents(LogSubscriber),
+    CancelJobSet(Uuid, Sender<Result<JobRunStatus, &'static str>>),
+    SetPriority(Uuid, i64),
+    /// Cancel runs that have not started (and running ones if set), replying with the runs that had not started.
+    Shutdown(bool, Sender<Vec<JobRunStatus>>),
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Serialize)]
+pub struct JobRunStatus {
+    pub id: Uuid,
+    pub name: String,
+    pub state: JobRunState,
+    pub triggered_by: Option<String>,
+    /// The run whose trigger queued this one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<Uuid>,
+    /// The runs this one's triggers queued.
+    pub children: Vec<Uuid>,
+    /// The triggers collapsed into this run by debouncing, oldest first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub triggers: Vec<DebouncedTrigger>,
+    pub parameters: HashMap<String, String>,
+    /// Queued actions of higher priority runs start first.
+    pub priority: i64,
+    pub queued_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub actions: Vec<ActionStatus>,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Serialize)]
+#[derive(Deserialize)]
+#[derive(PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobRunState {
+    Queued,
+    Running,
+    Completed,
+    Cancelled,
+    Failed,
+    /// The process stopped part way through the run.
+    Interrupted,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Serialize)]
+pub struct ActionStatus {
+    pub id: Uuid,
+    pub name: String,
+    pub state: ActionState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub attempts: Vec<AttemptStatus>,
+    /// The values the action published, once it has completed.
+    pub outputs: HashMap<String, String>,
+    /// The matrix values of this instance of the action.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub matrix: HashMap<String, String>,
+}
+
+/// The outcome of one attempt at running an action.
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Serialize)]
+pub struct AttemptStatus {
+    pub attempt: u32,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub succeeded: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Why an action attempt failed.
+pub struct ActionFailure {
+    pub message: String,
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Serialize)]
+#[derive(Deserialize)]
+#[derive(PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionState {
+    Queued,
+    Running,
+    Completed,
+    Skipped,
+    Failed,
+    /// The process stopped while the action was running.
+    Interrupted,
+    /// An attempt failed and the action is waiting to be tried again.
+    Retrying,
+    /// The action's `when` condition did not hold.
+    ConditionUnmet,
 }
 
+/// The named job queues, each with its own workers. Clones share the same queues.
+#[derive(Clone)]
+pub struct WorkerPools {
+    pools: Arc<HashMap<String, WorkerPool>>,
+    gate: RunGate<PendingRun>,
+}
+
+/// A job queue and the elastic set of worker threads taking jobs from it.
 pub struct WorkerPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Job>,
+    shared: Arc<PoolShared>,
 }
 
-pub type JobHandler = Box<dyn FnOnce(Uuid) -> String + Send + 'static>;
+/// A run's first jobs, held by the gate until the run may start.
+struct PendingRun {
+    pool: Arc<PoolShared>,
+    jobs: Vec<Job>,
+}
+
+struct PoolShared {
+    config: QueueConfiguration,
+    /// Shared by every queue, as locks are shared across jobs.
+    gate: RunGate<PendingRun>,
+    state: Mutex<PoolState>,
+    /// Signalled when a job is queued or the pool is closed.
+    available: Condvar,
+    aggregator: Aggregator,
+    logger: Logger,
+}
+
+struct PoolState {
+    queue: VecDeque<QueuedJob>,
+    threads: usize,
+    /// Workers waiting for a job.
+    idle: usize,
+    /// Workers running a job.
+    active: usize,
+    /// Jobs taken from the queue, used for the average wait.
+    started: u64,
+    completed: u64,
+    total_wait: Duration,
+    next_sequence: u64,
+    next_id: usize,
+    handles: Vec<JoinHandle<()>>,
+    closed: bool,
+}
+
+struct QueuedJob {
+    job: Job,
+    queued_at: Instant,
+    /// Orders jobs of the same priority by when they were queued.
+    sequence: u64,
+}
+
+/// A job waiting in a queue, as listed by the queue api.
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Serialize)]
+pub struct QueuedJobInfo {
+    /// The number of jobs ahead of this one, plus one.
+    pub position: usize,
+    pub run_id: Uuid,
+    pub id: Uuid,
+    pub job: String,
+    pub action: String,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub matrix: HashMap<String, String>,
+    pub priority: i64,
+    pub waiting_ms: u64,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Serialize)]
+pub struct QueueStats {
+    pub name: String,
+    pub min_workers: usize,
+    pub max_workers: usize,
+    pub workers: usize,
+    /// Jobs waiting for a worker.
+    pub depth: usize,
+    /// Jobs being run.
+    pub active: usize,
+    pub completed: u64,
+    /// The average time jobs waited in the queue before starting.
+    pub average_wait_ms: u64,
+}
+
+/// Runs one attempt of an action with the run's parameters and the outputs of the actions before it,
+/// returning the action's outputs. It is called again for each retry.
+pub type JobHandler = Box<dyn Fn(Uuid, &HashMap<String, String>) -> Result<HashMap<String, String>, ActionFailure> + Send + 'static>;
 
 pub struct Job {
     id: Uuid,
-    handler: JobHandler
+    run_id: Uuid,
+    job_name: String,
+    action_name: String,
+    priority: i64,
+    retry: Option<RetryPolicy>,
+    cancelled: Arc<AtomicBool>,
+    handler: JobHandler,
+    when: Option<Condition>,
+    /// The matrix values of this instance of the action.
+    matrix: HashMap<String, String>,
+    run: Arc<Mutex<RunProgress>>,
+}
+
+/// What a run has produced so far and the actions it still has to queue, shared by the run's jobs.
+struct RunProgress {
+    /// The run's parameters, the outputs published so far and the state of each finished action.
+    values: HashMap<String, String>,
+    priority: i64,
+    /// Set once an action has failed, actions without a condition are then skipped.
+    failed: bool,
+    /// The instances of the current action that have not finished.
+    running: usize,
+    /// The actions after the current one, each as the instances that run side by side.
+    stages: VecDeque<Vec<Job>>,
+}
+
+/// One instance of a job's action, an action with a matrix has one for each combination of its values.
+pub struct ActionInstance<'a> {
+    /// The action's position in the job.
+    pub index: usize,
+    pub action: &'a ActionConfiguration,
+    pub matrix: HashMap<String, String>,
+}
+
+/// The handles request handlers use to queue jobs and report on them.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct JobContext {
+    pub config: Arc<JobsConfiguration>,
+    pub sender: Sender<JobCommand>,
+    pub debouncer: Debouncer,
+    pub aggregator: Aggregator,
+    pub pools: WorkerPools,
 }
 
 pub struct JobCommand {
     pub(crate) name: String,
-    pub(crate) reply_channel: Sender<Result<Uuid, &'static str>>
+    pub(crate) principal: Option<String>,
+    pub(crate) parameters: HashMap<String, String>,
+    /// Overrides the job's default priority.
+    pub(crate) priority: Option<i64>,
+    /// Set when replaying a run from the job log.
+    pub(crate) resume: Option<ResumedRun>,
+    /// The run whose trigger queued this one.
+    pub(crate) parent: Option<Uuid>,
+    /// The triggers collapsed into the run by debouncing.
+    pub(crate) triggers: Vec<DebouncedTrigger>,
+    pub(crate) reply_channel: Sender<Result<Uuid, QueueError>>
+}
+
+/// Why the orchestrator did not queue a run.
+#[derive(Debug)]
+pub enum QueueError {
+    /// The job or its queue does not exist, or the run could not be recorded.
+    Failed(&'static str),
+    /// The job's concurrency policy refused the run.
+    Rejected(&'static str),
+    /// The run's parameters leave it with nothing to run.
+    Invalid(&'static str),
+    /// The run's parameters could not be expanded into its actions, holds every problem found.
+    InvalidParameters(Vec<String>),
+}
+
+impl QueueError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            QueueError::Failed(m) | QueueError::Rejected(m) | QueueError::Invalid(m) => m,
+            QueueError::InvalidParameters(_) => "Invalid parameters."
+        }
+    }
+}
+
+/// A run from the job log that is queued again under its original id.
+pub struct ResumedRun {
+    pub id: Uuid,
+    pub queued_at: DateTime<Utc>,
+    /// The states of action instances that finished before the run was interrupted, by index.
+    /// These are not run again.
+    pub finished: HashMap<usize, ActionState>,
+    /// The outputs the finished actions published, by index.
+    pub outputs: HashMap<usize, HashMap<String, String>>,
 }
 
 //pub type Job = 
 
 impl Orchestrator {
-    pub fn run(receiver: Receiver<JobCommand>, aggregator: Aggregator, jobs_config: JobsConfiguration, logger: Logger) {
+    pub fn run(receiver: Receiver<JobCommand>, aggregator: Aggregator, jobs_config: Arc<JobsConfiguration>, pools: WorkerPools, job_log: Option<JobLog>, logger: Logger) {
         //let (sender , receiver) : (Sender<Job>, Receiver<Job>) = mpsc::channel();
 
-        let workers = WorkerPool::new(4, aggregator.clone(), logger.clone());
-        
         loop {
             
-            let job_command = receiver.recv().unwrap();
-            logger.log_info(format!("orch"), format!("Job command received."));
+            let job_command =
+                match receiver.recv() {
+                    Ok(c) => c,
+                    // Every sender has been dropped, the server is shutting down.
+                    Err(_) => break
+                };
+            logger.log_info(format!("orch"), format!("Job command received.")).ok();
             // Get the job command.
             match jobs_config.get_job(job_command.name.as_str()) {
                 None => {
-                    logger.log_error("orch".to_string(), format!("Job `{}` not found.", job_command.name));
-                    job_command.reply_channel.send(Err("Job not found."));
+                    logger.log_error("orch".to_string(), format!("Job `{}` not found.", job_command.name)).ok();
+                    job_command.reply_channel.send(Err(QueueError::Failed("Job not found."))).ok();
                 }
                 Some(jc) => {
-                    let id = Uuid::new_v4();
-                    logger.log_info(format!("orch"), format!("Job received. Assigned id: {}", id));
-                    let job_ids: Vec<Uuid> =
-                        jc.actions
-                            .iter()
-                            .map(|a|{
-                              let j = create_job_handler(a);
-                                workers.execute(j.id, j.handler);
-                                aggregator.send_jobs(j.id);
-                                j.id
-                            })
-                            .collect();
-                    
-                    
-                    // Create the job handler(s) for actions.
-                    //workers.execute(id, job)        
+                    let pool =
+                        match pools.get(&jc.queue) {
+                            Some(p) => p,
+                            None => {
+                                logger.log_error("orch".to_string(), format!("Queue `{}` for job `{}` not found.", jc.queue, jc.name)).ok();
+                                job_command.reply_channel.send(Err(QueueError::Failed("Job queue not found."))).ok();
+                                continue;
+                            }
+                        };
+
+                    // Apply the job's concurrency policy. A replayed run was already accepted so always runs.
+                    let mut cancel_previous = vec![];
+                    if let (Some(limit), None) = (&jc.concurrency, &job_command.resume) {
+                        let earlier = pools.gate().runs_of(&jc.name);
+                        match (&limit.policy, earlier.len() >= limit.limit) {
+                            (ConcurrencyPolicy::Reject, true) => {
+                                logger.log_warning("orch".to_string(), format!("Job `{}` already running, run refused.", jc.name)).ok();
+                                job_command.reply_channel.send(Err(QueueError::Rejected("Job is already running."))).ok();
+                                continue;
+                            }
+                            (ConcurrencyPolicy::Coalesce, _) => {
+                                if let Some(waiting) = pools.gate().waiting_run(&jc.name) {
+                                    logger.log_info("orch".to_string(), format!("Job `{}` coalesced into waiting run {}.", jc.name, waiting)).ok();
+                                    job_command.reply_channel.send(Ok(waiting)).ok();
+                                    continue;
+                                }
+                            }
+                            (ConcurrencyPolicy::CancelPrevious, true) => cancel_previous = earlier,
+                            _ => {}
+                        }
+                    }
+
+                    let (id, queued_at, finished, mut resumed_outputs) =
+                        match &job_command.resume {
+                            Some(r) => (r.id, r.queued_at, r.finished.clone(), r.outputs.clone()),
+                            None => (Uuid::new_v4(), Utc::now(), HashMap::new(), HashMap::new())
+                        };
+                    logger.log_info(format!("orch"), format!("Job received. Assigned id: {}", id)).ok();
+                    let cancelled = Arc::new(AtomicBool::new(false));
+                    let priority = job_command.priority.unwrap_or(jc.priority);
+
+                    // Create the job handler(s) for each action instance, grouping the instances of an action into a stage.
+                    // Actions a resumed run already finished are not run again, but their outcomes and outputs
+                    // are still available to the actions after them.
+                    let run = Arc::new(Mutex::new(RunProgress { values: job_command.parameters.clone(), priority, failed: false, running: 0, stages: VecDeque::new() }));
+                    let mut progress = run.lock().unwrap_or_else(|p| p.into_inner());
+                    let mut actions: Vec<ActionStatus> = vec![];
+                    let mut last_index = None;
+                    let instances =
+                        match expand_actions(jc, &job_command.parameters) {
+                            Ok(i) => i,
+                            Err(errors) => {
+                                logger.log_error("orch".to_string(), format!("Job `{}` refused: {}", jc.name, errors.join(" "))).ok();
+                                if let (Some(l), Some(r)) = (&job_log, &job_command.resume) {
+                                    l.run_finished(r.id, JobRunState::Failed).ok();
+                                }
+                                job_command.reply_channel.send(Err(QueueError::InvalidParameters(errors))).ok();
+                                continue;
+                            }
+                        };
+                    for (i, instance) in instances.into_iter().enumerate() {
+                        let j = create_job_handler(id, &jc.name, &instance, priority, cancelled.clone(), run.clone(), &aggregator);
+                        let mut status = ActionStatus { id: j.id, name: instance.action.name.clone(), state: ActionState::Queued, error: None, attempts: vec![], outputs: HashMap::new(), matrix: instance.matrix.clone() };
+                        match finished.get(&i) {
+                            Some(state) => {
+                                status.state = state.clone();
+                                status.outputs = resumed_outputs.remove(&i).unwrap_or_default();
+                                progress.record(&instance.action.name, state, &status.outputs);
+                            }
+                            None => match (progress.stages.back_mut(), last_index == Some(instance.index)) {
+                                (Some(stage), true) => stage.push(j),
+                                _ => progress.stages.push_back(vec![j])
+                            }
+                        }
+                        last_index = Some(instance.index);
+                        actions.push(status);
+                    }
+                    let first = progress.next_stage();
+                    drop(progress);
+
+                    // The gate is only released as a run's last action finishes, so a run with nothing
+                    // to queue would hold its concurrency slot and locks forever.
+                    if first.is_empty() {
+                        logger.log_error("orch".to_string(), format!("Job `{}` refused: no actions to run.", jc.name)).ok();
+                        if let (Some(l), Some(r)) = (&job_log, &job_command.resume) {
+                            l.run_finished(r.id, JobRunState::Failed).ok();
+                        }
+                        job_command.reply_channel.send(Err(QueueError::Invalid("Job run has no actions to run."))).ok();
+                        continue;
+                    }
+
+                    let status = JobRunStatus {
+                        id,
+                        name: jc.name.clone(),
+                        state: JobRunState::Queued,
+                        triggered_by: job_command.principal.clone(),
+                        parent: job_command.parent,
+                        children: vec![],
+                        triggers: job_command.triggers.clone(),
+                        parameters: job_command.parameters.clone(),
+                        priority,
+                        queued_at,
+                        finished_at: None,
+                        actions,
+                    };
+
+                    // Record the run before acknowledging it, a replayed run is already in the log.
+                    // The job log acknowledges the run once it is on disk, so the orchestrator does not wait for it.
+                    let reply =
+                        match (&job_log, &job_command.resume) {
+                            (Some(l), None) => {
+                                l.accepted(&status, job_command.reply_channel, cancelled.clone());
+                                None
+                            }
+                            _ => Some(job_command.reply_channel)
+                        };
+
+                    // Register the run before any action can start so progress is never reported for an unknown run.
+                    aggregator.send_jobs(status, cancelled);
+
+                    for previous in cancel_previous {
+                        logger.log_warning("orch".to_string(), format!("Cancelling run {} of `{}` for run {}.", previous, jc.name, id)).ok();
+                        aggregator.cancel(previous).ok();
+                        pools.remove(previous);
+                    }
+
+                    // The actions run one after another, each is queued once every instance of the one before it has finished.
+                    // The first waits for the job's concurrency limit and locks.
+                    let request =
+                        match (&jc.concurrency, jc.locks.is_empty()) {
+                            (None, true) => None,
+                            (limit, _) => Some(RunRequest { id, job: jc.name.clone(), limit: limit.as_ref().map(|l| l.limit), locks: jc.locks.clone() })
+                        };
+                    pool.start(request, first);
+
+                    if let Some(reply) = reply {
+                        reply.send(Ok(id)).ok();
+                    }
                 }
             }
         }
+
+        logger.log_info("orch".to_string(), "Stopping, waiting for workers.".to_string()).ok();
+        pools.join();
     }
 }
 
-fn create_job_handler(action: &ActionType) -> Job {
+/// Expand the job's actions into the instances a run with these parameters executes, in order.
+/// Matrix values are rendered with the parameters and split on commas.
+/// A matrix dimension without any values would leave its action with nothing to run, so is an error.
+pub fn expand_actions<'a>(job: &'a JobConfiguration, parameters: &HashMap<String, String>) -> Result<Vec<ActionInstance<'a>>, Vec<String>> {
+    let mut instances = vec![];
+    let mut errors = vec![];
+    for (index, action) in job.actions.iter().enumerate() {
+        let mut combinations: Vec<HashMap<String, String>> = vec![HashMap::new()];
+        for dimension in action.matrix.iter() {
+            let values: Vec<String> =
+                dimension.values
+                    .iter()
+                    .flat_map(|v| render(v, parameters).split(',').map(|i| i.trim().to_string()).collect::<Vec<String>>())
+                    .filter(|v| !v.is_empty())
+                    .collect();
+            if values.is_empty() {
+                errors.push(format!("Matrix `{}` of action `{}` has no values.", dimension.name, action.name));
+            }
+            combinations =
+                combinations
+                    .iter()
+                    .flat_map(|c| values.iter().map(move |v| {
+                        let mut combination = c.clone();
+                        combination.insert(dimension.name.clone(), v.clone());
+                        combination
+                    }))
+                    .collect();
+        }
+        instances.extend(combinations.into_iter().map(|matrix| ActionInstance { index, action, matrix }));
+    }
+    match errors.is_empty() {
+        true => Ok(instances),
+        false => Err(errors)
+    }
+}
+
+/// Create the job for an action instance. Placeholders in its command are replaced when each attempt runs.
+/// Lines the action writes are passed to the aggregator for the run's log.
+fn create_job_handler(run_id: Uuid, job_name: &str, instance: &ActionInstance, priority: i64, cancelled: Arc<AtomicBool>, run: Arc<Mutex<RunProgress>>, aggregator: &Aggregator) -> Job {
     let id= Uuid::new_v4();
-    
+    let action = instance.action;
+
+    let log_aggregator = aggregator.clone();
+    let sink: OutputSink = Arc::new(move |stream, line: &str| log_aggregator.log_output(run_id, id, stream, line.to_string()));
     let job_handler =
-        match action {
+        match &action.action_type {
             ActionType::Command(ac) => {
-                let name = &ac.command_name.clone();
-                let args = &ac.args.clone();
-                execute_command(name.clone(), args.clone())   
+                execute_command(ac.clone(), action.outputs.clone(), action.timeout, sink)
             }
             ActionType::Test(tc) => {
-                test_job(tc.wait_time.unsigned_abs())   
+                test_job(tc.wait_time.unsigned_abs(), action.outputs.clone(), action.timeout, sink)
             }
         };
-    Job { id, handler: job_handler }
+    Job {
+        id,
+        run_id,
+        job_name: job_name.to_string(),
+        action_name: action.name.clone(),
+        priority,
+        retry: action.retry.clone(),
+        cancelled,
+        handler: job_handler,
+        when: action.when.clone(),
+        matrix: instance.matrix.clone(),
+        run,
+    }
 }
 
-fn execute_command(name: String, args: Vec<String>) -> JobHandler {
-    Box::new(move |id: Uuid|{
-        match  run_command_static(name.to_string(), args) {
+impl Job {
+    fn progress(&self) -> MutexGuard<'_, RunProgress> {
+        self.run.lock().unwrap_or_else(|p| p.into_inner())
+    }
+}
+
+impl RunProgress {
+    /// Record a finished instance of an action. An action with several instances takes the worst outcome.
+    fn record(&mut self, action: &str, state: &ActionState, outputs: &HashMap<String, String>) {
+        self.values.extend(outputs.iter().map(|(k, v)| (output_key(action, k), v.clone())));
+        self.failed |= *state == ActionState::Failed;
+
+        let key = state_key(action);
+        let worse = self.values.get(&key).map(|s| outcome_rank(s) < outcome_rank(state.as_str())).unwrap_or(true);
+        if worse {
+            self.values.insert(key, state.as_str().to_string());
+        }
+    }
+
+    /// Take the next action's instances to queue.
+    fn next_stage(&mut self) -> Vec<Job> {
+        let mut jobs = self.stages.pop_front().unwrap_or_default();
+        jobs.iter_mut().for_each(|j| j.priority = self.priority);
+        self.running = jobs.len();
+        jobs
+    }
+}
+
+fn outcome_rank(state: &str) -> u8 {
+    match state {
+        "failed" => 3,
+        "completed" => 2,
+        "skipped" => 1,
+        _ => 0
+    }
+}
+
+impl ActionState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ActionState::Queued => "queued",
+            ActionState::Running => "running",
+            ActionState::Completed => "completed",
+            ActionState::Skipped => "skipped",
+            ActionState::Failed => "failed",
+            ActionState::Interrupted => "interrupted",
+            ActionState::Retrying => "retrying",
+            ActionState::ConditionUnmet => "condition_unmet",
+        }
+    }
+}
+
+fn execute_command(template: CommandActionType, outputs: Vec<ActionOutput>, timeout: Option<Duration>, sink: OutputSink) -> JobHandler {
+    Box::new(move |_id: Uuid, values: &HashMap<String, String>|{
+        let command = CommandActionType {
+            command_name: render(&template.command_name, values),
+            args: template.args.iter().map(|a| render(a, values)).collect(),
+            cwd: template.cwd.as_ref().map(|c| render(c, values)),
+            env: template.env.iter().map(|(k, v)| (k.clone(), render(v, values))).collect(),
+        };
+        match run_command_with_timeout(&command.command_name, &command.args, command.cwd.as_deref(), &command.env, timeout, Some(&sink)) {
             Ok(output) => {
+                let exit_code = output.status.code();
+                let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+                let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
                 match format_output(output) {
-                    Ok(r) => {
-                        println!("******************************** Job {} result: {}", id, r);
-                        r
-                    }
+                    Ok(_) => collect_outputs(&outputs, &stdout, command.cwd.as_deref(), values),
                     Err(e) => {
-                        println!("******************************** Error: {}", e);
-                        e.to_string()
+                        let message =
+                            match (exit_code, stderr.is_empty()) {
+                                (Some(code), true) => format!("Process exited with code {}.", code),
+                                (_, true) => e.to_string(),
+                                (_, false) => stderr
+                            };
+                        Err(ActionFailure { message, exit_code, timed_out: false })
                     }
                 }
             }
-            Err(e) => {
-                println!("********************************************* {}", e);
-                "error".to_string()
+            Err(CommandError::TimedOut) => {
+                Err(ActionFailure { message: "Timed out.".to_string(), exit_code: None, timed_out: true })
+            }
+            Err(CommandError::Failed(e)) => {
+                sink(OutputStream::Stderr, e);
+                Err(ActionFailure { message: e.to_string(), exit_code: None, timed_out: false })
             }
         }
     })
 }
 
-fn test_job(wait_time: u64) -> JobHandler {
-    let handler= (move |id: Uuid|{
-        println!("*** TEST JOB - Job {} received. Simulating work...", id);
+fn test_job(wait_time: u64, outputs: Vec<ActionOutput>, timeout: Option<Duration>, sink: OutputSink) -> JobHandler {
+    let handler= (move |id: Uuid, values: &HashMap<String, String>|{
+        sink(OutputStream::Stdout, &format!("Test job {} received. Simulating work...", id));
         let wait_time = time::Duration::from_millis(wait_time);
+        if let Some(t) = timeout.filter(|t| *t < wait_time) {
+            thread::sleep(t);
+            return Err(ActionFailure { message: "Timed out.".to_string(), exit_code: None, timed_out: true });
+        }
         thread::sleep(wait_time);
-        println!("*** TEST JOB - Job {} completed.", id);
-        format!("Job reference: {}", id)
+        let stdout = format!("Job reference: {}", id);
+        sink(OutputStream::Stdout, &stdout);
+        collect_outputs(&outputs, &stdout, None, values)
     });
     Box::new(handler)
 }
 
+/// Read an action's outputs from its standard output or from files. Each output must be found.
+fn collect_outputs(outputs: &[ActionOutput], stdout: &str, cwd: Option<&str>, values: &HashMap<String, String>) -> Result<HashMap<String, String>, ActionFailure> {
+    let mut collected = HashMap::new();
+    for output in outputs {
+        let value =
+            match &output.source {
+                OutputSource::Stdout => Some(stdout.trim().to_string()),
+                OutputSource::KeyValue => {
+                    stdout
+                        .lines()
+                        .rev()
+                        .find_map(|l| l.strip_prefix(output.name.as_str()).and_then(|v| v.strip_prefix('=')))
+                        .map(|v| v.trim_end().to_string())
+                }
+                OutputSource::File(path) => {
+                    let path = render(path, values);
+                    let path = cwd.map(|dir| Path::new(dir).join(&path)).unwrap_or_else(|| Path::new(&path).to_path_buf());
+                    fs::read_to_string(path).ok().map(|v| v.trim().to_string())
+                }
+            };
+        let message =
+            match value {
+                None => format!("Output `{}` was not found.", output.name),
+                Some(v) if v.len() > MAX_OUTPUT_LENGTH => format!("Output `{}` is longer than {} bytes.", output.name, MAX_OUTPUT_LENGTH),
+                Some(v) => {
+                    collected.insert(output.name.clone(), v);
+                    continue;
+                }
+            };
+        return Err(ActionFailure { message, exit_code: None, timed_out: false });
+    }
+    Ok(collected)
+}
+
 
 impl Aggregator {
-    pub fn start(logger: Logger) -> Aggregator {
+    /// Start the aggregator thread, it stops once every `Aggregator` handle has been dropped.
+    pub fn start(logger: Logger, job_log: Option<JobLog>, triggers: Triggers) -> (Aggregator, JoinHandle<()>) {
         let (sender, receiver) = mpsc::channel();
-        thread::spawn(||{
-            aggregating_handler(receiver, logger);
+        let handle = thread::spawn(||{
+            aggregating_handler(receiver, job_log, triggers, logger);
         });
         
-        Aggregator { sender }
+        (Aggregator { sender }, handle)
     }
     
-    pub fn send_jobs(&self, id: Uuid) {
-        self.sender.send(AggregatorMessage::NewJobSet(id));
+    pub fn send_jobs(&self, status: JobRunStatus, cancelled: Arc<AtomicBool>) {
+        self.sender.send(AggregatorMessage::NewJobSet(status, cancelled)).ok();
     }
-    
-    pub fn get_progress(&self) {
-        self.sender.send(AggregatorMessage::ProgressReport());
+
+    pub fn start_job(&self, run_id: Uuid, id: Uuid) {
+        self.sender.send(AggregatorMessage::StartedJob(run_id, id)).ok();
     }
-    
-    pub fn complete_job(&self, id: Uuid) {
-        self.sender.send(AggregatorMessage::CompletedJob(id));
+
+    pub fn get_progress(&self) -> Vec<JobRunStatus> {
+        let (sender, reply) = mpsc::channel();
+        match self.sender.send(AggregatorMessage::ProgressReport(sender)) {
+            Ok(_) => reply.recv().unwrap_or_default(),
+            Err(_) => vec![]
+        }
+    }
+
+    pub fn get_run(&self, run_id: Uuid) -> Option<JobRunStatus> {
+        self.get_progress().into_iter().find(|r| r.id == run_id)
+    }
+
+    pub fn complete_job(&self, run_id: Uuid, id: Uuid, outputs: HashMap<String, String>) {
+        self.sender.send(AggregatorMessage::CompletedJob(run_id, id, outputs)).ok();
+    }
+
+    pub fn skip_job(&self, run_id: Uuid, id: Uuid) {
+        self.sender.send(AggregatorMessage::SkippedJob(run_id, id)).ok();
+    }
+
+    pub fn condition_unmet(&self, run_id: Uuid, id: Uuid) {
+        self.sender.send(AggregatorMessage::ConditionUnmet(run_id, id)).ok();
+    }
+
+    pub fn fail_job(&self, run_id: Uuid, id: Uuid, error: String) {
+        self.sender.send(AggregatorMessage::FailedJob(run_id, id, error)).ok();
+    }
+
+    pub fn record_attempt(&self, run_id: Uuid, id: Uuid, attempt: AttemptStatus) {
+        self.sender.send(AggregatorMessage::AttemptFinished(run_id, id, attempt)).ok();
+    }
+
+    pub fn retry_job(&self, run_id: Uuid, id: Uuid) {
+        self.sender.send(AggregatorMessage::RetryingJob(run_id, id)).ok();
+    }
+
+    pub fn log_output(&self, run_id: Uuid, id: Uuid, stream: OutputStream, line: String) {
+        self.sender.send(AggregatorMessage::Output(run_id, id, stream, line)).ok();
+    }
+
+    /// Follow a run's log, starting after the entry `after`. The subscriber is dropped once the run finishes.
+    pub fn subscribe_logs(&self, run_id: Uuid, after: Option<u64>, subscriber: LogSubscriber) -> Result<(), &'static str> {
+        let (sender, reply) = mpsc::channel();
+        match self.sender.send(AggregatorMessage::SubscribeLogs(run_id, after, subscriber, sender)) {
+            Ok(_) => reply.recv().unwrap_or(Err("Aggregator not running.")),
+            Err(_) => Err("Aggregator not running.")
+        }
+    }
+
+    /// Follow the log entries of every run from now on.
+    pub fn watch_events(&self, watcher: LogSubscriber) -> Result<(), &'static str> {
+        self.sender.send(AggregatorMessage::WatchEvents(watcher)).map_err(|_| "Aggregator not running.")
+    }
+
+    /// Cancel a job run, actions that have not started yet will be skipped.
+    pub fn cancel(&self, run_id: Uuid) -> Result<JobRunStatus, &'static str> {
+        let (sender, reply) = mpsc::channel();
+        match self.sender.send(AggregatorMessage::CancelJobSet(run_id, sender)) {
+            Ok(_) => reply.recv().unwrap_or(Err("Aggregator not running.")),
+            Err(_) => Err("Aggregator not running.")
+        }
+    }
+
+    pub fn set_priority(&self, run_id: Uuid, priority: i64) {
+        self.sender.send(AggregatorMessage::SetPriority(run_id, priority)).ok();
+    }
+
+    /// Cancel every run that has not started, and running ones too if `cancel_running` is set.
+    /// Returns the runs that had not started.
+    pub fn shutdown(&self, cancel_running: bool) -> Vec<JobRunStatus> {
+        let (sender, reply) = mpsc::channel();
+        match self.sender.send(AggregatorMessage::Shutdown(cancel_running, sender)) {
+            Ok(_) => reply.recv().unwrap_or_default(),
+            Err(_) => vec![]
+        }
     }
 }
 
-fn aggregating_handler(receiver: Receiver<AggregatorMessage>, logger: Logger) {
-    let mut jobs: HashMap<Uuid, _> = HashMap::new();
+fn aggregating_handler(receiver: Receiver<AggregatorMessage>, job_log: Option<JobLog>, mut triggers: Triggers, logger: Logger) {
+    let mut jobs: HashMap<Uuid, (JobRunStatus, Arc<AtomicBool>)> = HashMap::new();
+    let mut history: VecDeque<Uuid> = VecDeque::new();
+    let mut logs = RunLogs::new();
 
-    logger.log_info("aggregator".to_string(),"Aggregator running.".to_string());
+    logger.log_info("aggregator".to_string(),"Aggregator running.".to_string()).ok();
     loop {
-        //logger.log_debug("aggregator".to_string(),"Checking for messages.".to_string());
-        match receiver.try_recv() {
-            Ok(msg) => {
-                match msg {
-                    AggregatorMessage::NewJobSet(id) => {
-                        logger.log_info("aggregator".to_string(), format!("New job {} received.", id));
-                        jobs.insert(id, ());
-                        logger.log_info("aggregator".to_string(), format!("Outstanding jobs: {}", jobs.len()));
+        let msg =
+            match receiver.recv() {
+                Ok(msg) => msg,
+                Err(_) => {
+                    logger.log_warning("aggregator".to_string(), "All senders dropped, stopping.".to_string()).ok();
+                    return;
+                }
+            };
+
+        let mut finished_run = None;
+        match msg {
+            AggregatorMessage::NewJobSet(status, cancelled) => {
+                logger.log_info("aggregator".to_string(), format!("New job {} ({}) received.", status.id, status.name)).ok();
+                if let Some((parent, _)) = status.parent.and_then(|p| jobs.get_mut(&p)) {
+                    parent.children.push(status.id);
+                }
+                history.push_back(status.id);
+                logs.start(status.id, &status.name, &status.state);
+                if status.finished_at.is_some() {
+                    logs.finish(status.id);
+                }
+                jobs.insert(status.id, (status, cancelled));
+                logger.log_info("aggregator".to_string(), format!("Outstanding jobs: {}", count_outstanding(&jobs))).ok();
+            }
+            AggregatorMessage::StartedJob(run_id, id) => {
+                let finished = update_action(&mut jobs, run_id, id, ActionState::Running);
+                record_action(job_log.as_ref(), &jobs, run_id, id, finished, &logger);
+                log_action(&mut logs, &jobs, run_id, id, finished);
+            }
+            AggregatorMessage::ProgressReport(reply) => {
+                let runs = history.iter().filter_map(|id| jobs.get(id)).map(|(s, _)| s.clone()).collect();
+                reply.send(runs).ok();
+            }
+            AggregatorMessage::CompletedJob(run_id, id, outputs) => {
+                if let Some(action) = jobs.get_mut(&run_id).and_then(|(s, _)| s.actions.iter_mut().find(|a| a.id == id)) {
+                    action.outputs = outputs;
+                }
+                let finished = update_action(&mut jobs, run_id, id, ActionState::Completed);
+                if finished {
+                    logger.log_success("aggregator".to_string(), format!("Job {} complete.", run_id)).ok();
+                    finished_run = Some(run_id);
+                }
+                record_action(job_log.as_ref(), &jobs, run_id, id, finished, &logger);
+                log_action(&mut logs, &jobs, run_id, id, finished);
+                logger.log_info("aggregator".to_string(), format!("Outstanding jobs: {}", count_outstanding(&jobs))).ok();
+            }
+            AggregatorMessage::SkippedJob(run_id, id) => {
+                let finished = update_action(&mut jobs, run_id, id, ActionState::Skipped);
+                if finished {
+                    logger.log_warning("aggregator".to_string(), format!("Job {} cancelled.", run_id)).ok();
+                    finished_run = Some(run_id);
+                }
+                record_action(job_log.as_ref(), &jobs, run_id, id, finished, &logger);
+                log_action(&mut logs, &jobs, run_id, id, finished);
+            }
+            AggregatorMessage::ConditionUnmet(run_id, id) => {
+                let finished = update_action(&mut jobs, run_id, id, ActionState::ConditionUnmet);
+                record_action(job_log.as_ref(), &jobs, run_id, id, finished, &logger);
+                log_action(&mut logs, &jobs, run_id, id, finished);
+                if finished {
+                    finished_run = Some(run_id);
+                }
+            }
+            AggregatorMessage::FailedJob(run_id, id, error) => {
+                if let Some(action) = jobs.get_mut(&run_id).and_then(|(s, _)| s.actions.iter_mut().find(|a| a.id == id)) {
+                    action.error = Some(error);
+                }
+                let finished = update_action(&mut jobs, run_id, id, ActionState::Failed);
+                if finished {
+                    logger.log_error("aggregator".to_string(), format!("Job {} failed.", run_id)).ok();
+                    finished_run = Some(run_id);
+                }
+                record_action(job_log.as_ref(), &jobs, run_id, id, finished, &logger);
+                log_action(&mut logs, &jobs, run_id, id, finished);
+            }
+            AggregatorMessage::AttemptFinished(run_id, id, attempt) => {
+                if let Some(action) = jobs.get_mut(&run_id).and_then(|(s, _)| s.actions.iter_mut().find(|a| a.id == id)) {
+                    action.error = attempt.error.clone();
+                    action.attempts.push(attempt);
+                }
+            }
+            AggregatorMessage::RetryingJob(run_id, id) => {
+                update_action(&mut jobs, run_id, id, ActionState::Retrying);
+                log_action(&mut logs, &jobs, run_id, id, false);
+            }
+            AggregatorMessage::Output(run_id, id, stream, line) => {
+                if let Some(action) = jobs.get(&run_id).and_then(|(s, _)| s.actions.iter().find(|a| a.id == id)) {
+                    logs.append(run_id, RunEvent::Output { action: action.name.clone(), action_id: id, stream, line });
+                }
+            }
+            AggregatorMessage::SubscribeLogs(run_id, after, subscriber, reply) => {
+                match jobs.contains_key(&run_id) {
+                    true => {
+                        logs.subscribe(run_id, after, subscriber);
+                        reply.send(Ok(())).ok()
                     }
-                    AggregatorMessage::ProgressReport() => {}
-                    AggregatorMessage::CompletedJob(id) => {
-                        logger.log_success("aggregator".to_string(), format!("Job {} complete.", id));
-                        jobs.remove(&id);
-                        logger.log_info("aggregator".to_string(), format!("Outstanding jobs: {}", jobs.len()));
+                    false => reply.send(Err("Job run not found.")).ok()
+                };
+            }
+            AggregatorMessage::WatchEvents(watcher) => logs.watch(watcher),
+            AggregatorMessage::CancelJobSet(run_id, reply) => {
+                let result =
+                    match jobs.get_mut(&run_id) {
+                        None => Err("Job run not found."),
+                        Some((status, _)) if status.finished_at.is_some() => Err("Job run already finished."),
+                        Some((status, cancelled)) => {
+                            cancelled.store(true, Ordering::SeqCst);
+                            logger.log_warning("aggregator".to_string(), format!("Job {} cancellation requested.", run_id)).ok();
+                            Ok(status.clone())
+                        }
+                    };
+                reply.send(result).ok();
+            }
+            AggregatorMessage::SetPriority(run_id, priority) => {
+                if let Some((status, _)) = jobs.get_mut(&run_id) {
+                    status.priority = priority;
+                }
+            }
+            AggregatorMessage::Shutdown(cancel_running, reply) => {
+                let mut queued = vec![];
+                for id in history.iter() {
+                    if let Some((status, cancelled)) = jobs.get(id) {
+                        match (&status.state, status.finished_at.is_some()) {
+                            (_, true) => {}
+                            (JobRunState::Queued, _) => {
+                                cancelled.store(true, Ordering::SeqCst);
+                                queued.push(status.clone());
+                            }
+                            _ if cancel_running => cancelled.store(true, Ordering::SeqCst),
+                            _ => {}
+                        }
                     }
                 }
+                reply.send(queued).ok();
+                // Runs finishing from now on trigger nothing, as nothing more will be started.
+                triggers.sender = None;
             }
-            Err(e) => {
-                //logger.log_debug("aggregator".to_string(), format!("No messages received. Reason: {}", e));
-                let wait_time = time::Duration::from_millis(1000);
-                thread::sleep(wait_time);
+        }
+
+        if let Some(run_id) = finished_run {
+            if let Some((status, _)) = jobs.get(&run_id) {
+                triggers.fire(status, &logger);
             }
         }
+        trim_history(&mut jobs, &mut history, &mut logs);
     }
 }
 
-impl WorkerPool {
-    pub fn new(size: usize, aggregator: Aggregator, logger: Logger) -> WorkerPool {
-        assert!(size > 0);
-        let (sender, receiver) = mpsc::channel();
+impl Triggers {
+    pub fn new(jobs: Arc<JobsConfiguration>, sender: Sender<JobCommand>) -> Triggers {
+        Triggers { jobs, sender: Some(sender) }
+    }
 
-        let receiver = Arc::new(Mutex::new(receiver));
+    /// Queue the jobs a finished run triggers, with parameters rendered from the run's parameters and outputs.
+    fn fire(&self, status: &JobRunStatus, logger: &Logger) {
+        let triggers: Vec<&JobTrigger> =
+            match self.jobs.get_job(&status.name) {
+                None => return,
+                Some(j) => j.triggers.iter().filter(|t| t.on.matches(&status.state)).collect()
+            };
+        if triggers.is_empty() {
+            return;
+        }
+        let sender =
+            match &self.sender {
+                Some(s) => s,
+                None => {
+                    logger.log_warning("aggregator".to_string(), format!("Stopping, jobs triggered by run {} not queued.", status.id)).ok();
+                    return;
+                }
+            };
 
-        let mut workers = Vec::with_capacity(size);
+        let mut values = status.parameters.clone();
+        for action in status.actions.iter() {
+            values.extend(action.outputs.iter().map(|(k, v)| (output_key(&action.name, k), v.clone())));
+        }
+
+        for trigger in triggers {
+            let declared =
+                match self.jobs.get_job(&trigger.job) {
+                    None => continue,
+                    Some(j) => &j.parameters
+                };
+            let supplied = trigger.parameters.iter().map(|(k, v)| (k.clone(), render(v, &values))).collect();
+            let parameters =
+                match resolve_parameters(declared, &supplied, true) {
+                    Ok(p) => p,
+                    Err(errors) => {
+                        logger.log_error("aggregator".to_string(), format!("Job `{}` triggered by run {} not queued: {}", trigger.job, status.id, errors.join(" "))).ok();
+                        continue;
+                    }
+                };
 
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver), aggregator.clone(), logger.clone()));
+            // Nothing waits for the reply, the child run records its parent when it is registered.
+            let (reply_channel, _) = mpsc::channel();
+            let command = JobCommand {
+                name: trigger.job.clone(),
+                principal: Some(format!("job:{}", status.name)),
+                parameters,
+                priority: None,
+                resume: None,
+                parent: Some(status.id),
+                triggers: vec![],
+                reply_channel,
+            };
+            match sender.send(command) {
+                Ok(_) => logger.log_info("aggregator".to_string(), format!("Run {} triggered job `{}`.", status.id, trigger.job)).ok(),
+                Err(_) => logger.log_error("aggregator".to_string(), format!("Job `{}` triggered by run {} not queued, orchestrator not running.", trigger.job, status.id)).ok()
+            };
         }
+    }
+}
+
+/// Update an action's state and recalculate the run state.
+/// Returns true if the update finished the run.
+fn update_action(jobs: &mut HashMap<Uuid, (JobRunStatus, Arc<AtomicBool>)>, run_id: Uuid, id: Uuid, state: ActionState) -> bool {
+    match jobs.get_mut(&run_id) {
+        None => false,
+        Some((status, _)) => {
+            if let Some(action) = status.actions.iter_mut().find(|a| a.id == id) {
+                action.state = state;
+            }
+
+            let pending = status.actions.iter().any(|a| matches!(a.state, ActionState::Queued | ActionState::Running | ActionState::Retrying));
+            let started = status.actions.iter().any(|a| a.state != ActionState::Queued);
+            let skipped = status.actions.iter().any(|a| a.state == ActionState::Skipped);
+            let failed = status.actions.iter().any(|a| a.state == ActionState::Failed);
+
+            status.state =
+                match (pending, started, skipped, failed) {
+                    (false, _, _, true) => JobRunState::Failed,
+                    (false, _, true, false) => JobRunState::Cancelled,
+                    (false, _, false, false) => JobRunState::Completed,
+                    (true, true, _, _) => JobRunState::Running,
+                    (true, false, _, _) => JobRunState::Queued,
+                };
 
-        WorkerPool { workers, sender }
+            match (pending, status.finished_at.is_none()) {
+                (false, true) => {
+                    status.finished_at = Some(Utc::now());
+                    true
+                }
+                _ => false
+            }
+        }
     }
+}
 
-    pub fn execute<F>(&self, id: Uuid, f: F)
-        where
-            F: FnOnce(Uuid) -> String + Send + 'static,
-    {
-        let job = 
-            Job {
-                id,
-                handler: Box::new(f)
-            };
-            
-        match self.sender.send(job) {
-            Ok(_) => {}
-            Err(e) => {
-                println!("Error sending job: {}", e)
+/// Write an action's new state to the job log, and the run's end if `finished` is set.
+fn record_action(job_log: Option<&JobLog>, jobs: &HashMap<Uuid, (JobRunStatus, Arc<AtomicBool>)>, run_id: Uuid, id: Uuid, finished: bool, logger: &Logger) {
+    let (job_log, status) =
+        match (job_log, jobs.get(&run_id)) {
+            (Some(l), Some((s, _))) => (l, s),
+            _ => return
+        };
+
+    let result =
+        match status.actions.iter().position(|a| a.id == id) {
+            None => Ok(()),
+            Some(i) => match &status.actions[i].state {
+                ActionState::Running => job_log.action_started(run_id, i),
+                ActionState::Queued | ActionState::Retrying => Ok(()),
+                state => job_log.action_finished(run_id, i, state.clone(), &status.actions[i].outputs)
             }
+        }
+        .and_then(|_| match finished {
+            true => job_log.run_finished(run_id, status.state.clone()),
+            false => Ok(())
+        });
+
+    if let Err(e) = result {
+        logger.log_error("aggregator".to_string(), format!("Could not write to job log: {}", e)).ok();
+    }
+}
+
+/// Add an action's new state, and the run's if it has changed, to the run's log.
+fn log_action(logs: &mut RunLogs, jobs: &HashMap<Uuid, (JobRunStatus, Arc<AtomicBool>)>, run_id: Uuid, id: Uuid, finished: bool) {
+    let status =
+        match jobs.get(&run_id) {
+            None => return,
+            Some((s, _)) => s
+        };
+    let action =
+        match status.actions.iter().find(|a| a.id == id) {
+            None => return,
+            Some(a) => a
+        };
+
+    match &action.state {
+        ActionState::Queued => {}
+        ActionState::Running => {
+            // The run starting is logged before its first action.
+            logs.status(run_id, &status.state);
+            logs.append(run_id, RunEvent::ActionStarted { action: action.name.clone(), action_id: id, attempt: action.attempts.len() + 1 });
+        }
+        state => logs.append(run_id, RunEvent::ActionFinished { action: action.name.clone(), action_id: id, state: state.clone(), error: action.error.clone() })
+    }
+    logs.status(run_id, &status.state);
+    if finished {
+        logs.finish(run_id);
+    }
+}
+
+fn count_outstanding(jobs: &HashMap<Uuid, (JobRunStatus, Arc<AtomicBool>)>) -> usize {
+    jobs.values().filter(|(s, _)| s.finished_at.is_none()).count()
+}
+
+/// Drop the oldest finished runs once the history is over `MAX_HISTORY`.
+fn trim_history(jobs: &mut HashMap<Uuid, (JobRunStatus, Arc<AtomicBool>)>, history: &mut VecDeque<Uuid>, logs: &mut RunLogs) {
+    let mut finished = jobs.values().filter(|(s, _)| s.finished_at.is_some()).count();
+    let mut i = 0;
+    while finished > MAX_HISTORY && i < history.len() {
+        let done = jobs.get(&history[i]).map(|(s, _)| s.finished_at.is_some()).unwrap_or(true);
+        if done {
+            if let Some(id) = history.remove(i) {
+                jobs.remove(&id);
+                logs.remove(id);
+            }
+            finished -= 1;
+        } else {
+            i += 1;
+        }
+    }
+}
+
+impl WorkerPools {
+    /// Create a pool for each queue and start its minimum number of workers.
+    pub fn new(queues: &[QueueConfiguration], aggregator: Aggregator, logger: Logger) -> WorkerPools {
+        let gate = RunGate::new();
+        let pools =
+            queues
+                .iter()
+                .map(|q| (q.name.clone(), WorkerPool::new(q.clone(), gate.clone(), aggregator.clone(), logger.clone())))
+                .collect();
+
+        WorkerPools { pools: Arc::new(pools), gate }
+    }
+
+    /// The lock holders and waiters, and the runs of jobs with a concurrency limit.
+    pub fn locks(&self) -> (Vec<LockStatus>, Vec<JobConcurrency>) {
+        (self.gate.locks(), self.gate.jobs())
+    }
+
+    fn gate(&self) -> &RunGate<PendingRun> {
+        &self.gate
+    }
+
+    pub fn get(&self, name: &str) -> Option<&WorkerPool> {
+        self.pools.get(name)
+    }
+
+    /// Statistics for every queue, ordered by name.
+    pub fn stats(&self) -> Vec<QueueStats> {
+        let mut stats: Vec<QueueStats> = self.pools.values().map(|p| p.stats()).collect();
+        stats.sort_by(|a, b| a.name.cmp(&b.name));
+        stats
+    }
+
+    /// Change the priority of a run's queued actions, in whichever queue they are in.
+    /// Returns the number of actions that were still queued.
+    pub fn set_priority(&self, run_id: Uuid, priority: i64) -> usize {
+        self.pools.values().map(|p| p.set_priority(run_id, priority)).sum()
+    }
+
+    /// Take a run's queued actions out of their queue, or the run out of the gate if it is waiting to start,
+    /// reporting them as skipped. Returns the number of actions removed.
+    pub fn remove(&self, run_id: Uuid) -> usize {
+        let withdrawn =
+            match self.gate.withdraw(run_id) {
+                None => 0,
+                Some(pending) => {
+                    for job in pending.jobs.iter() {
+                        pending.pool.aggregator.skip_job(job.run_id, job.id);
+                        finish_job(&pending.pool, job, ActionState::Skipped, &HashMap::new(), true);
+                    }
+                    pending.jobs.len()
+                }
+            };
+        withdrawn + self.pools.values().map(|p| p.remove(run_id)).sum::<usize>()
+    }
+
+    /// Stop accepting jobs and wait for every queue's workers to finish the jobs already queued.
+    pub fn join(&self) {
+        self.pools.values().for_each(|p| p.join());
+    }
+}
+
+impl fmt::Debug for WorkerPools {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WorkerPools").field("queues", &self.pools.keys().collect::<Vec<&String>>()).finish()
+    }
+}
+
+impl WorkerPool {
+    fn new(config: QueueConfiguration, gate: RunGate<PendingRun>, aggregator: Aggregator, logger: Logger) -> WorkerPool {
+        let state = PoolState {
+            queue: VecDeque::new(),
+            threads: 0,
+            idle: 0,
+            active: 0,
+            started: 0,
+            completed: 0,
+            total_wait: Duration::ZERO,
+            next_sequence: 0,
+            next_id: 0,
+            handles: vec![],
+            closed: false,
+        };
+        let pool = WorkerPool { shared: Arc::new(PoolShared { config, gate, state: Mutex::new(state), available: Condvar::new(), aggregator, logger }) };
+
+        let mut state = pool.shared.lock();
+        for _ in 0..pool.shared.config.min_workers {
+            spawn_worker(&pool.shared, &mut state);
+        }
+        drop(state);
+
+        pool
+    }
+
+    /// Queue the first jobs of a run, once its job's concurrency limit and locks allow if it has a request.
+    pub fn start(&self, request: Option<RunRequest>, jobs: Vec<Job>) {
+        let jobs =
+            match request {
+                None => jobs,
+                Some(r) => {
+                    let id = r.id;
+                    match self.shared.gate.admit(r, PendingRun { pool: self.shared.clone(), jobs }) {
+                        Admission::Start(pending) => pending.jobs,
+                        Admission::Wait => {
+                            self.shared.logger.log_info(format!("queue_{}", self.shared.config.name), format!("Run {} waiting for its job's concurrency limit or locks.", id)).ok();
+                            return;
+                        }
+                    }
+                }
+            };
+        jobs.into_iter().for_each(|j| push_job(&self.shared, j, false));
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let state = self.shared.lock();
+        let average_wait =
+            match state.started {
+                0 => Duration::ZERO,
+                n => state.total_wait / n as u32
+            };
+
+        QueueStats {
+            name: self.shared.config.name.clone(),
+            min_workers: self.shared.config.min_workers,
+            max_workers: self.shared.config.max_workers,
+            workers: state.threads,
+            depth: state.queue.len(),
+            active: state.active,
+            completed: state.completed,
+            average_wait_ms: average_wait.as_millis() as u64,
+        }
+    }
+
+    /// The jobs waiting in the queue, in the order they will start.
+    pub fn queued(&self) -> Vec<QueuedJobInfo> {
+        self.shared.lock()
+            .queue
+            .iter()
+            .enumerate()
+            .map(|(i, q)| QueuedJobInfo {
+                position: i + 1,
+                run_id: q.job.run_id,
+                id: q.job.id,
+                job: q.job.job_name.clone(),
+                action: q.job.action_name.clone(),
+                matrix: q.job.matrix.clone(),
+                priority: q.job.priority,
+                waiting_ms: q.queued_at.elapsed().as_millis() as u64,
+            })
+            .collect()
+    }
+
+    fn set_priority(&self, run_id: Uuid, priority: i64) -> usize {
+        let mut state = self.shared.lock();
+        let mut changed = 0;
+        for queued in state.queue.iter_mut().filter(|q| q.job.run_id == run_id) {
+            queued.job.priority = priority;
+            queued.job.progress().priority = priority;
+            changed += 1;
+        }
+        if changed > 0 {
+            state.queue.make_contiguous().sort_by_key(|q| (Reverse(q.job.priority), q.sequence));
+        }
+        changed
+    }
+
+    fn remove(&self, run_id: Uuid) -> usize {
+        let removed: VecDeque<QueuedJob> = {
+            let mut state = self.shared.lock();
+            let (removed, kept) = state.queue.drain(..).partition(|q| q.job.run_id == run_id);
+            state.queue = kept;
+            removed
         };
+        for queued in removed.iter() {
+            self.shared.aggregator.skip_job(queued.job.run_id, queued.job.id);
+            finish_job(&self.shared, &queued.job, ActionState::Skipped, &HashMap::new(), true);
+        }
+        removed.len()
+    }
+
+    /// Stop accepting jobs and wait for the workers to finish the ones already queued.
+    fn join(&self) {
+        self.shared.lock().closed = true;
+        self.shared.available.notify_all();
+        // A worker that panics while being joined starts a replacement, so keep going until none are left.
+        loop {
+            let handle = self.shared.lock().handles.pop();
+            match handle {
+                Some(h) => { h.join().ok(); }
+                None => break
+            }
+        }
     }
 }
 
-struct Worker {
-    id: usize,
-    thread: thread::JoinHandle<()>,
+impl PoolShared {
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(|p| p.into_inner())
+    }
 }
 
-impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>, aggregator: Aggregator, logger: Logger) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let job = receiver.lock().unwrap().recv().unwrap();
-            //let job_id = Uuid::new_v4();
-            logger.log_info(format!("worker_{}", id), format!("Job received. id: {}", job.id));
-            //println!("Worker {} got a job. Executing", id);
-            //println!("Handled by {}", id);
-            let r = (job.handler)(job.id);
-            logger.log_success(format!("worker_{}", id), format!("Job {} complete.", job.id));
-            aggregator.complete_job(job.id);
+/// Queue a job, starting another worker if there are more jobs waiting than idle workers.
+/// Once the pool is closed only the next jobs of runs already started are accepted, so they finish.
+fn push_job(shared: &Arc<PoolShared>, job: Job, chained: bool) {
+    let mut state = shared.lock();
+    if state.closed && !chained {
+        drop(state);
+        shared.logger.log_error(format!("queue_{}", shared.config.name), format!("Queue stopped, job {} skipped.", job.id)).ok();
+        shared.aggregator.skip_job(job.run_id, job.id);
+        finish_job(shared, &job, ActionState::Skipped, &HashMap::new(), true);
+        return;
+    }
+
+    let sequence = state.next_sequence;
+    state.next_sequence += 1;
+    // Behind every job of the same or a higher priority.
+    let position = state.queue.iter().position(|q| q.job.priority < job.priority).unwrap_or(state.queue.len());
+    state.queue.insert(position, QueuedJob { job, queued_at: Instant::now(), sequence });
+    if state.queue.len() > state.idle && state.threads < shared.config.max_workers {
+        spawn_worker(shared, &mut state);
+    }
+    shared.available.notify_one();
+}
+
+/// Record a job's outcome in its run. Once every instance of its action has finished the run's next action
+/// is queued, or if `abandon` is set the rest of the run is reported as skipped.
+/// Once the run has finished its slot and locks are released, starting any runs waiting for them.
+fn finish_job(shared: &Arc<PoolShared>, job: &Job, state: ActionState, outputs: &HashMap<String, String>, abandon: bool) {
+    let (next, finished) = {
+        let mut run = job.progress();
+        run.record(&job.action_name, &state, outputs);
+        run.running = run.running.saturating_sub(1);
+        let next =
+            match (abandon, run.running) {
+                (true, _) => {
+                    run.stages.drain(..).flatten().for_each(|j| shared.aggregator.skip_job(j.run_id, j.id));
+                    vec![]
+                }
+                (false, 0) => run.next_stage(),
+                (false, _) => vec![]
+            };
+        (next, run.running == 0 && run.stages.is_empty())
+    };
+    next.into_iter().for_each(|j| push_job(shared, j, true));
+
+    if finished {
+        for pending in shared.gate.release(job.run_id) {
+            pending.jobs.into_iter().for_each(|j| push_job(&pending.pool, j, false));
+        }
+    }
+}
+
+/// Start a worker thread for the pool. The caller holds the pool's lock.
+fn spawn_worker(shared: &Arc<PoolShared>, state: &mut PoolState) {
+    let id = state.next_id;
+    state.next_id += 1;
+    state.threads += 1;
+
+    let pool = shared.clone();
+    let handle = thread::spawn(move || run_worker(pool, id));
+
+    state.handles.retain(|h| !h.is_finished());
+    state.handles.push(handle);
+}
+
+fn run_worker(shared: Arc<PoolShared>, id: usize) {
+    let name = format!("{}_worker_{}", shared.config.name, id);
+    let sentinel = Sentinel { name: name.clone(), shared: shared.clone() };
+    let mut state = shared.lock();
+
+    loop {
+        if let Some(queued) = state.queue.pop_front() {
+            state.active += 1;
+            state.started += 1;
+            state.total_wait += queued.queued_at.elapsed();
+            drop(state);
+
+            run_job(&shared, &name, queued.job);
+
+            state = shared.lock();
+            state.active -= 1;
+            state.completed += 1;
+            continue;
+        }
+
+        if state.closed {
+            break;
+        }
+
+        state.idle += 1;
+        let (s, wait) = shared.available.wait_timeout(state, shared.config.idle_timeout).unwrap_or_else(|p| p.into_inner());
+        state = s;
+        state.idle -= 1;
+
+        if wait.timed_out() && state.queue.is_empty() && state.threads > shared.config.min_workers {
+            shared.logger.log_info(name.clone(), "Idle, stopping.".to_string()).ok();
+            break;
+        }
+    }
+
+    state.threads -= 1;
+    drop(state);
+    drop(sentinel);
+}
+
+/// Run a job, retrying failed attempts according to its retry policy. The action is only reported
+/// as failed once no more attempts will be made. A job whose condition does not hold is not run,
+/// nor is one without a condition once an earlier action of the run has failed.
+fn run_job(shared: &Arc<PoolShared>, name: &str, job: Job) {
+    let (aggregator, logger) = (&shared.aggregator, &shared.logger);
+    if job.cancelled.load(Ordering::SeqCst) {
+        logger.log_warning(name.to_string(), format!("Job {} skipped, run {} was cancelled.", job.id, job.run_id)).ok();
+        aggregator.skip_job(job.run_id, job.id);
+        finish_job(shared, &job, ActionState::Skipped, &HashMap::new(), true);
+        return;
+    }
+
+    let (mut values, failed) = {
+        let run = job.progress();
+        (run.values.clone(), run.failed)
+    };
+    values.extend(job.matrix.iter().map(|(k, v)| (matrix_key(k), v.clone())));
+    match (&job.when, failed) {
+        (Some(condition), _) if !condition.evaluate(&values) => {
+            logger.log_info(name.to_string(), format!("Job {} not run, its condition does not hold.", job.id)).ok();
+            aggregator.condition_unmet(job.run_id, job.id);
+            finish_job(shared, &job, ActionState::ConditionUnmet, &HashMap::new(), false);
+            return;
+        }
+        (None, true) => {
+            logger.log_warning(name.to_string(), format!("Job {} skipped, an earlier action of run {} failed.", job.id, job.run_id)).ok();
+            aggregator.skip_job(job.run_id, job.id);
+            finish_job(shared, &job, ActionState::Skipped, &HashMap::new(), false);
+            return;
+        }
+        _ => {}
+    }
+    logger.log_info(name.to_string(), format!("Job received. id: {}", job.id)).ok();
+
+    let mut attempt = 1;
+    loop {
+        aggregator.start_job(job.run_id, job.id);
+        let started_at = Utc::now();
+        let result =
+            match panic::catch_unwind(AssertUnwindSafe(|| (job.handler)(job.id, &values))) {
+                Ok(r) => r,
+                Err(e) => {
+                    let message = panic_message(&e);
+                    logger.log_error(name.to_string(), format!("Job {} (run {}) panicked: {}", job.id, job.run_id, message)).ok();
+                    Err(ActionFailure { message: format!("Action panicked: {}", message), exit_code: None, timed_out: false })
+                }
+            };
+
+        let failure = result.as_ref().err();
+        aggregator.record_attempt(job.run_id, job.id, AttemptStatus {
+            attempt,
+            started_at,
+            finished_at: Utc::now(),
+            succeeded: failure.is_none(),
+            exit_code: failure.and_then(|f| f.exit_code),
+            timed_out: failure.map(|f| f.timed_out).unwrap_or(false),
+            error: failure.map(|f| f.message.clone()),
         });
 
-        Worker { id, thread }
+        let failure =
+            match result {
+                Ok(outputs) => {
+                    logger.log_success(name.to_string(), format!("Job {} complete.", job.id)).ok();
+                    aggregator.complete_job(job.run_id, job.id, outputs.clone());
+                    finish_job(shared, &job, ActionState::Completed, &outputs, false);
+                    return;
+                }
+                Err(f) => f
+            };
+
+        let delay =
+            match job.retry.as_ref().filter(|p| attempt < p.max_attempts && p.should_retry(&failure)) {
+                Some(p) => p.delay_before(attempt + 1),
+                None => {
+                    logger.log_error(name.to_string(), format!("Job {} failed after {} attempt(s): {}", job.id, attempt, failure.message)).ok();
+                    aggregator.fail_job(job.run_id, job.id, failure.message);
+                    finish_job(shared, &job, ActionState::Failed, &HashMap::new(), false);
+                    return;
+                }
+            };
+
+        logger.log_warning(name.to_string(), format!("Job {} attempt {} failed: {}. Retrying in {}ms.", job.id, attempt, failure.message, delay.as_millis())).ok();
+        aggregator.retry_job(job.run_id, job.id);
+        if !wait_unless_cancelled(delay, &job.cancelled) {
+            logger.log_warning(name.to_string(), format!("Job {} not retried, run {} was cancelled.", job.id, job.run_id)).ok();
+            aggregator.skip_job(job.run_id, job.id);
+            finish_job(shared, &job, ActionState::Skipped, &HashMap::new(), true);
+            return;
+        }
+        attempt += 1;
+    }
+}
+
+/// Sleep for `delay`, waking early if the run is cancelled. Returns false if it was.
+fn wait_unless_cancelled(delay: Duration, cancelled: &AtomicBool) -> bool {
+    let deadline = Instant::now() + delay;
+    while Instant::now() < deadline {
+        if cancelled.load(Ordering::SeqCst) {
+            return false;
+        }
+        thread::sleep((deadline - Instant::now()).min(Duration::from_millis(100)));
+    }
+    !cancelled.load(Ordering::SeqCst)
+}
+
+/// Lives on a worker thread and starts a replacement if the thread dies by panicking,
+/// so the pool never silently shrinks.
+struct Sentinel {
+    name: String,
+    shared: Arc<PoolShared>,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.shared.logger.log_warning(self.name.clone(), "Worker thread died, starting a replacement.".to_string()).ok();
+            let mut state = self.shared.lock();
+            state.threads -= 1;
+            spawn_worker(&self.shared, &mut state);
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use crate::configuration::{JobParameter, ParameterType};
+
+/// Check supplied values against a job's declared parameters, filling in defaults.
+/// Values are normalised, so `yes` for a bool becomes `true`. Parameters the job does not declare
+/// are rejected when `strict` is set and passed through unchanged otherwise.
+/// On failure every problem found is returned.
+pub fn resolve_parameters(declared: &[JobParameter], supplied: &HashMap<String, String>, strict: bool) -> Result<HashMap<String, String>, Vec<String>> {
+    let mut values = HashMap::new();
+    let mut errors = vec![];
+
+    for parameter in declared {
+        match (supplied.get(&parameter.name), &parameter.default) {
+            (Some(v), _) => match check_value(parameter, v) {
+                Ok(v) => { values.insert(parameter.name.clone(), v); }
+                Err(e) => errors.push(e)
+            },
+            (None, Some(d)) => { values.insert(parameter.name.clone(), d.clone()); }
+            (None, None) => errors.push(format!("Missing required parameter `{}`.", parameter.name))
+        }
+    }
+
+    for (name, value) in supplied.iter().filter(|(n, _)| !declared.iter().any(|p| &p.name == *n)) {
+        match strict {
+            true => errors.push(format!("Unknown parameter `{}`.", name)),
+            false => { values.insert(name.clone(), value.clone()); }
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(values),
+        false => {
+            errors.sort();
+            Err(errors)
+        }
+    }
+}
+
+/// Check a value against a parameter's type and pattern, returning the normalised value.
+pub fn check_value(parameter: &JobParameter, value: &str) -> Result<String, String> {
+    let value =
+        match &parameter.parameter_type {
+            ParameterType::String => value.to_string(),
+            ParameterType::Int => {
+                match value.trim().parse::<i64>() {
+                    Ok(i) => i.to_string(),
+                    Err(_) => return Err(format!("Parameter `{}` must be an integer.", parameter.name))
+                }
+            }
+            ParameterType::Bool => {
+                match value.trim().to_lowercase().as_str() {
+                    "true" | "1" | "yes" => "true".to_string(),
+                    "false" | "0" | "no" => "false".to_string(),
+                    _ => return Err(format!("Parameter `{}` must be true or false.", parameter.name))
+                }
+            }
+            ParameterType::Enum(options) => {
+                match options.iter().any(|o| o == value) {
+                    true => value.to_string(),
+                    false => return Err(format!("Parameter `{}` must be one of: {}.", parameter.name, options.join(", ")))
+                }
+            }
+        };
+
+    match &parameter.pattern {
+        Some(p) if !p.is_match(&value) => Err(format!("Parameter `{}` does not match the pattern `{}`.", parameter.name, p.as_str())),
+        _ => Ok(value)
+    }
+}
+
+/// Replace each `{{name}}` in the template with the parameter's value.
+/// Placeholders for parameters without a value are left as they are.
+pub fn render(template: &str, values: &HashMap<String, String>) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let end =
+            match rest[start..].find("}}") {
+                None => break,
+                Some(e) => start + e
+            };
+        output.push_str(&rest[..start]);
+        match values.get(rest[start + 2..end].trim()) {
+            Some(v) => output.push_str(v),
+            None => output.push_str(&rest[start..end + 2])
+        }
+        rest = &rest[end + 2..];
+    }
+
+    output.push_str(rest);
+    output
+}
+
+/// The placeholder name an action's output is referenced by.
+pub fn output_key(action: &str, name: &str) -> String {
+    format!("outputs.{}.{}", action, name)
+}
+
+/// The name an action's state is looked up by in conditions.
+pub fn state_key(action: &str) -> String {
+    format!("actions.{}.state", action)
+}
+
+/// The placeholder name of a matrix value for the current instance of an action.
+pub fn matrix_key(dimension: &str) -> String {
+    format!("matrix.{}", dimension)
+}
+
+/// The parameter names used by placeholders in the template.
+pub fn placeholders(template: &str) -> Vec<String> {
+    let mut names = vec![];
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        match rest[start..].find("}}") {
+            None => break,
+            Some(e) => {
+                names.push(rest[start + 2..start + e].trim().to_string());
+                rest = &rest[start + e + 2..];
+            }
+        }
+    }
+
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+    use super::*;
+
+    fn parameter(name: &str, parameter_type: ParameterType, default: Option<&str>, pattern: Option<&str>) -> JobParameter {
+        JobParameter {
+            name: name.to_string(),
+            parameter_type,
+            default: default.map(String::from),
+            // Patterns must match the whole value, as the configuration loads them.
+            pattern: pattern.map(|p| Regex::new(&format!("^(?:{})$", p)).unwrap()),
+        }
+    }
+
+    fn supplied(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn declared() -> Vec<JobParameter> {
+        vec![
+            parameter("target", ParameterType::Enum(vec!["staging".to_string(), "production".to_string()]), Some("staging"), None),
+            parameter("ref", ParameterType::String, Some("main"), Some("[A-Za-z0-9._/-]+")),
+            parameter("count", ParameterType::Int, None, None),
+            parameter("verbose", ParameterType::Bool, Some("false"), None),
+        ]
+    }
+
+    #[test]
+    fn fills_defaults_and_normalises() {
+        let values = resolve_parameters(&declared(), &supplied(&[("count", " 007 "), ("verbose", "Yes")]), true).unwrap();
+        assert_eq!(values, supplied(&[("target", "staging"), ("ref", "main"), ("count", "7"), ("verbose", "true")]));
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let errors = resolve_parameters(&declared(), &supplied(&[("target", "dev"), ("verbose", "maybe")]), true).unwrap_err();
+        assert_eq!(errors, vec![
+            "Missing required parameter `count`.".to_string(),
+            "Parameter `target` must be one of: staging, production.".to_string(),
+            "Parameter `verbose` must be true or false.".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn strict_rejects_undeclared_parameters() {
+        let errors = resolve_parameters(&declared(), &supplied(&[("count", "1"), ("commit", "abc")]), true).unwrap_err();
+        assert_eq!(errors, vec!["Unknown parameter `commit`.".to_string()]);
+    }
+
+    #[test]
+    fn non_strict_passes_undeclared_parameters_unchecked() {
+        let values = resolve_parameters(&declared(), &supplied(&[("count", "1"), ("commit", "abc; rm -rf /")]), false).unwrap();
+        assert_eq!(values.get("commit").map(|v| v.as_str()), Some("abc; rm -rf /"));
+        // Declared ones are still checked.
+        assert!(resolve_parameters(&declared(), &supplied(&[("count", "one")]), false).is_err());
+    }
+
+    #[test]
+    fn patterns_must_match_the_whole_value() {
+        let declared = declared();
+        let reference = &declared[1];
+        assert_eq!(check_value(reference, "release/1.2"), Ok("release/1.2".to_string()));
+        for value in ["main; rm -rf /", "$(id)", "main\nmain", " main", ""] {
+            assert!(check_value(reference, value).is_err(), "`{}` should not match", value);
+        }
+
+        // Alternatives are anchored as a group, not only the first and last.
+        let alternatives = parameter("env", ParameterType::String, None, Some("dev|prod"));
+        assert!(check_value(&alternatives, "prod").is_ok());
+        assert!(check_value(&alternatives, "dev; echo").is_err());
+        assert!(check_value(&alternatives, "x prod").is_err());
+    }
+
+    #[test]
+    fn patterns_apply_to_normalised_values() {
+        let port = parameter("port", ParameterType::Int, None, Some("[0-9]{2,4}"));
+        assert_eq!(check_value(&port, " 80 "), Ok("80".to_string()));
+        assert!(check_value(&port, "8").is_err());
+    }
+
+    #[test]
+    fn renders_known_placeholders() {
+        let values = supplied(&[("ref", "main"), ("outputs.build.version", "1.2")]);
+        assert_eq!(render("deploy {{ref}} at {{ outputs.build.version }}", &values), "deploy main at 1.2");
+        assert_eq!(render("{{ref}}{{ref}}", &values), "mainmain");
+    }
+
+    #[test]
+    fn leaves_unknown_and_unclosed_placeholders() {
+        let values = supplied(&[("ref", "main")]);
+        assert_eq!(render("{{unknown}} {{ref}}", &values), "{{unknown}} main");
+        assert_eq!(render("{{ref}} {{ref", &values), "main {{ref");
+        assert_eq!(render("{ref} }}", &values), "{ref} }}");
+        // Values are not rendered again.
+        assert_eq!(render("{{a}}", &supplied(&[("a", "{{ref}}"), ("ref", "main")])), "{{ref}}");
+    }
+
+    #[test]
+    fn lists_placeholders() {
+        assert_eq!(placeholders("{{ a }} and {{matrix.os}} {{unclosed"), vec!["a".to_string(), "matrix.os".to_string()]);
+        assert!(placeholders("none here").is_empty());
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use serde_json::{json, Value};
+use crate::auth::Principal;
+
+/// Once this many clients are tracked, full buckets are dropped to bound memory,
+/// and if that is not enough the least recently used.
+const MAX_TRACKED_BUCKETS: usize = 10000;
+
+/// How many of the least recently used buckets are dropped at once, so the scan is not repeated on every request.
+const EVICTION_BATCH: usize = MAX_TRACKED_BUCKETS / 10;
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum RateLimitKey {
+    ClientIp,
+    /// Falls back to the client address for unauthenticated requests.
+    Principal,
+    ClientIpAndPrincipal,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket per client. Each request takes one token, buckets hold at most
+/// `capacity` tokens and refill at `refill_per_second`.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct RateLimiter {
+    name: String,
+    key: RateLimitKey,
+    capacity: f64,
+    refill_per_second: f64,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+    allowed: Arc<AtomicU64>,
+    limited: Arc<AtomicU64>,
+}
+
+impl RateLimitKey {
+    pub fn from_str(data: &str) -> Result<RateLimitKey, &'static str> {
+        match data.to_lowercase().as_str() {
+            "ip" => Ok(RateLimitKey::ClientIp),
+            "principal" => Ok(RateLimitKey::Principal),
+            "ip_and_principal" => Ok(RateLimitKey::ClientIpAndPrincipal),
+            _ => Err("Unknown rate limit key")
+        }
+    }
+
+    fn create(&self, client_ip: &IpAddr, principal: Option<&Principal>) -> String {
+        match (self, principal) {
+            (RateLimitKey::ClientIp, _) => client_ip.to_string(),
+            (RateLimitKey::Principal, Some(p)) => format!("principal:{}", p.name),
+            (RateLimitKey::Principal, None) => client_ip.to_string(),
+            (RateLimitKey::ClientIpAndPrincipal, Some(p)) => format!("{}/{}", client_ip, p.name),
+            (RateLimitKey::ClientIpAndPrincipal, None) => client_ip.to_string(),
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(name: String, key: RateLimitKey, capacity: f64, refill_per_second: f64) -> Result<RateLimiter, &'static str> {
+        if capacity < 1.0 || refill_per_second <= 0.0 {
+            return Err("Rate limit capacity must be at least 1 and refill rate above 0");
+        }
+
+        Ok(RateLimiter {
+            name,
+            key,
+            capacity,
+            refill_per_second,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            allowed: Arc::new(AtomicU64::new(0)),
+            limited: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Take a token for the client, or return how long until one is available.
+    pub fn check(&self, client_ip: &IpAddr, principal: Option<&Principal>) -> Result<(), Duration> {
+        let key = self.key.create(client_ip, principal);
+        let now = Instant::now();
+
+        let mut buckets =
+            match self.buckets.lock() {
+                Ok(b) => b,
+                // A poisoned limiter should not take the server down with it.
+                Err(poisoned) => poisoned.into_inner()
+            };
+
+        if buckets.len() >= MAX_TRACKED_BUCKETS {
+            let (capacity, rate) = (self.capacity, self.refill_per_second);
+            buckets.retain(|_, b| b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < capacity);
+        }
+        // Rotating keys, such as spoofed forwarded addresses, keep their buckets partly drained.
+        if buckets.len() >= MAX_TRACKED_BUCKETS {
+            let mut used: Vec<(Instant, String)> = buckets.iter().map(|(k, b)| (b.updated, k.clone())).collect();
+            used.select_nth_unstable(EVICTION_BATCH);
+            for (_, key) in used.into_iter().take(EVICTION_BATCH) {
+                buckets.remove(&key);
+            }
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket { tokens: self.capacity, updated: now });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        bucket.updated = now;
+
+        match bucket.tokens >= 1.0 {
+            true => {
+                bucket.tokens -= 1.0;
+                self.allowed.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            false => {
+                self.limited.fetch_add(1, Ordering::Relaxed);
+                Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.refill_per_second))
+            }
+        }
+    }
+
+    /// True if the limiter keys on the principal, so can only be checked once the request is authenticated.
+    pub fn is_keyed_by_principal(&self) -> bool {
+        !matches!(self.key, RateLimitKey::ClientIp)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn snapshot(&self) -> Value {
+        let tracked = self.buckets.lock().map(|b| b.len()).unwrap_or(0);
+        json!({
+            "name": self.name,
+            "capacity": self.capacity,
+            "refill_per_second": self.refill_per_second,
+            "tracked_clients": tracked,
+            "allowed": self.allowed.load(Ordering::Relaxed),
+            "limited": self.limited.load(Ordering::Relaxed)
+        })
+    }
+}
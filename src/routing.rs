@@ -1,11 +1,24 @@
-﻿use std::{fs, thread, time};
-use std::process::{Command, Output};
-use std::sync::mpsc::{channel, Sender};
+﻿use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::process::Output;
 use regex::Regex;
-use uuid::Uuid;
+use serde_json::json;
+use crate::access::AccessList;
+use crate::api::{handle_jobs_api, parameters_from_body, queue_job};
+use crate::auth::{AuthFailure, Authenticator, Authorizer, JobPermission, Principal, RouteAuth};
 use crate::commands::run_command;
-use crate::http::{HttpRequest, HttpResponse};
-use crate::orchestration::{Job, JobCommand, JobHandler};
+use crate::compression::CompressionPolicy;
+use crate::configuration::DebounceSettings;
+use crate::cors::CorsPolicy;
+use crate::events::handle_events;
+use crate::http::{HttpRequest, HttpResponse, HttpVerb};
+use crate::logging::logging::Logger;
+use crate::metrics::Metrics;
+use crate::orchestration::JobContext;
+use crate::rate_limiting::RateLimiter;
+use crate::server::ConnectionContext;
+use crate::webhooks::{WebhookOutcome, WebhookRoute};
 
 #[derive(Clone)]
 #[derive(Debug)]
@@ -26,7 +39,10 @@ pub struct CommandRoute {
 #[derive(Debug)]
 pub struct JobRoute {
     name: String,
-    args: Vec<String>,
+    /// Fixed parameter values, these can not be overridden by the request.
+    parameters: HashMap<String, String>,
+    /// Replaces the job's own debounce settings for triggers from this route.
+    debounce: Option<DebounceSettings>,
     //response_handler: fn(Output) -> HttpResponse
 }
 
@@ -35,7 +51,11 @@ pub struct JobRoute {
 pub enum RouteHandler {
     Static(StaticRoute),
     Command(CommandRoute),
-    Job(JobRoute)
+    Job(JobRoute),
+    Jobs,
+    Events,
+    Webhook(WebhookRoute),
+    Metrics,
 }
 
 impl RouteHandler {
@@ -47,16 +67,51 @@ impl RouteHandler {
         RouteHandler::Command(CommandRoute { command_name, args, response_handler })
     }
     
-    pub fn create_job(name: String, args: Vec<String>) -> RouteHandler {
-        RouteHandler::Job(JobRoute { name, args })
+    pub fn create_job(name: String, parameters: HashMap<String, String>, debounce: Option<DebounceSettings>) -> RouteHandler {
+        RouteHandler::Job(JobRoute { name, parameters, debounce })
+    }
+    
+    pub fn create_jobs() -> RouteHandler {
+        RouteHandler::Jobs
+    }
+    
+    pub fn create_events() -> RouteHandler {
+        RouteHandler::Events
+    }
+    
+    pub fn create_webhook(webhook: WebhookRoute) -> RouteHandler {
+        RouteHandler::Webhook(webhook)
+    }
+    
+    pub fn create_metrics() -> RouteHandler {
+        RouteHandler::Metrics
     }
     
-    pub fn handle(&self, job_handler: Sender<JobCommand>, request: HttpRequest) -> Result<HttpResponse, &'static str> {
+    pub fn handle(&self, route_map: &RouteMap, request: HttpRequest, principal: Option<&Principal>) -> Result<HttpResponse, &'static str> {
         match self {
             RouteHandler::Static(sr) => {
-                let body = fs::read(&sr.content_path).unwrap();
-                let response = HttpResponse::create(200, String::from(&sr.content_type), Some(body));
-                Ok(response)
+                let accept_encoding = request.get_header("Accept-Encoding");
+                let compression = route_map.defaults.compression.as_ref();
+
+                if let Some((file, encoding)) = compression.and_then(|c| c.open_static(&sr.content_path, &sr.content_type, accept_encoding)) {
+                    let length = file.metadata().map_err(|_| "Could not read static file")?.len();
+                    let mut response = HttpResponse::create_file(200, String::from(&sr.content_type), file, length);
+                    response.add_header("Content-Encoding".to_string(), encoding.name().to_string());
+                    return Ok(response);
+                }
+
+                let mut file = File::open(&sr.content_path).map_err(|_| "Could not open static file")?;
+                let length = file.metadata().map_err(|_| "Could not read static file")?.len();
+
+                // Only responses compressed on the fly need to be read into memory.
+                match compression.map(|c| c.would_compress(&sr.content_type, accept_encoding, length)).unwrap_or(false) {
+                    true => {
+                        let mut body = Vec::with_capacity(length as usize);
+                        file.read_to_end(&mut body).map_err(|_| "Could not read static file")?;
+                        Ok(HttpResponse::create(200, String::from(&sr.content_type), Some(body)))
+                    }
+                    false => Ok(HttpResponse::create_file(200, String::from(&sr.content_type), file, length))
+                }
             }
             RouteHandler::Command(cr) => {
                 let output= run_command(&cr.command_name, &cr.args)?;
@@ -65,11 +120,71 @@ impl RouteHandler {
                 Ok(response)
             }
             RouteHandler::Job(jr) => {
-                let (sender, reply_channel) = channel();
-                job_handler.send(JobCommand { name: jr.name.clone(), reply_channel: sender });
-                let body = "Job queued".bytes().collect();
-                let response = HttpResponse::create(201, "text/plain".to_string(), Some(body));
-                Ok(response)
+                if let Err(failure) = route_map.authorizer.authorize_job(principal, &jr.name, JobPermission::Trigger) {
+                    return Ok(failure.to_response());
+                }
+                // The trigger can override the job's default priority with `?priority=`.
+                let priority =
+                    match request.get_query_param("priority").map(|p| p.parse::<i64>()) {
+                        None => None,
+                        Some(Ok(p)) => Some(p),
+                        Some(Err(_)) => {
+                            let body = json!({ "message": "Invalid priority" });
+                            return Ok(HttpResponse::create(400, "application/json".to_string(), Some(body.to_string().into_bytes())));
+                        }
+                    };
+                // Parameters come from the request body, the route's own values take precedence.
+                let mut parameters =
+                    match parameters_from_body(request.body()) {
+                        Ok(p) => p,
+                        Err(e) => {
+                            let body = json!({ "message": e });
+                            return Ok(HttpResponse::create(400, "application/json".to_string(), Some(body.to_string().into_bytes())));
+                        }
+                    };
+                parameters.extend(jr.parameters.clone());
+                queue_job(&route_map.jobs, &jr.name, principal.map(|p| p.name.clone()), parameters, priority, true, jr.debounce.as_ref())
+            }
+            RouteHandler::Jobs => {
+                handle_jobs_api(&route_map.jobs, &route_map.authorizer, &request, principal)
+            }
+            RouteHandler::Events => {
+                handle_events(&route_map.jobs, &route_map.authorizer, &request, principal)
+            }
+            RouteHandler::Webhook(wr) => {
+                match wr.verify(&request) {
+                    WebhookOutcome::Trigger(mut parameters) => {
+                        // The webhook secret only proves who sent the request, the route's principal needs the job's trigger
+                        // permission like any other. Without one only jobs open to anonymous triggers can be run.
+                        let principal = principal.cloned().or_else(|| wr.principal.as_ref().map(|name| Principal { name: name.clone(), credential_set: "webhook".to_string() }));
+                        if let Err(failure) = route_map.authorizer.authorize_job(principal.as_ref(), &wr.job, JobPermission::Trigger) {
+                            return Ok(failure.to_response());
+                        }
+                        let triggered_by = principal.map(|p| p.name).unwrap_or_else(|| format!("webhook:{}", wr.provider_name()));
+                        // `event` and `branch` are only passed to jobs that declare them, mapped parameters must be declared
+                        // to load, so every value from the payload is checked like any other trigger's.
+                        if let Some(job) = route_map.jobs.config.get_job(&wr.job) {
+                            parameters.retain(|name, _| job.parameters.iter().any(|p| &p.name == name));
+                        }
+                        queue_job(&route_map.jobs, &wr.job, Some(triggered_by), parameters, None, true, wr.debounce.as_ref())
+                    }
+                    WebhookOutcome::Ignored(reason) => {
+                        let body = json!({ "message": "Ignored", "reason": reason });
+                        Ok(HttpResponse::create(202, "application/json".to_string(), Some(body.to_string().into_bytes())))
+                    }
+                    WebhookOutcome::Unauthorized(reason) => {
+                        let body = json!({ "message": "Unauthorized", "reason": reason });
+                        Ok(HttpResponse::create(401, "application/json".to_string(), Some(body.to_string().into_bytes())))
+                    }
+                    WebhookOutcome::Invalid(reason) => {
+                        let body = json!({ "message": "Bad request", "reason": reason });
+                        Ok(HttpResponse::create(400, "application/json".to_string(), Some(body.to_string().into_bytes())))
+                    }
+                }
+            }
+            RouteHandler::Metrics => {
+                let body = route_map.metrics.snapshot().to_string().into_bytes();
+                Ok(HttpResponse::create(200, "application/json".to_string(), Some(body)))
             }
         }
     }
@@ -80,14 +195,18 @@ impl RouteHandler {
 pub struct Route {
     route_regex: Regex,
     handler: RouteHandler,
+    auth: Option<RouteAuth>,
+    access: Option<AccessList>,
+    rate_limiter: Option<RateLimiter>,
+    cors: Option<CorsPolicy>,
 }
 
 impl Route {
-    pub fn new(route_regex: Regex, handler: RouteHandler) -> Route {
-        Route { route_regex, handler }
+    pub fn new(route_regex: Regex, handler: RouteHandler, auth: Option<RouteAuth>, access: Option<AccessList>, rate_limiter: Option<RateLimiter>, cors: Option<CorsPolicy>) -> Route {
+        Route { route_regex, handler, auth, access, rate_limiter, cors }
     }
 
-    pub fn is_match(&self, route: &String) -> bool {
+    pub fn is_match(&self, route: &str) -> bool {
         self.route_regex.is_match(route)
     }
 }
@@ -96,27 +215,177 @@ impl Route {
 #[derive(Debug)]
 pub struct RouteMap {
     pub routes: Vec<Route>,
-    job_handler: Sender<JobCommand>
+    jobs: JobContext,
+    authenticator: Authenticator,
+    authorizer: Authorizer,
+    defaults: RouteDefaults,
+    metrics: Metrics,
+}
+
+/// Server wide settings applied to every route.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct RouteDefaults {
+    pub rate_limiter: Option<RateLimiter>,
+    /// The cors policy for routes without their own.
+    pub cors: Option<CorsPolicy>,
+    pub compression: Option<CompressionPolicy>,
 }
 
 impl RouteMap {
     
-    pub fn new(job_handler: Sender<JobCommand>, routes: Vec<Route>) -> RouteMap {
-        RouteMap { routes, job_handler }
+    pub fn new(jobs: JobContext, routes: Vec<Route>, authenticator: Authenticator, authorizer: Authorizer, defaults: RouteDefaults, metrics: Metrics) -> RouteMap {
+        RouteMap { routes, jobs, authenticator, authorizer, defaults, metrics }
     }
     
-    pub fn handle(&self, request: HttpRequest) -> Result<HttpResponse, &'static str> {
-        let route =
-            self.routes
-                .iter()
-                .fold(None, |acc, r| match r.is_match(&request.header.route) {
-                    true => Some(r),
-                    false => acc
-                });
+    
+    /// Find the route for a request path, the last matching route wins.
+    fn find_route(&self, route: &str) -> Option<&Route> {
+        self.routes
+            .iter()
+            .fold(None, |acc, r| match r.is_match(route) {
+                true => Some(r),
+                false => acc
+            })
+    }
+
+    /// True if handling the request may block, because the route reads files, runs commands,
+    /// verifies password hashes or waits for the orchestrator or aggregator to reply.
+    /// These requests are handled off the server's event loop.
+    pub fn is_blocking(&self, request: &HttpRequest) -> bool {
+        // Preflight requests are answered without invoking the route handler.
+        if matches!(request.header.verb, HttpVerb::OPTIONS) {
+            return false;
+        }
+        match self.find_route(request.path()) {
+            None => false,
+            Some(r) => {
+                !matches!(r.handler, RouteHandler::Metrics)
+                    || r.auth.as_ref().map(|a| self.authenticator.is_expensive(a)).unwrap_or(false)
+            }
+        }
+    }
+
+    pub fn handle(&self, request: HttpRequest, logger: &Logger, context: &ConnectionContext) -> Result<HttpResponse, &'static str> {
+        let route = self.find_route(request.path());
+
+        self.metrics.increment("requests");
 
         match route {
-            None => Err("Route not found"),
-            Some(r) => r.handler.handle(self.job_handler.clone(), request)
+            None => {
+                for keyed_by_principal in [false, true] {
+                    if let Some(response) = self.check_rate_limits(None, None, keyed_by_principal, logger, context) {
+                        return Ok(response);
+                    }
+                }
+                Err("Route not found")
+            }
+            Some(r) => {
+                if let Some(access) = &r.access {
+                    if !access.is_allowed(&context.client_ip) {
+                        logger.log_warning(format!("{} access", context.slug), format!("Request from {} denied by route access list for {}", context.from, request.header.route)).ok();
+                        let body = " { \"message\": \"Forbidden\"}".as_bytes().to_vec();
+                        return Ok(HttpResponse::create(403, String::from("application/json"), Some(body)));
+                    }
+                }
+
+                let cors = r.cors.as_ref().or(self.defaults.cors.as_ref());
+
+                // OPTIONS requests are answered here and never reach a route handler.
+                if matches!(request.header.verb, HttpVerb::OPTIONS) {
+                    return match (cors, CorsPolicy::is_preflight(&request)) {
+                        (Some(policy), true) => {
+                            logger.log_info(format!("{} cors", context.slug), format!("Preflight request for {}", request.header.route)).ok();
+                            Ok(policy.preflight(&request))
+                        }
+                        _ => Ok(HttpResponse::create(204, String::from("text/plain"), None))
+                    };
+                }
+
+                let origin = request.get_header("Origin").cloned();
+                let accept_encoding = request.get_header("Accept-Encoding").cloned();
+                let mut response =
+                    match self.handle_route(r, request, logger, context) {
+                        Ok(response) => response,
+                        // Answered here rather than by the server so the cors headers are added, otherwise browsers only see a network error.
+                        Err(e) => {
+                            logger.log_error(format!("{} connection-handler", context.slug), format!("Error in response handler: {}", e)).ok();
+                            let body = " { \"message\": \"Server error\"}".as_bytes().to_vec();
+                            HttpResponse::create(500, String::from("application/json"), Some(body))
+                        }
+                    };
+                if let Some(policy) = cors {
+                    policy.apply(origin.as_ref(), &mut response);
+                }
+                if let Some(compression) = &self.defaults.compression {
+                    compression.apply(accept_encoding.as_ref(), &mut response);
+                }
+                Ok(response)
+            }
+        }
+    }
+
+    /// Rate limit and authenticate the request, then invoke the route handler.
+    /// Limiters keyed by client address are checked first, so failed attempts to authenticate use up tokens
+    /// and password guessing is limited. Those keyed by principal are checked once it is known, or by
+    /// client address if authentication fails.
+    fn handle_route(&self, r: &Route, request: HttpRequest, logger: &Logger, context: &ConnectionContext) -> Result<HttpResponse, &'static str> {
+        if let Some(response) = self.check_rate_limits(r.rate_limiter.as_ref(), None, false, logger, context) {
+            return Ok(response);
+        }
+
+        let mut principal = None;
+        if let Some(auth) = &r.auth {
+            match self.authenticator.authenticate(auth, &request) {
+                Ok(p) => {
+                    logger.log_info(format!("{} auth", context.slug), format!("Authenticated as `{}` ({}).", p.name, p.credential_set)).ok();
+                    if let Some(response) = self.check_rate_limits(r.rate_limiter.as_ref(), Some(&p), true, logger, context) {
+                        return Ok(response);
+                    }
+                    if let Err(failure) = self.authorizer.authorize_route(&p, request.path()) {
+                        logger.log_warning(format!("{} auth", context.slug), format!("Principal `{}` has no role granting {}", p.name, request.header.route)).ok();
+                        return Ok(failure.to_response());
+                    }
+                    principal = Some(p);
+                }
+                Err(failure) => {
+                    match &failure {
+                        AuthFailure::Unauthorized(_) => {
+                            logger.log_warning(format!("{} auth", context.slug), format!("Unauthorized request for {} from {}", request.header.route, context.from)).ok();
+                        }
+                        AuthFailure::Forbidden(principal) => {
+                            logger.log_warning(format!("{} auth", context.slug), format!("Principal `{}` is not allowed on {}", principal.name, request.header.route)).ok();
+                        }
+                        AuthFailure::MissingPermission { permission, resource } => {
+                            logger.log_warning(format!("{} auth", context.slug), format!("Missing permission `{}` on {}", permission, resource)).ok();
+                        }
+                    }
+                    if let Some(response) = self.check_rate_limits(r.rate_limiter.as_ref(), None, true, logger, context) {
+                        return Ok(response);
+                    }
+                    return Ok(failure.to_response());
+                }
+            }
+        } else if let Some(response) = self.check_rate_limits(r.rate_limiter.as_ref(), None, true, logger, context) {
+            return Ok(response);
+        }
+
+        r.handler.handle(self, request, principal.as_ref())
+    }
+
+    /// Check the global and then the route rate limiter, those keyed by principal or else those keyed by
+    /// client address only, returning a 429 response if either is exhausted.
+    fn check_rate_limits(&self, route_limiter: Option<&RateLimiter>, principal: Option<&Principal>, keyed_by_principal: bool, logger: &Logger, context: &ConnectionContext) -> Option<HttpResponse> {
+        for limiter in self.defaults.rate_limiter.iter().chain(route_limiter).filter(|l| l.is_keyed_by_principal() == keyed_by_principal) {
+            if let Err(retry_after) = limiter.check(&context.client_ip, principal) {
+                self.metrics.increment("rate_limited");
+                logger.log_warning(format!("{} rate-limit", context.slug), format!("Request from {} limited by `{}`", context.from, limiter.name())).ok();
+                let body = " { \"message\": \"Too many requests\"}".as_bytes().to_vec();
+                let mut response = HttpResponse::create(429, String::from("application/json"), Some(body));
+                response.add_header("Retry-After".to_string(), format!("{}", retry_after.as_secs_f64().ceil().max(1.0) as u64));
+                return Some(response);
+            }
         }
+        None
     }
 }
\ No newline at end of file
@@ -0,0 +1,213 @@
+use std::collections::{HashMap, VecDeque};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+use crate::commands::OutputStream;
+use crate::orchestration::{ActionState, JobRunState};
+
+/// The most entries kept for a run, the oldest are dropped first.
+const MAX_ENTRIES: usize = 10000;
+
+/// The most bytes of entries kept for a run, the oldest are dropped first.
+/// Kept below `MAX_PENDING_STREAM_BYTES` so a subscriber can always be sent a run's whole log.
+const MAX_RUN_BYTES: usize = 1024 * 1024;
+
+/// The most bytes of entries kept across every run, the oldest entries of any run are dropped first.
+const MAX_TOTAL_BYTES: usize = 64 * 1024 * 1024;
+
+/// Longer lines of output are cut short.
+const MAX_LINE_LENGTH: usize = 4096;
+
+/// Roughly what an entry takes besides its job name and line of output.
+const ENTRY_OVERHEAD: usize = 256;
+
+/// Something that happened during a run.
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RunEvent {
+    /// The run moved to a new state.
+    Status { state: JobRunState },
+    ActionStarted { action: String, action_id: Uuid, attempt: usize },
+    /// An attempt at an action ended, with the action's state afterwards.
+    ActionFinished {
+        action: String,
+        action_id: Uuid,
+        state: ActionState,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// A line an action's command wrote.
+    Output { action: String, action_id: Uuid, stream: OutputStream, line: String },
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+#[derive(Serialize)]
+pub struct LogEntry {
+    /// Counts up from 1 within each run.
+    pub id: u64,
+    pub run_id: Uuid,
+    pub job: String,
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: RunEvent,
+}
+
+/// Receives a run's log entries, returning false once it no longer wants them.
+pub type LogSubscriber = Box<dyn FnMut(&LogEntry) -> bool + Send>;
+
+/// The log of each run the aggregator knows about, and the subscribers following them.
+pub struct RunLogs {
+    runs: HashMap<Uuid, RunLog>,
+    /// Receive the entries of every run.
+    watchers: Vec<LogSubscriber>,
+    /// The size of the entries kept across every run.
+    bytes: usize,
+}
+
+#[derive(Default)]
+struct RunLog {
+    job: String,
+    entries: VecDeque<LogEntry>,
+    /// The size of `entries`.
+    bytes: usize,
+    last_id: u64,
+    /// The run state last logged.
+    state: Option<JobRunState>,
+    finished: bool,
+    subscribers: Vec<LogSubscriber>,
+}
+
+impl LogEntry {
+    /// The entry as a Server-Sent Event, named after its type and carrying the entry as json.
+    pub fn to_sse(&self) -> String {
+        let name =
+            match &self.event {
+                RunEvent::Status { .. } => "status",
+                RunEvent::ActionStarted { .. } => "action_started",
+                RunEvent::ActionFinished { .. } => "action_finished",
+                RunEvent::Output { .. } => "output",
+            };
+        let data = serde_json::to_string(self).unwrap_or_default();
+        format!("id: {}\nevent: {}\ndata: {}\n\n", self.id, name, data)
+    }
+
+    /// Roughly the memory the entry takes.
+    fn size(&self) -> usize {
+        let line =
+            match &self.event {
+                RunEvent::Output { line, .. } => line.len(),
+                RunEvent::ActionFinished { error, .. } => error.as_ref().map(|e| e.len()).unwrap_or(0),
+                _ => 0
+            };
+        ENTRY_OVERHEAD + self.job.len() + line
+    }
+}
+
+impl RunLog {
+    /// Drop the oldest entry, returning its size.
+    fn pop_front(&mut self) -> usize {
+        let size = self.entries.pop_front().map(|e| e.size()).unwrap_or(0);
+        self.bytes -= size;
+        size
+    }
+}
+
+impl RunLogs {
+    pub fn new() -> RunLogs {
+        RunLogs { runs: HashMap::new(), watchers: Vec::new(), bytes: 0 }
+    }
+
+    /// Start the log of a new run with its state.
+    pub fn start(&mut self, run_id: Uuid, job: &str, state: &JobRunState) {
+        self.runs.entry(run_id).or_default().job = job.to_string();
+        self.status(run_id, state);
+    }
+
+    /// Add an entry to a run's log and pass it to the run's subscribers.
+    pub fn append(&mut self, run_id: Uuid, event: RunEvent) {
+        let event =
+            match event {
+                RunEvent::Output { action, action_id, stream, mut line } if line.len() > MAX_LINE_LENGTH => {
+                    let mut end = MAX_LINE_LENGTH;
+                    while !line.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    line.truncate(end);
+                    RunEvent::Output { action, action_id, stream, line }
+                }
+                e => e
+            };
+
+        let log = self.runs.entry(run_id).or_default();
+        log.last_id += 1;
+        let entry = LogEntry { id: log.last_id, run_id, job: log.job.clone(), at: Utc::now(), event };
+        log.subscribers.retain_mut(|s| s(&entry));
+        self.watchers.retain_mut(|w| w(&entry));
+        let size = entry.size();
+        log.bytes += size;
+        self.bytes += size;
+        log.entries.push_back(entry);
+        while log.entries.len() > MAX_ENTRIES || log.bytes > MAX_RUN_BYTES {
+            self.bytes -= log.pop_front();
+        }
+        self.trim();
+    }
+
+    /// Drop the oldest entries, of whichever runs they belong to, until those kept are within `MAX_TOTAL_BYTES`.
+    fn trim(&mut self) {
+        while self.bytes > MAX_TOTAL_BYTES {
+            let oldest =
+                self.runs.values_mut()
+                    .filter(|l| !l.entries.is_empty())
+                    .min_by_key(|l| l.entries.front().map(|e| e.at));
+            match oldest {
+                Some(log) => self.bytes -= log.pop_front(),
+                None => break
+            };
+        }
+    }
+
+    /// Log the run's state if it has changed since it was last logged.
+    pub fn status(&mut self, run_id: Uuid, state: &JobRunState) {
+        let log = self.runs.entry(run_id).or_default();
+        if log.state.as_ref() != Some(state) {
+            log.state = Some(state.clone());
+            self.append(run_id, RunEvent::Status { state: state.clone() });
+        }
+    }
+
+    /// Mark the run as finished, ending its subscriptions.
+    pub fn finish(&mut self, run_id: Uuid) {
+        let log = self.runs.entry(run_id).or_default();
+        log.finished = true;
+        log.subscribers.clear();
+    }
+
+    /// Pass the run's entries after `after` to the subscriber, then the new ones until the run finishes.
+    /// Entries dropped to keep within the limits are skipped.
+    pub fn subscribe(&mut self, run_id: Uuid, after: Option<u64>, mut subscriber: LogSubscriber) {
+        let log = self.runs.entry(run_id).or_default();
+        for entry in log.entries.iter().filter(|e| e.id > after.unwrap_or(0)) {
+            if !subscriber(entry) {
+                return;
+            }
+        }
+        if !log.finished {
+            log.subscribers.push(subscriber);
+        }
+    }
+
+    /// Pass every new entry, of any run, to the watcher until it no longer wants them.
+    pub fn watch(&mut self, watcher: LogSubscriber) {
+        self.watchers.push(watcher);
+    }
+
+    pub fn remove(&mut self, run_id: Uuid) {
+        if let Some(log) = self.runs.remove(&run_id) {
+            self.bytes -= log.bytes;
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use mio::net::TcpStream;
+
+/// The most read into memory at once when `sendfile` can not be used.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Send up to `count` bytes of `file` starting at `offset` to the socket, advancing `offset` by the amount sent.
+/// Uses `sendfile` where available so the data does not pass through user space, otherwise copies through a buffer.
+pub fn send_file(stream: &TcpStream, file: &File, offset: &mut u64, count: u64) -> io::Result<usize> {
+    #[cfg(target_os = "linux")]
+    {
+        match linux_sendfile(stream, file, offset, count) {
+            // Not every file system supports `sendfile`.
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOSYS)) => {}
+            result => return result
+        }
+    }
+
+    send_buffered(stream, file, offset, count)
+}
+
+#[cfg(target_os = "linux")]
+fn linux_sendfile(stream: &TcpStream, file: &File, offset: &mut u64, count: u64) -> io::Result<usize> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut off = *offset as libc::off_t;
+    // A single call can not send more than this.
+    let count = count.min(0x7fff_f000) as usize;
+    let sent = unsafe { libc::sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut off, count) };
+    match sent {
+        -1 => Err(io::Error::last_os_error()),
+        n => {
+            *offset += n as u64;
+            Ok(n as usize)
+        }
+    }
+}
+
+/// Read a chunk from the file and write what the socket takes. Anything not written is read again next time.
+fn send_buffered(mut stream: &TcpStream, mut file: &File, offset: &mut u64, count: u64) -> io::Result<usize> {
+    let mut buffer = vec![0; (count as usize).min(BUFFER_SIZE)];
+    file.seek(SeekFrom::Start(*offset))?;
+    let read = file.read(&mut buffer)?;
+    if read == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File shorter than expected"));
+    }
+
+    let written = stream.write(&buffer[..read])?;
+    *offset += written as u64;
+    Ok(written)
+}
@@ -1,131 +1,890 @@
-﻿use std::io::prelude::*;
-use std::{fs, thread};
-use std::net::{TcpListener, TcpStream};
-use std::process::{Command, Output};
-use std::sync::mpsc::channel;
-use regex::Regex;
-use serde::de::Unexpected::Str;
-use uuid;
+﻿use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::{ErrorKind, IoSlice};
+use std::panic::{self, AssertUnwindSafe};
+use std::net::IpAddr;
+use std::net;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::time::{Duration, Instant};
+use mio::{Events, Interest, Poll, Token, Waker};
+use mio::net::{TcpListener, TcpStream};
 use uuid::Uuid;
+use crate::access::{parse_proxy_protocol, AccessPolicy};
 use crate::configuration::Configuration;
-use crate::connection_pool::ConnectionPool;
-use crate::http::{HttpRequest, HttpRequestHeader, HttpResponse};
-use crate::http::HttpVerb::GET;
-use crate::logging::logging::{Log, Logger};
-use crate::orchestration::{Aggregator, Orchestrator};
+use crate::connection_pool::{panic_message, ConnectionLimits, ConnectionPool, Rejection};
+use crate::http::{FileBody, HttpRequest, HttpRequestHeader, HttpResponse, StreamBody};
+use crate::logging::logging::Logger;
+use crate::metrics::Metrics;
+use crate::shutdown::Shutdown;
 use crate::routing::RouteMap;
+use crate::sendfile::send_file;
+use crate::websocket::{encode_close, encode_frame, Opcode, WebSocket, CLOSE_GOING_AWAY, CLOSE_NORMAL};
 
 
+/// The largest request body that will be read, larger requests are rejected.
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// The largest request header that will be read, larger requests are rejected.
+const MAX_HEADER_SIZE: usize = 4096;
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+
+/// How often connection timeouts are checked.
+const TICK: Duration = Duration::from_millis(100);
+
+/// How long a WebSocket may go without sending before it is pinged. One that has not been heard from
+/// for three times as long is closed.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
 pub struct Server;
 
+/// Limits on how long a client may take to send a request and receive the response.
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct Timeouts {
+    /// The total time allowed to receive the request header.
+    pub header_read: Duration,
+    /// The longest wait for more body data before giving up.
+    pub body_read: Duration,
+    /// The longest the response may go without any progress writing it.
+    pub write: Duration,
+    /// Bodies larger than this must arrive at `min_body_rate` or faster.
+    pub large_body_size: usize,
+    /// In bytes per second, 0 disables the check.
+    pub min_body_rate: u64,
+    /// How long an idle connection is kept open for another request, zero disables keep-alive.
+    pub keep_alive: Duration,
+}
+
+/// The settings shared by every connection.
+pub struct ConnectionSettings {
+    access: AccessPolicy,
+    timeouts: Timeouts,
+    limits: ConnectionLimits,
+    metrics: Metrics,
+}
+
+enum ParseError {
+    HeaderTimeout,
+    BodyTimeout,
+    /// The body arrived slower than the minimum transfer rate.
+    SlowBody,
+    Invalid(&'static str),
+}
+
+#[derive(Clone)]
 pub struct ConnectionContext {
     id: Uuid,
-    slug: String,
-    from: String,
+    pub slug: String,
+    pub from: String,
+    /// The address of the connected socket.
+    pub peer: IpAddr,
+    /// The resolved client address, differs from `peer` for requests via trusted proxies.
+    pub client_ip: IpAddr,
+}
+
+enum ConnectionState {
+    /// Waiting for the rest of a request.
+    Reading,
+    /// The request is being handled on the connection pool.
+    Handling,
+    Writing,
+}
+
+/// A connection owned by the event loop.
+struct Connection {
+    stream: TcpStream,
+    context: ConnectionContext,
+    state: ConnectionState,
+    /// Bytes read but not parsed yet, may hold the start of the next request.
+    input: Vec<u8>,
+    /// The header of the current request if its body is still arriving, and when the header was complete.
+    pending: Option<(HttpRequestHeader, Instant)>,
+    /// Set until a PROXY protocol header is parsed or ruled out.
+    expect_proxy: bool,
+    /// The client address from a PROXY protocol header.
+    proxied: Option<IpAddr>,
+    /// When the connection started waiting for the current request.
+    idle_since: Instant,
+    /// When the first byte of the current request arrived.
+    request_started: Option<Instant>,
+    last_read: Instant,
+    /// The response status line and headers.
+    output: Vec<u8>,
+    body: Vec<u8>,
+    /// Bytes written from `output` followed by `body`.
+    written: usize,
+    file: Option<FileBody>,
+    file_offset: u64,
+    /// A streamed body, written after `body` as its chunks arrive.
+    chunks: Option<StreamBody>,
+    chunk: Vec<u8>,
+    chunk_written: usize,
+    /// Set once the connection has been upgraded to a WebSocket.
+    websocket: Option<WebSocket>,
+    last_write: Instant,
+    requests: usize,
+    keep_alive: bool,
+    /// The client has closed its side, no more requests will arrive.
+    read_closed: bool,
+}
+
+/// A response from a handler run on the connection pool.
+struct Completion {
+    token: Token,
+    response: HttpResponse,
+}
+
+/// The readiness based core of the server. Parsing and writing happen here without blocking,
+/// route handlers that may block are passed to the connection pool.
+struct EventLoop {
+    poll: Poll,
+    listener: Option<TcpListener>,
+    waker: Arc<Waker>,
+    connections: HashMap<Token, Connection>,
+    /// Open connections per client address.
+    clients: HashMap<IpAddr, usize>,
+    next_token: usize,
+    completion_sender: Sender<Completion>,
+    completions: Receiver<Completion>,
+    pool: ConnectionPool,
+    route_map: RouteMap,
+    settings: ConnectionSettings,
+    logger: Logger,
+    draining: bool,
 }
 
 impl Server {
-    pub fn start(config: Configuration, logger: Logger) {
+    /// Serve connections until shutdown is requested, then drain in-flight requests.
+    pub fn start(config: Configuration, logger: Logger, shutdown: Shutdown) {
         
-        let listener = TcpListener::bind(config.address).unwrap();
+        let listener = net::TcpListener::bind(config.address).unwrap();
+        listener.set_nonblocking(true).unwrap();
 
-        let connection_pool = ConnectionPool::new(4, logger.clone());
+        let poll = Poll::new().unwrap();
+        let mut listener = TcpListener::from_std(listener);
+        poll.registry().register(&mut listener, LISTENER, Interest::READABLE).unwrap();
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER).unwrap());
+        shutdown.set_waker(waker.clone());
 
-        //let logger = log.get_logger();
+        let (completion_sender, completions) = channel();
+        let mut event_loop = EventLoop {
+            poll,
+            listener: Some(listener),
+            waker,
+            connections: HashMap::new(),
+            clients: HashMap::new(),
+            next_token: 2,
+            completion_sender,
+            completions,
+            pool: ConnectionPool::new(&config.limits, logger.clone()),
+            route_map: config.routes,
+            settings: ConnectionSettings {
+                access: config.access,
+                timeouts: config.timeouts,
+                limits: config.limits,
+                metrics: config.metrics,
+            },
+            logger: logger.clone(),
+            draining: false,
+        };
 
-        for stream in listener.incoming() {
-            let stream = stream.unwrap();
-            let remote = stream.peer_addr().unwrap();
-            let context = ConnectionContext::new(String::from(remote.ip().to_string()));
-            let logger = logger.clone();
+        event_loop.run(&shutdown, config.shutdown.drain_timeout);
 
-            logger.log_info(format!("{}", context.slug), format!("Request received from {}", context.from));
-            
-            let rm = config.routes.clone();
-            connection_pool.execute(|| {
-                handle_connection(stream, logger, context, rm)
-            });
-        }
+        // Give idle handler threads a moment to notice the queue closing, even if the drain deadline has passed.
+        match event_loop.pool.shutdown(Instant::now() + Duration::from_secs(1)) {
+            true => logger.log_info("server".to_string(), "All connections finished.".to_string()).ok(),
+            false => logger.log_warning("server".to_string(), "Drain timeout reached with connections still in progress.".to_string()).ok()
+        };
     }
 }
 
 impl ConnectionContext {
-    pub fn new(from: String) -> ConnectionContext {
+    pub fn new(peer: IpAddr) -> ConnectionContext {
         let id = Uuid::new_v4();
         let slug = String::from(Uuid::new_v4().to_string().split_at(6).0);
-        ConnectionContext { id, slug, from }
+        ConnectionContext { id, slug, from: peer.to_string(), peer, client_ip: peer }
     }
 }
 
-fn handle_connection(mut stream: TcpStream, logger: Logger, context: ConnectionContext, route_map: RouteMap) {
-    logger.log_info(format!("{} connection-handler", context.slug), format!("Connection received"));
-    let response =
-        match parse_request(&stream, &logger, &context) {
-            Ok(request) => {
-                match handle_request(request, &logger, &context, &route_map) {
-                    Ok(response) => response,
+impl EventLoop {
+    fn run(&mut self, shutdown: &Shutdown, drain_timeout: Duration) {
+        let mut events = Events::with_capacity(1024);
+        let mut drain_deadline = None;
+        let mut last_tick = Instant::now();
+
+        loop {
+            if shutdown.is_requested() && drain_deadline.is_none() {
+                drain_deadline = Some(Instant::now() + drain_timeout);
+                self.stop_listening();
+            }
+            if let Some(deadline) = drain_deadline {
+                if self.connections.is_empty() || Instant::now() >= deadline {
+                    return;
+                }
+            }
+
+            if let Err(e) = self.poll.poll(&mut events, Some(TICK)) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                self.logger.log_error("server".to_string(), format!("Polling failed: {}", e)).ok();
+                return;
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept(),
+                    WAKER => {
+                        self.complete_handled();
+                        self.write_streams();
+                    }
+                    token => self.process(token, event.is_readable() || event.is_read_closed())
+                }
+            }
+
+            if last_tick.elapsed() >= TICK {
+                self.check_timeouts();
+                last_tick = Instant::now();
+            }
+        }
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let listener =
+                match &self.listener {
+                    None => return,
+                    Some(l) => l
+                };
+
+            let (mut stream, remote) =
+                match listener.accept() {
+                    Ok(s) => s,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
                     Err(e) => {
-                        logger.log_error(format!("{} connection-handler", context.slug), format!("Error in response handler: {}", e));
-                        handle_500()
+                        self.logger.log_error("server".to_string(), format!("Could not accept connection: {}", e)).ok();
+                        return;
+                    }
+                };
+
+            let context = ConnectionContext::new(remote.ip());
+            self.settings.metrics.increment("connections_accepted");
+            self.logger.log_info(context.slug.clone(), format!("Request received from {}", context.from)).ok();
+
+            let open = self.clients.get(&context.peer).copied().unwrap_or(0);
+            if self.settings.limits.max_per_client > 0 && open >= self.settings.limits.max_per_client {
+                self.reject(&context, Rejection::ClientLimit);
+                // Best effort, a client at its limit does not get to hold up the loop.
+                let mut response = handle_503(self.settings.limits.retry_after);
+                stream.write(&response.to_bytes()).ok();
+                continue;
+            }
+
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            if let Err(e) = self.poll.registry().register(&mut stream, token, Interest::READABLE | Interest::WRITABLE) {
+                self.logger.log_error(context.slug.clone(), format!("Could not register connection: {}", e)).ok();
+                continue;
+            }
+
+            *self.clients.entry(context.peer).or_insert(0) += 1;
+            let now = Instant::now();
+            let connection = Connection {
+                stream,
+                expect_proxy: self.settings.access.resolver.accepts_proxy_protocol(&context.peer),
+                context,
+                state: ConnectionState::Reading,
+                input: Vec::new(),
+                pending: None,
+                proxied: None,
+                idle_since: now,
+                request_started: None,
+                last_read: now,
+                output: Vec::new(),
+                body: Vec::new(),
+                written: 0,
+                file: None,
+                file_offset: 0,
+                chunks: None,
+                chunk: Vec::new(),
+                chunk_written: 0,
+                websocket: None,
+                last_write: now,
+                requests: 0,
+                keep_alive: !self.settings.timeouts.keep_alive.is_zero(),
+                read_closed: false,
+            };
+            self.connections.insert(token, connection);
+        }
+    }
+
+    /// Stop accepting connections and close the ones waiting for a new request, or for more of a streamed response.
+    fn stop_listening(&mut self) {
+        self.draining = true;
+        if let Some(mut listener) = self.listener.take() {
+            self.poll.registry().deregister(&mut listener).ok();
+        }
+        self.logger.log_info("server".to_string(), "Stopped accepting connections, draining.".to_string()).ok();
+
+        let idle: Vec<Token> =
+            self.connections
+                .iter()
+                .filter(|(_, c)| (matches!(c.state, ConnectionState::Reading) && c.input.is_empty() && c.pending.is_none()) || c.chunks.is_some())
+                .map(|(t, _)| *t)
+                .collect();
+        for token in idle {
+            if let Some(mut connection) = self.connections.remove(&token) {
+                // Best effort, unless it would land in the middle of another frame.
+                if connection.websocket.is_some() && connection.awaiting_chunk() {
+                    connection.stream.write_all(&encode_close(CLOSE_GOING_AWAY, "Server shutting down")).ok();
+                }
+                self.close(connection);
+            }
+        }
+        self.connections.values_mut().for_each(|c| c.keep_alive = false);
+    }
+
+    /// Handle readiness for a connection.
+    fn process(&mut self, token: Token, readable: bool) {
+        // Taken out of the map while in use so the rest of the loop can still be borrowed.
+        let mut connection =
+            match self.connections.remove(&token) {
+                None => return,
+                Some(c) => c
+            };
+
+        if readable {
+            connection.read_available();
+        }
+
+        match self.advance(token, &mut connection) {
+            true => { self.connections.insert(token, connection); }
+            false => self.close(connection)
+        }
+    }
+
+    /// Move the connection through as many states as it can go without blocking.
+    /// Returns false once the connection should be closed.
+    fn advance(&mut self, token: Token, connection: &mut Connection) -> bool {
+        loop {
+            match connection.state {
+                ConnectionState::Reading => {
+                    match connection.parse(&self.settings) {
+                        Ok(Some(request)) => self.dispatch(token, connection, request),
+                        // Nothing more will arrive, and anything partial can never be completed.
+                        Ok(None) if connection.read_closed => return false,
+                        Ok(None) => return true,
+                        Err(e) => {
+                            let response = self.parse_error_response(connection, e);
+                            connection.keep_alive = false;
+                            connection.start_response(response);
+                        }
+                    }
+                }
+                ConnectionState::Handling => return true,
+                ConnectionState::Writing => {
+                    if let Some(websocket) = &mut connection.websocket {
+                        let reply = websocket.receive(&mut connection.input);
+                        connection.chunk.extend(reply);
+                    }
+                    match connection.write_pending() {
+                        Ok(true) if connection.keep_alive => {
+                            connection.reset();
+                            connection.read_available();
+                        }
+                        Ok(true) => return false,
+                        // A streamed response to a client that has gone away will never be read.
+                        Ok(false) if connection.read_closed && connection.chunks.is_some() => return false,
+                        Ok(false) => return true,
+                        Err(e) => {
+                            self.logger.log_warning(format!("{} connection-handler", connection.context.slug), format!("Error writing response: {}", e)).ok();
+                            return false;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Resolve the client and either handle the request here or pass it to the connection pool.
+    fn dispatch(&mut self, token: Token, connection: &mut Connection, request: HttpRequest) {
+        let context = &mut connection.context;
+        let access = &self.settings.access;
+        context.client_ip = access.resolver.resolve(context.peer, connection.proxied, &request);
+        if context.client_ip != context.peer {
+            context.from = format!("{} (via {})", context.client_ip, context.peer);
+            self.logger.log_info(format!("{} connection-handler", context.slug), format!("Client address resolved to {}", context.from)).ok();
+        }
+
+        connection.requests += 1;
+        connection.keep_alive = connection.keep_alive && !self.draining && wants_keep_alive(&request);
+
+        if !access.access_list.is_allowed(&context.client_ip) {
+            self.logger.log_warning(format!("{} connection-handler", context.slug), format!("Request from {} denied by access list", context.from)).ok();
+            connection.start_response(handle_403());
+            return;
+        }
+
+        if !self.route_map.is_blocking(&request) {
+            let response = respond(request, &self.logger, context, &self.route_map, &self.settings.metrics);
+            if let Some(stream) = &response.stream {
+                stream.set_waker(self.waker.clone());
+            }
+            connection.start_response(response);
+            return;
+        }
+
+        let (logger, context, route_map, metrics) = (self.logger.clone(), context.clone(), self.route_map.clone(), self.settings.metrics.clone());
+        let (completions, waker) = (self.completion_sender.clone(), self.waker.clone());
+        let result = self.pool.execute(move || {
+            let response = respond(request, &logger, &context, &route_map, &metrics);
+            completions.send(Completion { token, response }).ok();
+            waker.wake().ok();
+        });
+
+        match result {
+            Ok(_) => connection.state = ConnectionState::Handling,
+            Err(rejection) => {
+                self.reject(&connection.context, rejection);
+                connection.start_response(handle_503(self.settings.limits.retry_after));
+            }
+        }
+    }
+
+    /// Pick up responses from the connection pool.
+    fn complete_handled(&mut self) {
+        while let Ok(completion) = self.completions.try_recv() {
+            // The connection may have been dropped at the drain deadline.
+            let mut connection =
+                match self.connections.remove(&completion.token) {
+                    None => continue,
+                    Some(c) => c
+                };
+            if let Some(stream) = &completion.response.stream {
+                stream.set_waker(self.waker.clone());
+            }
+            connection.start_response(completion.response);
+            match self.advance(completion.token, &mut connection) {
+                true => { self.connections.insert(completion.token, connection); }
+                false => self.close(connection)
+            }
+        }
+    }
+
+    /// Write the chunks that have arrived for streamed responses.
+    fn write_streams(&mut self) {
+        let streaming: Vec<Token> =
+            self.connections
+                .iter()
+                .filter(|(_, c)| c.chunks.is_some())
+                .map(|(t, _)| *t)
+                .collect();
+        for token in streaming {
+            self.process(token, false);
+        }
+    }
+
+    fn check_timeouts(&mut self) {
+        let now = Instant::now();
+        let timeouts = &self.settings.timeouts;
+        let mut expired = vec![];
+        let mut pings = vec![];
+
+        for (token, connection) in self.connections.iter() {
+            if connection.websocket.is_some() {
+                if now.duration_since(connection.last_read) > PING_INTERVAL * 3 {
+                    self.logger.log_warning(format!("{} connection-handler", connection.context.slug), format!("WebSocket from {} stopped responding", connection.context.from)).ok();
+                    expired.push((*token, None));
+                } else if now.duration_since(connection.last_write) > PING_INTERVAL {
+                    pings.push(*token);
+                }
+                continue;
+            }
+
+            let error =
+                match (&connection.state, &connection.pending) {
+                    (ConnectionState::Reading, Some((_, body_started))) => {
+                        match now.duration_since(connection.last_read) > timeouts.body_read {
+                            true => Some(ParseError::BodyTimeout),
+                            false => check_body_rate(timeouts, connection.pending_length(), connection.input.len(), *body_started).err()
+                        }
+                    }
+                    // The first request's header deadline runs from the connection opening,
+                    // later requests get the keep-alive timeout to start and then the header timeout.
+                    (ConnectionState::Reading, None) => {
+                        match (connection.requests, connection.request_started) {
+                            (0, _) if now.duration_since(connection.idle_since) > timeouts.header_read => Some(ParseError::HeaderTimeout),
+                            (0, _) => None,
+                            (_, Some(started)) if now.duration_since(started) > timeouts.header_read => Some(ParseError::HeaderTimeout),
+                            (_, Some(_)) => None,
+                            (_, None) if now.duration_since(connection.idle_since) > timeouts.keep_alive => {
+                                expired.push((*token, None));
+                                None
+                            }
+                            (_, None) => None
+                        }
+                    }
+                    // A streamed response waiting for its next chunk is not stalled.
+                    (ConnectionState::Writing, _) if now.duration_since(connection.last_write) > timeouts.write && !connection.awaiting_chunk() => {
+                        self.settings.metrics.increment("timeouts_write");
+                        self.logger.log_warning(format!("{} connection-handler", connection.context.slug), format!("Timed out writing response to {}", connection.context.from)).ok();
+                        expired.push((*token, None));
+                        None
+                    }
+                    _ => None
+                };
+
+            if let Some(e) = error {
+                expired.push((*token, Some(e)));
+            }
+        }
+
+        for token in pings {
+            if let Some(connection) = self.connections.get_mut(&token) {
+                connection.chunk.extend(encode_frame(Opcode::Ping, b""));
+            }
+            self.process(token, false);
+        }
+
+        for (token, error) in expired {
+            let mut connection =
+                match self.connections.remove(&token) {
+                    None => continue,
+                    Some(c) => c
+                };
+
+            match error {
+                // Idle keep-alive connections and stalled writes are closed without a response.
+                None => self.close(connection),
+                Some(e) => {
+                    let response = self.parse_error_response(&connection, e);
+                    connection.keep_alive = false;
+                    connection.start_response(response);
+                    match self.advance(token, &mut connection) {
+                        true => { self.connections.insert(token, connection); }
+                        false => self.close(connection)
                     }
                 }
             }
-            Err(e) => {
-                logger.log_error(format!("{} connection-handler", context.slug), format!("Error parsing http request: {}", e));
+        }
+    }
+
+    fn parse_error_response(&self, connection: &Connection, error: ParseError) -> HttpResponse {
+        let context = &connection.context;
+        match error {
+            ParseError::HeaderTimeout => {
+                self.settings.metrics.increment("timeouts_header_read");
+                self.logger.log_warning(format!("{} connection-handler", context.slug), format!("Timed out reading request header from {}", context.from)).ok();
+                handle_408()
+            }
+            ParseError::BodyTimeout => {
+                self.settings.metrics.increment("timeouts_body_read");
+                self.logger.log_warning(format!("{} connection-handler", context.slug), format!("Timed out reading request body from {}", context.from)).ok();
+                handle_408()
+            }
+            ParseError::SlowBody => {
+                self.settings.metrics.increment("timeouts_slow_body");
+                self.logger.log_warning(format!("{} connection-handler", context.slug), format!("Request body from {} below minimum transfer rate", context.from)).ok();
+                handle_408()
+            }
+            ParseError::Invalid(e) => {
+                self.logger.log_error(format!("{} connection-handler", context.slug), format!("Error parsing http request: {}", e)).ok();
                 handle_400()
             }
-        };
+        }
+    }
 
-    handle_response(&stream, response);
+    fn reject(&self, context: &ConnectionContext, rejection: Rejection) {
+        let reason =
+            match rejection {
+                Rejection::QueueFull => {
+                    self.settings.metrics.increment("connections_rejected_queue_full");
+                    "handler queue full"
+                }
+                Rejection::ClientLimit => {
+                    self.settings.metrics.increment("connections_rejected_client_limit");
+                    "too many connections from client"
+                }
+            };
+        self.logger.log_warning(context.slug.clone(), format!("Rejected request from {}: {}", context.from, reason)).ok();
+    }
+
+    fn close(&mut self, mut connection: Connection) {
+        self.poll.registry().deregister(&mut connection.stream).ok();
+        connection.stream.shutdown(net::Shutdown::Write).ok();
+        if let Some(open) = self.clients.get_mut(&connection.context.peer) {
+            *open -= 1;
+            if *open == 0 {
+                self.clients.remove(&connection.context.peer);
+            }
+        }
+    }
 }
 
-fn parse_request(mut stream: &TcpStream, logger: &Logger, context: &ConnectionContext) -> Result<HttpRequest, &'static str> {
-    let mut buffer = [0; 4096];
-    let mut body: Vec<u8> = Vec::new();
-    logger.log_info(format!("{} http-parser", context.slug), format!("Parsing header."));
-    stream.read(&mut buffer).unwrap();
-    logger.log_info(format!("{} http-parser", context.slug), format!("Read to buffer."));
-    let (header, body_start_index) = HttpRequestHeader::create_from_buffer(buffer)?;
-    let body = match (header.content_length > 0, body_start_index + header.content_length as usize > 4096) {
-        // Short cut -> content length is 0 so no body
-        (false, _) => {
-            None
+impl Connection {
+    /// Read everything available without blocking.
+    fn read_available(&mut self) {
+        let mut chunk = [0; 8192];
+        // Stop at the largest possible request, the rest is read once this one is handled.
+        while self.input.len() < MAX_HEADER_SIZE + MAX_BODY_SIZE {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.read_closed = true;
+                    return;
+                }
+                Ok(n) => {
+                    self.input.extend_from_slice(&chunk[..n]);
+                    self.last_read = Instant::now();
+                    self.request_started.get_or_insert(self.last_read);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => {
+                    self.read_closed = true;
+                    return;
+                }
+            }
         }
-        // If the body_start_index + content length 
-        // the request of the body is bigger than the buffer and more reads needed
-        (true, true) => {
-            // TODO handle!
-            None
+    }
+
+    /// Parse a complete request from the input, if one has arrived.
+    fn parse(&mut self, settings: &ConnectionSettings) -> Result<Option<HttpRequest>, ParseError> {
+        if self.expect_proxy && !self.parse_proxy_header()? {
+            return Ok(None);
         }
-        // If the body_start_index + content length < 2048,
-        // the body is in the initial buffer and no more reading is needed.
-        (true, false) => {
-            let end = body_start_index + header.content_length as usize;
 
-            let body = buffer[body_start_index..end].to_vec();
+        if self.pending.is_none() {
+            let end =
+                match self.input.windows(4).position(|w| w == b"\r\n\r\n") {
+                    Some(i) if i + 4 <= MAX_HEADER_SIZE => i + 4,
+                    Some(_) => return Err(ParseError::Invalid("Request header larger than buffer")),
+                    None if self.input.len() >= MAX_HEADER_SIZE => return Err(ParseError::Invalid("Request header larger than buffer")),
+                    None => return Ok(None)
+                };
 
-            Some(body)
+            let mut buffer = [0; MAX_HEADER_SIZE];
+            buffer[..end].copy_from_slice(&self.input[..end]);
+            let (header, _) = HttpRequestHeader::create_from_buffer(buffer).map_err(ParseError::Invalid)?;
+            self.input.drain(..end);
+
+            if header.content_length.max(0) as usize > MAX_BODY_SIZE {
+                return Err(ParseError::Invalid("Request body too large"));
+            }
+            self.pending = Some((header, Instant::now()));
+        }
+
+        let content_length = self.pending_length();
+        if let Some((_, body_started)) = &self.pending {
+            if self.input.len() < content_length {
+                check_body_rate(&settings.timeouts, content_length, self.input.len(), *body_started)?;
+                return Ok(None);
+            }
         }
-    };
 
-    HttpRequest::create(header, body)
+        let header =
+            match self.pending.take() {
+                None => return Ok(None),
+                Some((h, _)) => h
+            };
+        let body =
+            match content_length {
+                // Short cut -> content length is 0 so no body
+                0 => None,
+                n => Some(self.input.drain(..n).collect())
+            };
+
+        HttpRequest::create(header, body).map(Some).map_err(ParseError::Invalid)
+    }
+
+    /// Strip a PROXY protocol v1 line from the start of the connection if there is one.
+    /// Returns false if more input is needed to tell.
+    fn parse_proxy_header(&mut self) -> Result<bool, ParseError> {
+        let prefix = self.input.len().min(6);
+        if self.input[..prefix] != b"PROXY "[..prefix] {
+            self.expect_proxy = false;
+            return Ok(true);
+        }
+
+        // PROXY protocol v1 lines are at most 107 bytes including the `\r\n`.
+        let end =
+            match self.input[..self.input.len().min(107)].windows(2).position(|w| w == b"\r\n") {
+                Some(end) => end,
+                None if self.input.len() >= 107 => return Err(ParseError::Invalid("Invalid PROXY protocol header")),
+                None => return Ok(false)
+            };
+
+        self.proxied = parse_proxy_protocol(&String::from_utf8_lossy(&self.input[..end])).map_err(ParseError::Invalid)?;
+        self.input.drain(..end + 2);
+        self.expect_proxy = false;
+        Ok(true)
+    }
+
+    fn pending_length(&self) -> usize {
+        self.pending.as_ref().map(|(h, _)| h.content_length.max(0) as usize).unwrap_or(0)
+    }
+
+    fn start_response(&mut self, mut response: HttpResponse) {
+        // A streamed body ends when the connection closes.
+        self.keep_alive = self.keep_alive && response.stream.is_none();
+        self.websocket = response.websocket.take();
+        if self.websocket.is_none() {
+            let connection = match self.keep_alive { true => "keep-alive", false => "close" };
+            response.add_header("Connection".to_string(), connection.to_string());
+        }
+        self.output = response.header_bytes();
+        self.body = response.body.take().unwrap_or_default();
+        self.file = response.file.take();
+        self.file_offset = 0;
+        self.chunks = response.stream.take();
+        self.chunk = Vec::new();
+        self.chunk_written = 0;
+        self.written = 0;
+        self.last_write = Instant::now();
+        self.state = ConnectionState::Writing;
+    }
+
+    /// Write as much of the response as the socket takes. Returns true once it has all been written.
+    fn write_pending(&mut self) -> std::io::Result<bool> {
+        let header_length = self.output.len();
+        while self.written < header_length + self.body.len() {
+            let result =
+                match self.written < header_length {
+                    true => self.stream.write_vectored(&[IoSlice::new(&self.output[self.written..]), IoSlice::new(&self.body)]),
+                    false => self.stream.write(&self.body[self.written - header_length..])
+                };
+            match result {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.written += n;
+                    self.last_write = Instant::now();
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e)
+            }
+        }
+
+        if let Some(file) = &self.file {
+            while self.file_offset < file.length {
+                let remaining = file.length - self.file_offset;
+                match send_file(&self.stream, &file.file, &mut self.file_offset, remaining) {
+                    // The file has shrunk since the `Content-Length` was sent, the response can not be completed.
+                    Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                    Ok(_) => self.last_write = Instant::now(),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e)
+                }
+            }
+        }
+
+        if let Some(stream) = &self.chunks {
+            loop {
+                while self.chunk_written < self.chunk.len() {
+                    match self.stream.write(&self.chunk[self.chunk_written..]) {
+                        Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                        Ok(n) => {
+                            self.chunk_written += n;
+                            self.last_write = Instant::now();
+                        }
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                        Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                        Err(e) => return Err(e)
+                    }
+                }
+                // Nothing follows a WebSocket close frame.
+                if self.websocket.as_ref().map(|w| w.is_closing()).unwrap_or(false) {
+                    break;
+                }
+                match stream.try_recv() {
+                    Ok(chunk) => {
+                        self.chunk = chunk;
+                        self.chunk_written = 0;
+                    }
+                    Err(TryRecvError::Empty) => return Ok(false),
+                    Err(TryRecvError::Disconnected) => {
+                        match &mut self.websocket {
+                            Some(websocket) => self.chunk.extend(websocket.close(CLOSE_NORMAL, "")),
+                            None => break
+                        }
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// True if a streamed response has written everything it has been given so far.
+    fn awaiting_chunk(&self) -> bool {
+        self.chunks.is_some() && self.written >= self.output.len() + self.body.len() && self.chunk_written >= self.chunk.len()
+    }
+
+    /// Get ready for the next request on a kept-alive connection.
+    fn reset(&mut self) {
+        let now = Instant::now();
+        self.state = ConnectionState::Reading;
+        self.output = Vec::new();
+        self.body = Vec::new();
+        self.file = None;
+        self.chunks = None;
+        self.chunk = Vec::new();
+        self.chunk_written = 0;
+        self.websocket = None;
+        self.written = 0;
+        self.idle_since = now;
+        // Pipelined requests may already have started arriving.
+        self.request_started = match self.input.is_empty() { true => None, false => Some(now) };
+    }
 }
 
-fn handle_request(request: HttpRequest, logger: &Logger, context: &ConnectionContext, route_map: &RouteMap) -> Result<HttpResponse, &'static str> {
-    logger.log_info(format!("{} request-handler", context.slug), format!("Handling request for {}", request.header.route));
-    
-    route_map.handle(request)
+/// Large bodies must keep up the minimum transfer rate once the first body timeout period has passed.
+fn check_body_rate(timeouts: &Timeouts, content_length: usize, received: usize, started: Instant) -> Result<(), ParseError> {
+    let elapsed = started.elapsed();
+    let check_rate = timeouts.min_body_rate > 0 && content_length > timeouts.large_body_size;
+    match check_rate && elapsed > timeouts.body_read && (received as f64 / elapsed.as_secs_f64()) < timeouts.min_body_rate as f64 {
+        true => Err(ParseError::SlowBody),
+        false => Ok(())
+    }
+}
+
+/// HTTP/1.1 connections persist unless the client asks to close, HTTP/1.0 ones only if asked to.
+fn wants_keep_alive(request: &HttpRequest) -> bool {
+    let connection = request.get_header("Connection").map(|c| c.to_lowercase());
+    match (request.header.http_version.as_str(), connection.as_deref()) {
+        (_, Some("close")) => false,
+        ("HTTP/1.1", _) => true,
+        (_, Some("keep-alive")) => true,
+        _ => false
+    }
 }
 
-fn handle_response(mut stream: &TcpStream, mut response: HttpResponse) {
-    match stream.write(&response.to_bytes()) {
-        Ok(_) => {
-            stream.flush();
+/// Run the route handlers for a request, turning errors and panics into a 500 response.
+fn respond(request: HttpRequest, logger: &Logger, context: &ConnectionContext, route_map: &RouteMap, metrics: &Metrics) -> HttpResponse {
+    // A panicking route handler fails its own request instead of taking the server down.
+    match panic::catch_unwind(AssertUnwindSafe(|| handle_request(request, logger, context, route_map))) {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => {
+            logger.log_error(format!("{} connection-handler", context.slug), format!("Error in response handler: {}", e)).ok();
+            handle_500()
+        }
+        Err(e) => {
+            metrics.increment("handler_panics");
+            logger.log_error(format!("{} connection-handler", context.slug), format!("Request {} handler panicked: {}", context.id, panic_message(&e))).ok();
+            handle_500()
         }
-        Err(_) => {}
     }
 }
 
+fn handle_request(request: HttpRequest, logger: &Logger, context: &ConnectionContext, route_map: &RouteMap) -> Result<HttpResponse, &'static str> {
+    logger.log_info(format!("{} request-handler", context.slug), format!("Handling request for {}", request.header.route)).ok();
+    
+    route_map.handle(request, logger, context)
+}
+
 /*
 fn get_details() -> Output {
     Command::new("sh")
@@ -148,6 +907,24 @@ fn handle_400() -> HttpResponse {
     HttpResponse::create(400, String::from("application/json"), Some(body))
 }
 
+fn handle_403() -> HttpResponse {
+    let body = " { \"message\": \"Forbidden\"}".as_bytes().to_vec();
+
+    HttpResponse::create(403, String::from("application/json"), Some(body))
+}
+
+fn handle_408() -> HttpResponse {
+    let body = " { \"message\": \"Request timeout\"}".as_bytes().to_vec();
+    HttpResponse::create(408, String::from("application/json"), Some(body))
+}
+
+fn handle_503(retry_after: u64) -> HttpResponse {
+    let body = " { \"message\": \"Service unavailable\"}".as_bytes().to_vec();
+    let mut response = HttpResponse::create(503, String::from("application/json"), Some(body));
+    response.add_header("Retry-After".to_string(), retry_after.to_string());
+    response
+}
+
 fn handle_404() -> HttpResponse {
     let body = " { \"message\": \"Not found\"}".as_bytes().to_vec();
 
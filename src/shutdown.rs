@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use mio::Waker;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::debounce::DebouncedTrigger;
+use crate::logging::logging::Logger;
+use crate::orchestration::{Aggregator, JobCommand, JobRunStatus};
+
+/// What happens to running jobs when the server shuts down.
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum JobShutdownMode {
+    /// Let running jobs finish, up to the job timeout.
+    Wait,
+    /// Skip the remaining actions of running jobs.
+    Cancel,
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct ShutdownPolicy {
+    /// How long in-flight requests get to finish once the listener stops.
+    pub drain_timeout: Duration,
+    pub jobs: JobShutdownMode,
+    /// How long running jobs get to finish, after which they are cancelled.
+    pub job_timeout: Duration,
+    /// Jobs that have not started are saved here and queued again on the next start.
+    pub queue_path: Option<String>,
+}
+
+/// Set once a termination signal has been received, clones share the same state.
+#[derive(Clone)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    /// Wakes the server's event loop so it notices the request.
+    waker: Arc<Mutex<Option<Arc<Waker>>>>,
+}
+
+/// A job run that was still queued at shutdown.
+#[derive(Serialize)]
+#[derive(Deserialize)]
+struct PersistedJob {
+    name: String,
+    triggered_by: Option<String>,
+    parameters: HashMap<String, String>,
+    #[serde(default)]
+    priority: Option<i64>,
+    #[serde(default)]
+    parent: Option<Uuid>,
+    #[serde(default)]
+    triggers: Vec<DebouncedTrigger>,
+}
+
+impl JobShutdownMode {
+    pub fn from_str(data: &str) -> Result<JobShutdownMode, &'static str> {
+        match data.to_lowercase().as_str() {
+            "wait" => Ok(JobShutdownMode::Wait),
+            "cancel" => Ok(JobShutdownMode::Cancel),
+            _ => Err("Unknown job shutdown mode")
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown { requested: Arc::new(AtomicBool::new(false)), waker: Arc::new(Mutex::new(None)) }
+    }
+
+    /// Request shutdown on SIGINT or SIGTERM.
+    pub fn install(&self, logger: Logger) -> Result<(), &'static str> {
+        let shutdown = self.clone();
+        ctrlc::set_handler(move || {
+            logger.log_warning("shutdown".to_string(), "Termination signal received, shutting down.".to_string()).ok();
+            shutdown.request();
+        }).map_err(|_| "Could not install signal handler")
+    }
+
+    pub fn request(&self) {
+        if self.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        if let Some(waker) = self.waker.lock().ok().and_then(|w| w.clone()) {
+            waker.wake().ok();
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Register the waker for the server's event loop.
+    pub fn set_waker(&self, waker: Arc<Waker>) {
+        if let Ok(mut w) = self.waker.lock() {
+            *w = Some(waker);
+        }
+    }
+}
+
+/// Wait for a thread to finish, giving up at the deadline. Returns true if it was joined.
+pub fn join_with_deadline(handle: JoinHandle<()>, deadline: Instant) -> bool {
+    while !handle.is_finished() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    handle.join().is_ok()
+}
+
+/// Stop job processing. Runs that have not started are cancelled and saved for the next start,
+/// running jobs are waited for or cancelled according to the policy.
+pub fn stop_jobs(policy: &ShutdownPolicy, aggregator: &Aggregator, logger: &Logger) {
+    let cancel_running = matches!(policy.jobs, JobShutdownMode::Cancel);
+    let queued = aggregator.shutdown(cancel_running);
+
+    if !queued.is_empty() {
+        logger.log_warning("shutdown".to_string(), format!("{} queued job(s) will not be started.", queued.len())).ok();
+        if let Some(path) = &policy.queue_path {
+            match persist_queued_jobs(path, &queued) {
+                Ok(_) => logger.log_info("shutdown".to_string(), format!("Queued jobs saved to {}.", path)).ok(),
+                Err(e) => logger.log_error("shutdown".to_string(), format!("Could not save queued jobs: {}", e)).ok()
+            };
+        }
+    }
+
+    if !wait_for_jobs(aggregator, Instant::now() + policy.job_timeout) {
+        logger.log_warning("shutdown".to_string(), "Running jobs did not finish in time, cancelling.".to_string()).ok();
+        aggregator.shutdown(true);
+        wait_for_jobs(aggregator, Instant::now() + Duration::from_secs(1));
+    }
+}
+
+/// Queue any jobs saved at the last shutdown.
+pub fn restore_queued_jobs(policy: &ShutdownPolicy, job_sender: &Sender<JobCommand>, logger: &Logger) {
+    let path =
+        match &policy.queue_path {
+            None => return,
+            Some(p) => p
+        };
+
+    let jobs: Vec<PersistedJob> =
+        match fs::read_to_string(path) {
+            Err(_) => return,
+            Ok(data) => {
+                match serde_json::from_str(&data) {
+                    Ok(jobs) => jobs,
+                    Err(_) => {
+                        logger.log_error("shutdown".to_string(), format!("Could not parse saved jobs in {}.", path)).ok();
+                        return;
+                    }
+                }
+            }
+        };
+
+    for job in jobs {
+        let (reply_channel, reply) = channel();
+        let command = JobCommand { name: job.name.clone(), principal: job.triggered_by, parameters: job.parameters, priority: job.priority, resume: None, parent: job.parent, triggers: job.triggers, reply_channel };
+        let result = job_sender.send(command).map_err(|_| "Orchestrator not running.").and_then(|_| reply.recv().map_err(|_| "Orchestrator not running.").and_then(|r| r.map_err(|e| e.message())));
+        match result {
+            Ok(id) => logger.log_info("shutdown".to_string(), format!("Restored queued job `{}` as {}.", job.name, id)).ok(),
+            Err(e) => logger.log_error("shutdown".to_string(), format!("Could not restore queued job `{}`: {}", job.name, e)).ok()
+        };
+    }
+
+    fs::remove_file(path).ok();
+}
+
+fn persist_queued_jobs(path: &str, runs: &[JobRunStatus]) -> Result<(), &'static str> {
+    // Add to anything saved by an earlier shutdown that was never restored.
+    let mut jobs: Vec<PersistedJob> =
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default();
+
+    jobs.extend(runs.iter().map(|r| PersistedJob { name: r.name.clone(), triggered_by: r.triggered_by.clone(), parameters: r.parameters.clone(), priority: Some(r.priority), parent: r.parent, triggers: r.triggers.clone() }));
+
+    let data = serde_json::to_string_pretty(&jobs).map_err(|_| "Could not serialize queued jobs")?;
+    fs::write(path, data).map_err(|_| "Could not write queued jobs file")
+}
+
+/// Wait until no job runs are outstanding. Returns false if the deadline passed first.
+fn wait_for_jobs(aggregator: &Aggregator, deadline: Instant) -> bool {
+    loop {
+        if aggregator.get_progress().iter().all(|r| r.finished_at.is_some()) {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use serde_json::Value;
+use crate::auth::{constant_time_eq, verify_hmac_sha256};
+use crate::configuration::DebounceSettings;
+use crate::http::HttpRequest;
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub enum WebhookProvider {
+    /// `X-Hub-Signature-256: sha256=<hex hmac>` and `X-GitHub-Event`.
+    GitHub,
+    /// `X-Gitlab-Token: <secret>` and the payload `object_kind`.
+    GitLab,
+    /// A hex HMAC-SHA256 (optionally prefixed with `sha256=`) in a configured header.
+    Generic { signature_header: String, event_header: Option<String> },
+}
+
+#[derive(Clone)]
+#[derive(Debug)]
+pub struct WebhookRoute {
+    pub job: String,
+    provider: WebhookProvider,
+    secret: Vec<u8>,
+    /// If empty all events are accepted.
+    events: Vec<String>,
+    /// If empty all branches (and non-branch refs) are accepted.
+    branches: Vec<String>,
+    /// Pairs of `(parameter name, json pointer into the payload)`.
+    parameters: Vec<(String, String)>,
+    /// Replaces the job's own debounce settings for triggers from this route.
+    pub debounce: Option<DebounceSettings>,
+    /// The principal a verified webhook triggers the job as, unless the route authenticated one.
+    /// Its roles must grant the job's trigger permission, as for any other trigger.
+    pub principal: Option<String>,
+}
+
+pub enum WebhookOutcome {
+    /// The webhook is valid and matches the filters, holds the job parameters.
+    Trigger(HashMap<String, String>),
+    /// The webhook is valid but filtered out.
+    Ignored(String),
+    /// The signature is missing or does not match.
+    Unauthorized(&'static str),
+    /// The payload could not be read.
+    Invalid(&'static str),
+}
+
+impl WebhookProvider {
+    pub fn from_str(data: &str, signature_header: Option<String>, event_header: Option<String>) -> Result<WebhookProvider, &'static str> {
+        match data.to_lowercase().as_str() {
+            "github" => Ok(WebhookProvider::GitHub),
+            "gitlab" => Ok(WebhookProvider::GitLab),
+            "generic" => {
+                match signature_header {
+                    None => Err("Generic webhooks require a signature header"),
+                    Some(signature_header) => Ok(WebhookProvider::Generic { signature_header, event_header })
+                }
+            }
+            _ => Err("Unknown webhook provider")
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            WebhookProvider::GitHub => "github",
+            WebhookProvider::GitLab => "gitlab",
+            WebhookProvider::Generic { .. } => "generic",
+        }
+    }
+}
+
+impl WebhookRoute {
+    pub fn new(job: String, provider: WebhookProvider, secret: Vec<u8>, events: Vec<String>, branches: Vec<String>, parameters: Vec<(String, String)>, debounce: Option<DebounceSettings>) -> WebhookRoute {
+        WebhookRoute { job, provider, secret, events, branches, parameters, debounce, principal: None }
+    }
+
+    pub fn provider_name(&self) -> &'static str {
+        self.provider.name()
+    }
+
+    /// Verify the request signature over the full body, then apply the event and branch filters.
+    pub fn verify(&self, request: &HttpRequest) -> WebhookOutcome {
+        if let Err(e) = self.verify_signature(request) {
+            return WebhookOutcome::Unauthorized(e);
+        }
+
+        let payload: Value =
+            match serde_json::from_slice(request.body()) {
+                Ok(p) => p,
+                Err(_) => return WebhookOutcome::Invalid("Webhook payload is not json")
+            };
+
+        let event = self.get_event(request, &payload);
+        let branch =
+            payload
+                .get("ref")
+                .and_then(|r| r.as_str())
+                .and_then(|r| r.strip_prefix("refs/heads/"))
+                .map(|b| b.to_string());
+
+        if !self.events.is_empty() && !event.as_ref().map(|e| self.events.contains(e)).unwrap_or(false) {
+            return WebhookOutcome::Ignored(format!("Event `{}` is not handled", event.unwrap_or_default()));
+        }
+
+        if !self.branches.is_empty() && !branch.as_ref().map(|b| self.branches.contains(b)).unwrap_or(false) {
+            return WebhookOutcome::Ignored(format!("Branch `{}` is not handled", branch.unwrap_or_default()));
+        }
+
+        let mut parameters = HashMap::new();
+
+        if let Some(e) = event {
+            parameters.insert("event".to_string(), e);
+        }
+        if let Some(b) = branch {
+            parameters.insert("branch".to_string(), b);
+        }
+
+        for (name, pointer) in &self.parameters {
+            match payload.pointer(pointer) {
+                None | Some(Value::Null) => {}
+                Some(Value::String(v)) => {
+                    parameters.insert(name.clone(), v.clone());
+                }
+                Some(v) => {
+                    parameters.insert(name.clone(), v.to_string());
+                }
+            }
+        }
+
+        WebhookOutcome::Trigger(parameters)
+    }
+
+    fn verify_signature(&self, request: &HttpRequest) -> Result<(), &'static str> {
+        match &self.provider {
+            WebhookProvider::GitHub => {
+                let signature = request.get_header("X-Hub-Signature-256").ok_or("Missing signature header")?;
+                verify_signature_hex(&self.secret, request.body(), signature)
+            }
+            WebhookProvider::GitLab => {
+                let token = request.get_header("X-Gitlab-Token").ok_or("Missing token header")?;
+                match constant_time_eq(token.as_bytes(), &self.secret) {
+                    true => Ok(()),
+                    false => Err("Invalid token")
+                }
+            }
+            WebhookProvider::Generic { signature_header, .. } => {
+                let signature = request.get_header(signature_header).ok_or("Missing signature header")?;
+                verify_signature_hex(&self.secret, request.body(), signature)
+            }
+        }
+    }
+
+    fn get_event(&self, request: &HttpRequest, payload: &Value) -> Option<String> {
+        match &self.provider {
+            WebhookProvider::GitHub => request.get_header("X-GitHub-Event").cloned(),
+            WebhookProvider::GitLab => {
+                payload
+                    .get("object_kind")
+                    .and_then(|k| k.as_str())
+                    .map(|k| k.to_string())
+            }
+            WebhookProvider::Generic { event_header, .. } => {
+                event_header.as_ref().and_then(|h| request.get_header(h)).cloned()
+            }
+        }
+    }
+}
+
+fn verify_signature_hex(secret: &[u8], body: &[u8], signature: &str) -> Result<(), &'static str> {
+    let signature = signature.trim();
+    let hex = signature.strip_prefix("sha256=").unwrap_or(signature);
+    let decoded = decode_hex(hex).ok_or("Signature is not hex")?;
+
+    match verify_hmac_sha256(secret, body, &decoded) {
+        true => Ok(()),
+        false => Err("Invalid signature")
+    }
+}
+
+fn decode_hex(data: &str) -> Option<Vec<u8>> {
+    if !data.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..data.len())
+        .step_by(2)
+        .map(|i| data.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
@@ -0,0 +1,492 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use sha1::{Digest, Sha1};
+use crate::http::{BodySender, HttpRequest, HttpResponse, HttpVerb};
+
+/// Appended to the client's key to prove the server understood the handshake (RFC 6455 section 1.3).
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The largest message accepted from a client, larger ones close the connection.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_UNSUPPORTED: u16 = 1003;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_LARGE: u16 = 1009;
+
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+/// A frame received from a client, with its payload unmasked.
+struct Frame {
+    fin: bool,
+    opcode: Opcode,
+    payload: Vec<u8>,
+}
+
+/// Receives each text message from the client, returning any text to send back.
+/// Called on the server's event loop, so it must not block.
+pub type MessageHandler = Box<dyn FnMut(&str) -> Option<String> + Send>;
+
+/// The server side of an upgraded connection. Reassembles messages from the frames the client sends
+/// and answers pings and close frames; the messages the server sends go through a `WebSocketSender`.
+pub struct WebSocket {
+    on_message: MessageHandler,
+    /// The opcode and payload so far of a fragmented message.
+    message: Option<(Opcode, Vec<u8>)>,
+    /// Set once a close frame has been sent, nothing is sent after it.
+    closing: bool,
+}
+
+/// Sends messages to the client of an upgraded connection. Dropping every sender closes the socket.
+#[derive(Clone)]
+pub struct WebSocketSender {
+    body: BodySender,
+}
+
+impl Opcode {
+    fn from_u8(value: u8) -> Option<Opcode> {
+        match value {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            Opcode::Continuation => 0x0,
+            Opcode::Text => 0x1,
+            Opcode::Binary => 0x2,
+            Opcode::Close => 0x8,
+            Opcode::Ping => 0x9,
+            Opcode::Pong => 0xA,
+        }
+    }
+
+    fn is_control(self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+}
+
+/// Accept a WebSocket handshake, returning the `101 Switching Protocols` response that upgrades the
+/// connection and a sender for messages to the client.
+pub fn upgrade(request: &HttpRequest, on_message: MessageHandler) -> Result<(HttpResponse, WebSocketSender), &'static str> {
+    let has_token = |name: &str, token: &str| request.get_header(name).map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token))).unwrap_or(false);
+    if !matches!(request.header.verb, HttpVerb::GET) || !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        return Err("Expected a WebSocket upgrade request");
+    }
+    if request.get_header("Sec-WebSocket-Version").map(|v| v.trim()) != Some("13") {
+        return Err("Unsupported WebSocket version, expected 13");
+    }
+    let key =
+        match request.get_header("Sec-WebSocket-Key").map(|k| k.trim()) {
+            Some(k) if STANDARD.decode(k).map(|k| k.len() == 16).unwrap_or(false) => k,
+            _ => return Err("Missing or invalid Sec-WebSocket-Key")
+        };
+
+    let (mut response, body) = HttpResponse::create_stream(101, String::new());
+    response.headers.remove("Content-Type");
+    response.add_header("Upgrade".to_string(), "websocket".to_string());
+    response.add_header("Connection".to_string(), "Upgrade".to_string());
+    response.add_header("Sec-WebSocket-Accept".to_string(), accept_key(key));
+    response.websocket = Some(WebSocket { on_message, message: None, closing: false });
+    Ok((response, WebSocketSender { body }))
+}
+
+/// The `Sec-WebSocket-Accept` value answering a client's key.
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(HANDSHAKE_GUID.as_bytes());
+    STANDARD.encode(hasher.finalize())
+}
+
+/// Encode an unmasked frame, as frames from the server are.
+pub fn encode_frame(opcode: Opcode, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode.to_u8());
+    match payload.len() {
+        n if n < 126 => frame.push(n as u8),
+        n if n <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        n => {
+            frame.push(127);
+            frame.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Encode a close frame with a status code and reason.
+pub fn encode_close(code: u16, reason: &str) -> Vec<u8> {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(reason.as_bytes());
+    encode_frame(Opcode::Close, &payload)
+}
+
+/// True for the close codes an endpoint may send (RFC 6455 section 7.4), the reserved
+/// 1004, 1005, 1006 and 1015 are only ever reported locally.
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+/// Parse a frame from the start of the input, returning it and its length once it has all arrived.
+fn parse_frame(input: &[u8]) -> Result<Option<(Frame, usize)>, (u16, &'static str)> {
+    if input.len() < 2 {
+        return Ok(None);
+    }
+    if input[0] & 0x70 != 0 {
+        return Err((CLOSE_PROTOCOL_ERROR, "Reserved bits set"));
+    }
+    let fin = input[0] & 0x80 != 0;
+    let opcode = Opcode::from_u8(input[0] & 0x0F).ok_or((CLOSE_PROTOCOL_ERROR, "Unknown opcode"))?;
+    if input[1] & 0x80 == 0 {
+        return Err((CLOSE_PROTOCOL_ERROR, "Client frames must be masked"));
+    }
+
+    let (length, offset) =
+        match input[1] & 0x7F {
+            126 if input.len() < 4 => return Ok(None),
+            126 => (u16::from_be_bytes([input[2], input[3]]) as u64, 4),
+            127 if input.len() < 10 => return Ok(None),
+            127 => (u64::from_be_bytes(<[u8; 8]>::try_from(&input[2..10]).unwrap_or_default()), 10),
+            n => (n as u64, 2)
+        };
+    if opcode.is_control() && (length > 125 || !fin) {
+        return Err((CLOSE_PROTOCOL_ERROR, "Invalid control frame"));
+    }
+    if length > MAX_MESSAGE_SIZE as u64 {
+        return Err((CLOSE_TOO_LARGE, "Message too large"));
+    }
+
+    let start = offset + 4;
+    let end = start + length as usize;
+    if input.len() < end {
+        return Ok(None);
+    }
+    let mask = &input[offset..start];
+    let payload = input[start..end].iter().enumerate().map(|(i, b)| b ^ mask[i % 4]).collect();
+    Ok(Some((Frame { fin, opcode, payload }, end)))
+}
+
+impl WebSocket {
+    /// Handle the complete frames at the start of the input, removing them from it.
+    /// Returns the frames to send in reply, written once the message being sent has been.
+    pub fn receive(&mut self, input: &mut Vec<u8>) -> Vec<u8> {
+        let mut reply = Vec::new();
+        while !self.closing {
+            let (frame, length) =
+                match parse_frame(input) {
+                    Ok(None) => break,
+                    Ok(Some(f)) => f,
+                    Err((code, reason)) => {
+                        reply.extend(self.close(code, reason));
+                        break;
+                    }
+                };
+            input.drain(..length);
+            if let Some(frame) = self.handle_frame(frame) {
+                reply.extend(frame);
+            }
+        }
+        // Anything after a close frame is ignored.
+        if self.closing {
+            input.clear();
+        }
+        reply
+    }
+
+    fn handle_frame(&mut self, frame: Frame) -> Option<Vec<u8>> {
+        match (frame.opcode, self.message.take()) {
+            (Opcode::Ping, message) => {
+                self.message = message;
+                Some(encode_frame(Opcode::Pong, &frame.payload))
+            }
+            (Opcode::Pong, message) => {
+                self.message = message;
+                None
+            }
+            // Echo the client's status code, as the close handshake expects, unless it may not be sent.
+            (Opcode::Close, _) => {
+                match frame.payload.len() {
+                    0 => Some(self.close(CLOSE_NORMAL, "")),
+                    1 => Some(self.close(CLOSE_PROTOCOL_ERROR, "Invalid close frame")),
+                    _ => {
+                        let code = u16::from_be_bytes([frame.payload[0], frame.payload[1]]);
+                        match is_valid_close_code(code) && std::str::from_utf8(&frame.payload[2..]).is_ok() {
+                            true => Some(self.close(code, "")),
+                            false => Some(self.close(CLOSE_PROTOCOL_ERROR, "Invalid close frame"))
+                        }
+                    }
+                }
+            }
+            (Opcode::Continuation, None) => Some(self.close(CLOSE_PROTOCOL_ERROR, "Unexpected continuation frame")),
+            (Opcode::Text | Opcode::Binary, Some(_)) => Some(self.close(CLOSE_PROTOCOL_ERROR, "Expected a continuation frame")),
+            (Opcode::Continuation, Some((opcode, mut payload))) => {
+                if payload.len() + frame.payload.len() > MAX_MESSAGE_SIZE {
+                    return Some(self.close(CLOSE_TOO_LARGE, "Message too large"));
+                }
+                payload.extend(frame.payload);
+                self.complete(frame.fin, opcode, payload)
+            }
+            (opcode, None) => self.complete(frame.fin, opcode, frame.payload)
+        }
+    }
+
+    /// Pass on a message once its last fragment has arrived.
+    fn complete(&mut self, fin: bool, opcode: Opcode, payload: Vec<u8>) -> Option<Vec<u8>> {
+        if !fin {
+            self.message = Some((opcode, payload));
+            return None;
+        }
+        if opcode == Opcode::Binary {
+            return Some(self.close(CLOSE_UNSUPPORTED, "Binary messages are not supported"));
+        }
+        match String::from_utf8(payload) {
+            Ok(text) => (self.on_message)(&text).map(|r| encode_frame(Opcode::Text, r.as_bytes())),
+            Err(_) => Some(self.close(CLOSE_INVALID_DATA, "Messages must be valid UTF-8"))
+        }
+    }
+
+    /// Start closing the socket, returning the close frame to send.
+    pub fn close(&mut self, code: u16, reason: &str) -> Vec<u8> {
+        self.closing = true;
+        encode_close(code, reason)
+    }
+
+    /// True once a close frame has been sent.
+    pub fn is_closing(&self) -> bool {
+        self.closing
+    }
+}
+
+impl WebSocketSender {
+    /// Send a text message. Fails once the connection has closed.
+    pub fn send_text(&self, text: &str) -> Result<(), &'static str> {
+        self.body.send(encode_frame(Opcode::Text, text.as_bytes()))
+    }
+
+    /// True once the connection has closed.
+    pub fn is_closed(&self) -> bool {
+        self.body.is_closed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A client frame, masked as clients must.
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![(if fin { 0x80 } else { 0 }) | opcode];
+        match payload.len() {
+            n if n < 126 => frame.push(0x80 | n as u8),
+            n if n <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            n => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(n as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    fn echo_socket() -> WebSocket {
+        WebSocket { on_message: Box::new(|m| Some(m.to_string())), message: None, closing: false }
+    }
+
+    /// The status code of a close frame sent by the server.
+    fn close_code(reply: &[u8]) -> Option<u16> {
+        match reply.first() {
+            Some(0x88) if reply.len() >= 4 => Some(u16::from_be_bytes([reply[2], reply[3]])),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn parses_masked_frame() {
+        let (frame, length) = parse_frame(&client_frame(true, 0x1, b"hello")).unwrap().unwrap();
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, Opcode::Text);
+        assert_eq!(frame.payload, b"hello");
+        assert_eq!(length, 2 + 4 + 5);
+    }
+
+    #[test]
+    fn rejects_unmasked_frame() {
+        let frame = encode_frame(Opcode::Text, b"hello");
+        assert_eq!(parse_frame(&frame).err().map(|e| e.0), Some(CLOSE_PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn waits_for_whole_frame() {
+        let frame = client_frame(true, 0x1, b"hello");
+        for end in 0..frame.len() {
+            assert!(parse_frame(&frame[..end]).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn parses_16_bit_length() {
+        let payload = vec![b'a'; 300];
+        let (frame, length) = parse_frame(&client_frame(true, 0x1, &payload)).unwrap().unwrap();
+        assert_eq!(frame.payload, payload);
+        assert_eq!(length, 2 + 2 + 4 + 300);
+    }
+
+    #[test]
+    fn parses_64_bit_length() {
+        let payload = vec![b'a'; MAX_MESSAGE_SIZE];
+        let frame = client_frame(true, 0x1, &payload);
+        assert_eq!(frame[1] & 0x7F, 127);
+        let (parsed, length) = parse_frame(&frame).unwrap().unwrap();
+        assert_eq!(parsed.payload, payload);
+        assert_eq!(length, 2 + 8 + 4 + MAX_MESSAGE_SIZE);
+    }
+
+    #[test]
+    fn rejects_oversize_frame() {
+        let frame = client_frame(true, 0x1, &vec![b'a'; MAX_MESSAGE_SIZE + 1]);
+        assert_eq!(parse_frame(&frame).err().map(|e| e.0), Some(CLOSE_TOO_LARGE));
+
+        // The length alone is enough, the payload need not have arrived.
+        let mut header = vec![0x81, 0x80 | 127];
+        header.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert_eq!(parse_frame(&header).err().map(|e| e.0), Some(CLOSE_TOO_LARGE));
+    }
+
+    #[test]
+    fn rejects_reserved_bits_and_unknown_opcodes() {
+        let mut frame = client_frame(true, 0x1, b"a");
+        frame[0] |= 0x40;
+        assert_eq!(parse_frame(&frame).err().map(|e| e.0), Some(CLOSE_PROTOCOL_ERROR));
+        assert_eq!(parse_frame(&client_frame(true, 0x3, b"a")).err().map(|e| e.0), Some(CLOSE_PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn rejects_long_and_fragmented_control_frames() {
+        assert_eq!(parse_frame(&client_frame(true, 0x9, &[0; 126])).err().map(|e| e.0), Some(CLOSE_PROTOCOL_ERROR));
+        assert!(parse_frame(&client_frame(true, 0x9, &[0; 125])).unwrap().is_some());
+        assert_eq!(parse_frame(&client_frame(false, 0x9, b"a")).err().map(|e| e.0), Some(CLOSE_PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn reassembles_fragmented_message() {
+        let mut socket = echo_socket();
+        let mut input = client_frame(false, 0x1, b"hel");
+        // A ping between fragments is answered without ending the message.
+        input.extend(client_frame(true, 0x9, b"p"));
+        input.extend(client_frame(true, 0x0, b"lo"));
+        let reply = socket.receive(&mut input);
+
+        let mut expected = encode_frame(Opcode::Pong, b"p");
+        expected.extend(encode_frame(Opcode::Text, b"hello"));
+        assert_eq!(reply, expected);
+        assert!(input.is_empty());
+        assert!(!socket.is_closing());
+    }
+
+    #[test]
+    fn keeps_partial_frame_for_later() {
+        let mut socket = echo_socket();
+        let frame = client_frame(true, 0x1, b"hello");
+        let mut input = frame[..4].to_vec();
+        assert!(socket.receive(&mut input).is_empty());
+        assert_eq!(input.len(), 4);
+        input.extend_from_slice(&frame[4..]);
+        assert_eq!(socket.receive(&mut input), encode_frame(Opcode::Text, b"hello"));
+    }
+
+    #[test]
+    fn rejects_oversize_fragmented_message() {
+        let mut socket = echo_socket();
+        let mut input = client_frame(false, 0x1, &vec![b'a'; MAX_MESSAGE_SIZE]);
+        input.extend(client_frame(true, 0x0, b"a"));
+        assert_eq!(close_code(&socket.receive(&mut input)), Some(CLOSE_TOO_LARGE));
+        assert!(socket.is_closing());
+    }
+
+    #[test]
+    fn rejects_unexpected_continuation_and_interleaved_messages() {
+        let mut socket = echo_socket();
+        assert_eq!(close_code(&socket.receive(&mut client_frame(true, 0x0, b"a"))), Some(CLOSE_PROTOCOL_ERROR));
+
+        let mut socket = echo_socket();
+        let mut input = client_frame(false, 0x1, b"a");
+        input.extend(client_frame(true, 0x1, b"b"));
+        assert_eq!(close_code(&socket.receive(&mut input)), Some(CLOSE_PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn rejects_binary_and_invalid_utf8() {
+        let mut socket = echo_socket();
+        assert_eq!(close_code(&socket.receive(&mut client_frame(true, 0x2, b"a"))), Some(CLOSE_UNSUPPORTED));
+
+        let mut socket = echo_socket();
+        assert_eq!(close_code(&socket.receive(&mut client_frame(true, 0x1, &[0xFF, 0xFE]))), Some(CLOSE_INVALID_DATA));
+    }
+
+    #[test]
+    fn echoes_valid_close_codes() {
+        for code in [1000u16, 1001, 1003, 1007, 1014, 3000, 4999] {
+            let mut socket = echo_socket();
+            let reply = socket.receive(&mut client_frame(true, 0x8, &code.to_be_bytes()));
+            assert_eq!(close_code(&reply), Some(code));
+            assert!(socket.is_closing());
+        }
+
+        let mut socket = echo_socket();
+        assert_eq!(socket.receive(&mut client_frame(true, 0x8, b"")), encode_close(CLOSE_NORMAL, ""));
+    }
+
+    #[test]
+    fn rejects_invalid_close_codes() {
+        for code in [0u16, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000] {
+            let mut socket = echo_socket();
+            let reply = socket.receive(&mut client_frame(true, 0x8, &code.to_be_bytes()));
+            assert_eq!(close_code(&reply), Some(CLOSE_PROTOCOL_ERROR), "code {}", code);
+        }
+
+        let mut socket = echo_socket();
+        assert_eq!(close_code(&socket.receive(&mut client_frame(true, 0x8, &[0x03]))), Some(CLOSE_PROTOCOL_ERROR));
+
+        let mut payload = CLOSE_NORMAL.to_be_bytes().to_vec();
+        payload.extend_from_slice(&[0xFF, 0xFE]);
+        let mut socket = echo_socket();
+        assert_eq!(close_code(&socket.receive(&mut client_frame(true, 0x8, &payload))), Some(CLOSE_PROTOCOL_ERROR));
+    }
+
+    #[test]
+    fn ignores_input_after_close() {
+        let mut socket = echo_socket();
+        let mut input = client_frame(true, 0x8, &CLOSE_NORMAL.to_be_bytes());
+        input.extend(client_frame(true, 0x1, b"late"));
+        assert_eq!(socket.receive(&mut input), encode_close(CLOSE_NORMAL, ""));
+        assert!(input.is_empty());
+    }
+}